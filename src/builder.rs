@@ -0,0 +1,487 @@
+//! Typed playbooks, to be built from Rust code rather than assembled as contexts
+//!
+//! **Example(s)**
+//! ```rust,ignore
+//! let mut playbook = Playbook::new()
+//!     .whitelist(WhitelistEntry::new("train.py"))
+//!     .docker(DockerSpec::new("aleozlx/tkstack2:latest").runtime("nvidia").volume("/mnt/data:/data"));
+//! for lr in [0.1, 0.01, 0.001].iter() {
+//!     playbook = playbook.step(Step::new("train").name(format!("Train with lr={}", lr)).set("lr", CtxObj::Real(*lr)));
+//! }
+//! std::fs::write("sweep.yml", playbook.to_yaml())?;
+//! playbook_api::run_playbook(playbook.into(), ctx_args)?;
+//! ```
+//!
+//! Keys without a field of their own are kept as they are in `extra`.
+//! Containerized steps read the playbook again from the `playbook` argument inside of the container,
+//! so a playbook that has been built must be written to that path before it runs.
+
+use yaml_rust::{Yaml, YamlEmitter, YamlLoader};
+use ymlctx::context::{Context, CtxObj};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WhitelistEntry {
+    /// Path to the source of the actions, relative to the playbook
+    pub src: String,
+    pub extra: Context
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DockerSpec {
+    pub image: String,
+    pub interactive: Option<bool>,
+    pub impersonate: Option<String>,
+    pub runtime: Option<String>,
+    pub ipc: Option<String>,
+    pub network: Option<String>,
+    pub gui: Option<bool>,
+    pub name: Option<String>,
+    pub volumes: Vec<String>,
+    pub ports: Vec<String>,
+    pub environment: Vec<String>,
+    pub extra: Context
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Step {
+    pub name: Option<String>,
+    pub action: String,
+    pub needs: Option<Vec<String>>,
+    pub tags: Vec<String>,
+    pub docker: Option<DockerSpec>,
+    /// Parameters of the action, and any other keys of the step
+    pub extra: Context
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Playbook {
+    pub whitelist: Vec<WhitelistEntry>,
+    pub docker: Option<DockerSpec>,
+    pub steps: Vec<Step>,
+    pub on_failure: Vec<Step>,
+    pub finally: Vec<Step>,
+    /// Global variables, and any other keys of the playbook
+    pub extra: Context
+}
+
+impl WhitelistEntry {
+    pub fn new<S: Into<String>>(src: S) -> WhitelistEntry {
+        WhitelistEntry { src: src.into(), extra: Context::new() }
+    }
+
+    pub fn from_ctx(ctx: &Context) -> Result<WhitelistEntry, String> {
+        Ok(WhitelistEntry {
+            src: required_str(ctx, "src", "whitelist")?,
+            extra: ctx.hide("src")
+        })
+    }
+
+    fn yaml(&self) -> Yaml {
+        let mut map = Mapping::new();
+        map.str("src", &self.src);
+        map.extra(&self.extra);
+        map.into()
+    }
+}
+
+impl DockerSpec {
+    pub fn new<S: Into<String>>(image: S) -> DockerSpec {
+        DockerSpec {
+            image: image.into(),
+            interactive: None,
+            impersonate: None,
+            runtime: None,
+            ipc: None,
+            network: None,
+            gui: None,
+            name: None,
+            volumes: Vec::new(),
+            ports: Vec::new(),
+            environment: Vec::new(),
+            extra: Context::new()
+        }
+    }
+
+    pub fn interactive(mut self, interactive: bool) -> DockerSpec {
+        self.interactive = Some(interactive);
+        self
+    }
+
+    pub fn impersonate<S: Into<String>>(mut self, impersonate: S) -> DockerSpec {
+        self.impersonate = Some(impersonate.into());
+        self
+    }
+
+    pub fn runtime<S: Into<String>>(mut self, runtime: S) -> DockerSpec {
+        self.runtime = Some(runtime.into());
+        self
+    }
+
+    pub fn ipc<S: Into<String>>(mut self, ipc: S) -> DockerSpec {
+        self.ipc = Some(ipc.into());
+        self
+    }
+
+    pub fn network<S: Into<String>>(mut self, network: S) -> DockerSpec {
+        self.network = Some(network.into());
+        self
+    }
+
+    pub fn gui(mut self, gui: bool) -> DockerSpec {
+        self.gui = Some(gui);
+        self
+    }
+
+    pub fn name<S: Into<String>>(mut self, name: S) -> DockerSpec {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Mount a volume, e.g. `/mnt/data:/data`, which is read-only unless it ends with `:rw`
+    pub fn volume<S: Into<String>>(mut self, volume: S) -> DockerSpec {
+        self.volumes.push(volume.into());
+        self
+    }
+
+    pub fn port<S: Into<String>>(mut self, port: S) -> DockerSpec {
+        self.ports.push(port.into());
+        self
+    }
+
+    /// Set an environment variable, e.g. `LANG=C.UTF-8`
+    pub fn env<S: Into<String>>(mut self, var: S) -> DockerSpec {
+        self.environment.push(var.into());
+        self
+    }
+
+    pub fn set(mut self, key: &str, val: CtxObj) -> DockerSpec {
+        self.extra = self.extra.set(key, val);
+        self
+    }
+
+    pub fn from_ctx(ctx: &Context) -> Result<DockerSpec, String> {
+        let keys = ["image", "interactive", "impersonate", "runtime", "ipc", "network", "gui", "name", "volumes", "ports", "environment"];
+        Ok(DockerSpec {
+            image: required_str(ctx, "image", "docker")?,
+            interactive: optional_bool(ctx, "interactive", "docker")?,
+            impersonate: optional_str(ctx, "impersonate", "docker")?,
+            runtime: optional_str(ctx, "runtime", "docker")?,
+            ipc: optional_str(ctx, "ipc", "docker")?,
+            network: optional_str(ctx, "network", "docker")?,
+            gui: optional_bool(ctx, "gui", "docker")?,
+            name: optional_str(ctx, "name", "docker")?,
+            volumes: strings(ctx, "volumes", "docker")?.unwrap_or_default(),
+            ports: strings(ctx, "ports", "docker")?.unwrap_or_default(),
+            environment: strings(ctx, "environment", "docker")?.unwrap_or_default(),
+            extra: keys.iter().fold(ctx.clone(), |extra, key| extra.hide(key))
+        })
+    }
+
+    fn yaml(&self) -> Yaml {
+        let mut map = Mapping::new();
+        map.str("image", &self.image);
+        map.opt_bool("interactive", self.interactive);
+        map.opt_str("impersonate", &self.impersonate);
+        map.opt_str("runtime", &self.runtime);
+        map.opt_str("ipc", &self.ipc);
+        map.opt_str("network", &self.network);
+        map.opt_bool("gui", self.gui);
+        map.opt_str("name", &self.name);
+        map.strings("volumes", &self.volumes);
+        map.strings("ports", &self.ports);
+        map.strings("environment", &self.environment);
+        map.extra(&self.extra);
+        map.into()
+    }
+}
+
+impl Step {
+    pub fn new<S: Into<String>>(action: S) -> Step {
+        Step { name: None, action: action.into(), needs: None, tags: Vec::new(), docker: None, extra: Context::new() }
+    }
+
+    pub fn name<S: Into<String>>(mut self, name: S) -> Step {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Declare a step that this step needs, by its name
+    pub fn needs<S: Into<String>>(mut self, step: S) -> Step {
+        self.needs.get_or_insert_with(Vec::new).push(step.into());
+        self
+    }
+
+    pub fn tag<S: Into<String>>(mut self, tag: S) -> Step {
+        self.tags.push(tag.into());
+        self
+    }
+
+    pub fn docker(mut self, docker: DockerSpec) -> Step {
+        self.docker = Some(docker);
+        self
+    }
+
+    /// Set a parameter of the action, or any other key of the step, e.g. `when`
+    pub fn set(mut self, key: &str, val: CtxObj) -> Step {
+        self.extra = self.extra.set(key, val);
+        self
+    }
+
+    pub fn from_ctx(ctx: &Context) -> Result<Step, String> {
+        Ok(Step {
+            name: optional_str(ctx, "name", "step")?,
+            action: required_str(ctx, "action", "step")?,
+            needs: strings(ctx, "needs", "step")?,
+            tags: strings(ctx, "tags", "step")?.unwrap_or_default(),
+            docker: match ctx.get("docker") {
+                Some(CtxObj::Context(ctx_docker)) => Some(DockerSpec::from_ctx(ctx_docker)?),
+                Some(_) => { return Err(String::from("Key `docker` of a step must be a mapping.")); },
+                None => None
+            },
+            extra: ctx.hide("name").hide("action").hide("needs").hide("tags").hide("docker")
+        })
+    }
+
+    fn yaml(&self) -> Yaml {
+        let mut map = Mapping::new();
+        map.opt_str("name", &self.name);
+        map.str("action", &self.action);
+        if let Some(ref needs) = self.needs {
+            map.insert("needs", Yaml::Array(needs.iter().map(|name| Yaml::String(name.to_owned())).collect()));
+        }
+        map.strings("tags", &self.tags);
+        if let Some(ref docker) = self.docker {
+            map.insert("docker", docker.yaml());
+        }
+        map.extra(&self.extra);
+        map.into()
+    }
+}
+
+impl Playbook {
+    pub fn new() -> Playbook {
+        Playbook { whitelist: Vec::new(), docker: None, steps: Vec::new(), on_failure: Vec::new(), finally: Vec::new(), extra: Context::new() }
+    }
+
+    pub fn whitelist(mut self, entry: WhitelistEntry) -> Playbook {
+        self.whitelist.push(entry);
+        self
+    }
+
+    /// The default container of the steps
+    pub fn docker(mut self, docker: DockerSpec) -> Playbook {
+        self.docker = Some(docker);
+        self
+    }
+
+    pub fn step(mut self, step: Step) -> Playbook {
+        self.steps.push(step);
+        self
+    }
+
+    pub fn on_failure(mut self, step: Step) -> Playbook {
+        self.on_failure.push(step);
+        self
+    }
+
+    pub fn finally(mut self, step: Step) -> Playbook {
+        self.finally.push(step);
+        self
+    }
+
+    /// Set a global variable, or any other key of the playbook, e.g. `timeout`
+    pub fn set(mut self, key: &str, val: CtxObj) -> Playbook {
+        self.extra = self.extra.set(key, val);
+        self
+    }
+
+    pub fn from_ctx(ctx: &Context) -> Result<Playbook, String> {
+        let steps = |key: &str, required: bool| -> Result<Vec<Step>, String> {
+            match ctx.get(key) {
+                None if !required => Ok(Vec::new()),
+                Some(CtxObj::Array(_)) => match ctx.list_contexts(key) {
+                    Some(steps) => steps.iter().map(Step::from_ctx).collect(),
+                    None => Err(format!("Key `{}` must be a list of mappings.", key))
+                },
+                _ => Err(format!("Key `{}` is not an array.", key))
+            }
+        };
+        Ok(Playbook {
+            whitelist: match ctx.get("whitelist") {
+                None => Vec::new(),
+                Some(_) => match ctx.list_contexts("whitelist") {
+                    Some(whitelist) => whitelist.iter().map(WhitelistEntry::from_ctx).collect::<Result<Vec<WhitelistEntry>, String>>()?,
+                    None => { return Err(String::from("Key `whitelist` should be a list of mappings.")); }
+                }
+            },
+            docker: match ctx.get("docker") {
+                Some(CtxObj::Context(ctx_docker)) => Some(DockerSpec::from_ctx(ctx_docker)?),
+                Some(_) => { return Err(String::from("Key `docker` must be a mapping.")); },
+                None => None
+            },
+            steps: steps("steps", true)?,
+            on_failure: steps("on_failure", false)?,
+            finally: steps("finally", false)?,
+            extra: ctx.hide("whitelist").hide("docker").hide("steps").hide("on_failure").hide("finally")
+        })
+    }
+
+    /// Read a playbook of a single document
+    pub fn from_yaml(contents: &str) -> Result<Playbook, String> {
+        match YamlLoader::load_from_str(contents) {
+            Ok(docs) => match docs.into_iter().next() {
+                Some(doc @ Yaml::Hash(_)) => Playbook::from_ctx(&Context::from(doc)),
+                _ => Err(String::from("The playbook must be a mapping."))
+            },
+            Err(e) => Err(format!("{}", e))
+        }
+    }
+
+    /// Write the playbook as YAML, with keys in a stable order
+    pub fn to_yaml(&self) -> String {
+        let mut map = Mapping::new();
+        map.extra(&self.extra);
+        if !self.whitelist.is_empty() {
+            map.insert("whitelist", Yaml::Array(self.whitelist.iter().map(WhitelistEntry::yaml).collect()));
+        }
+        if let Some(ref docker) = self.docker {
+            map.insert("docker", docker.yaml());
+        }
+        map.insert("steps", Yaml::Array(self.steps.iter().map(Step::yaml).collect()));
+        for (key, steps) in [("on_failure", &self.on_failure), ("finally", &self.finally)].iter() {
+            if !steps.is_empty() {
+                map.insert(key, Yaml::Array(steps.iter().map(Step::yaml).collect()));
+            }
+        }
+        let mut out = String::new();
+        YamlEmitter::new(&mut out).dump(&map.into()).unwrap();
+        out.push('\n');
+        out
+    }
+}
+
+impl Default for Playbook {
+    fn default() -> Self {
+        Playbook::new()
+    }
+}
+
+impl From<Playbook> for Context {
+    fn from(playbook: Playbook) -> Self {
+        Context::from(YamlLoader::load_from_str(&playbook.to_yaml()).unwrap().remove(0))
+    }
+}
+
+/// A YAML mapping, whose keys are emitted in the order in which they are inserted
+struct Mapping(yaml_rust::yaml::Hash);
+
+impl Mapping {
+    fn new() -> Mapping {
+        Mapping(yaml_rust::yaml::Hash::new())
+    }
+
+    fn insert(&mut self, key: &str, val: Yaml) {
+        self.0.insert(Yaml::String(key.to_owned()), val);
+    }
+
+    fn str(&mut self, key: &str, val: &str) {
+        self.insert(key, Yaml::String(val.to_owned()));
+    }
+
+    fn opt_str(&mut self, key: &str, val: &Option<String>) {
+        if let Some(val) = val { self.str(key, val); }
+    }
+
+    fn opt_bool(&mut self, key: &str, val: Option<bool>) {
+        if let Some(val) = val { self.insert(key, Yaml::Boolean(val)); }
+    }
+
+    fn strings(&mut self, key: &str, vals: &[String]) {
+        if !vals.is_empty() {
+            self.insert(key, Yaml::Array(vals.iter().map(|val| Yaml::String(val.to_owned())).collect()));
+        }
+    }
+
+    /// Keys of a context, in alphabetical order
+    fn extra(&mut self, ctx: &Context) {
+        let mut keys: Vec<&String> = ctx.keys().collect();
+        keys.sort();
+        for key in keys {
+            self.insert(key, sorted(ctx.get(key).unwrap()));
+        }
+    }
+}
+
+impl From<Mapping> for Yaml {
+    fn from(map: Mapping) -> Self {
+        Yaml::Hash(map.0)
+    }
+}
+
+/// A value as YAML, with the keys of its mappings in alphabetical order
+fn sorted(val: &CtxObj) -> Yaml {
+    match val {
+        CtxObj::Context(ctx) => {
+            let mut map = Mapping::new();
+            map.extra(ctx);
+            map.into()
+        },
+        CtxObj::Array(items) => Yaml::Array(items.iter().map(sorted).collect()),
+        _ => val.to_owned().into()
+    }
+}
+
+fn required_str(ctx: &Context, key: &str, what: &str) -> Result<String, String> {
+    match optional_str(ctx, key, what)? {
+        Some(s) => Ok(s),
+        None => Err(format!("Key `{}` of a {} is required.", key, what))
+    }
+}
+
+fn optional_str(ctx: &Context, key: &str, what: &str) -> Result<Option<String>, String> {
+    match ctx.get(key) {
+        Some(CtxObj::Str(s)) => Ok(Some(s.to_owned())),
+        Some(_) => Err(format!("Key `{}` of a {} must be a string.", key, what)),
+        None => Ok(None)
+    }
+}
+
+fn optional_bool(ctx: &Context, key: &str, what: &str) -> Result<Option<bool>, String> {
+    match ctx.get(key) {
+        Some(CtxObj::Bool(b)) => Ok(Some(*b)),
+        Some(_) => Err(format!("Key `{}` of a {} must be a boolean.", key, what)),
+        None => Ok(None)
+    }
+}
+
+/// A list of strings, where a lone string is a list of one
+fn strings(ctx: &Context, key: &str, what: &str) -> Result<Option<Vec<String>>, String> {
+    match ctx.get(key) {
+        Some(CtxObj::Str(s)) => Ok(Some(vec![s.to_owned()])),
+        Some(CtxObj::Array(items)) => items.iter().map(|item| match item {
+            CtxObj::Str(s) => Ok(s.to_owned()),
+            _ => Err(format!("Key `{}` of a {} must be a list of strings.", key, what))
+        }).collect::<Result<Vec<String>, String>>().map(Some),
+        Some(_) => Err(format!("Key `{}` of a {} must be a list of strings.", key, what)),
+        None => Ok(None)
+    }
+}
+
+#[test]
+fn test_builder() {
+    let playbook = Playbook::new()
+        .whitelist(WhitelistEntry::new("train.py"))
+        .docker(DockerSpec::new("aleozlx/tkstack2:latest").runtime("nvidia").volume("/mnt/data:/data").set("vars", CtxObj::Context(Context::from("gpus: 2"))))
+        .set("epochs", CtxObj::Int(90))
+        .step(Step::new("train").name("Train").tag("gpu").set("lr", CtxObj::Real(0.1)))
+        .step(Step::new("eval").needs("Train"))
+        .finally(Step::new("sys_shell").set("bash", CtxObj::Str(String::from("echo done"))));
+    let yml = playbook.to_yaml();
+    assert_eq!(yml, "---\nepochs: 90\nwhitelist:\n  - src: train.py\ndocker:\n  image: \"aleozlx/tkstack2:latest\"\n  runtime: nvidia\n  volumes:\n    - \"/mnt/data:/data\"\n  vars:\n    gpus: 2\n\
+        steps:\n  - name: Train\n    action: train\n    tags:\n      - gpu\n    lr: 0.1\n  - action: eval\n    needs:\n      - Train\nfinally:\n  - action: sys_shell\n    bash: echo done\n");
+    assert_eq!(Playbook::from_yaml(&yml), Ok(playbook.clone()));
+    let ctx: Context = playbook.into();
+    assert_eq!(ctx.list_contexts("steps").unwrap()[1].get("needs"), Some(&CtxObj::Array(vec![CtxObj::Str(String::from("Train"))])));
+    assert!(Playbook::from_yaml("steps:\n- name: no action").is_err());
+    assert!(Playbook::from_yaml("docker:\n  image: 1\nsteps: []").is_err());
+}
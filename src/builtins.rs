@@ -1,5 +1,8 @@
 use crate::systems::docker;
-use std::path::Path;
+use crate::journal;
+use std::path::{Path, PathBuf};
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::Write;
 use nix::unistd::ForkResult;
@@ -9,7 +12,7 @@ use yaml_rust::YamlLoader;
 use ymlctx::context::{Context, CtxObj};
 use itertools::Itertools;
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub enum ExitCode {
     Success,
     ErrSys,
@@ -39,6 +42,19 @@ impl Into<i32> for ExitCode {
     }
 }
 
+impl From<i32> for ExitCode {
+    fn from(exit_code: i32) -> Self {
+        match exit_code {
+            0 => ExitCode::Success,
+            1 => ExitCode::ErrSys,
+            2 => ExitCode::ErrApp,
+            3 => ExitCode::ErrYML,
+            4 => ExitCode::ErrTask,
+            x => ExitCode::Any(x)
+        }
+    }
+}
+
 /// A context labeled as either stateful or stateless,
 /// or diverging when neither is applicable, in which case the program must provide an exit code and exit gracefully.
 /// 
@@ -62,7 +78,77 @@ impl From<Result<Context, ExitCode>> for TransientContext {
     }
 }
 
-type BuiltIn = fn(Context) -> TransientContext;
+pub type BuiltIn = fn(Context) -> TransientContext;
+
+/// Names of the built-in actions of playbook-rs
+pub const NAMES: &[&str] = &["sys_exit", "sys_shell", "sys_vars", "sys_fork", "sys_ctxdump", "sys_include"];
+
+/// Built-in actions of library users, each named after a namespace of their own
+///
+/// **Example(s)**
+/// ```rust,ignore
+/// let mut registry = Registry::new();
+/// registry.register("acme", "upload", acme_upload)?; // action: acme_upload
+/// playbook_api::run_playbook_with(raw, ctx_args, RunOptions::new().registry(registry))?;
+/// ```
+///
+/// The `sys` namespace is reserved for the built-in actions of playbook-rs.
+#[derive(Clone, Default)]
+pub struct Registry {
+    actions: HashMap<String, BuiltIn>
+}
+
+impl Registry {
+    pub fn new() -> Registry {
+        Registry { actions: HashMap::new() }
+    }
+
+    /// Register an action as `<namespace>_<name>`
+    pub fn register(&mut self, namespace: &str, name: &str, action: BuiltIn) -> Result<(), String> {
+        if namespace.is_empty() || !namespace.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(format!("Namespace `{}` must be alphanumeric.", namespace));
+        }
+        if namespace == "sys" {
+            return Err(String::from("Namespace `sys` is reserved for the built-in actions."));
+        }
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(format!("Action name `{}` must be alphanumeric.", name));
+        }
+        let key = format!("{}_{}", namespace, name);
+        if self.actions.contains_key(&key) {
+            return Err(format!("Action `{}` has already been registered.", key));
+        }
+        self.actions.insert(key, action);
+        Ok(())
+    }
+
+    pub fn get(&self, action: &str) -> Option<BuiltIn> {
+        self.actions.get(action).cloned()
+    }
+
+    /// Names of the registered actions, in alphabetical order
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.actions.keys().cloned().collect();
+        names.sort();
+        names
+    }
+}
+
+thread_local! {
+    static REGISTRY: RefCell<Registry> = RefCell::new(Registry::new());
+}
+
+/// Replace the registry of the runs, and tell the previous one
+pub(crate) fn replace_registry(registry: Registry) -> Registry {
+    REGISTRY.with(|cell| cell.replace(registry))
+}
+
+/// Names of the built-in actions, including those registered for the current run
+pub fn names() -> Vec<String> {
+    let mut names: Vec<String> = NAMES.iter().map(|&name| name.to_owned()).collect();
+    names.extend(REGISTRY.with(|cell| cell.borrow().names()));
+    names
+}
 
 /// The built-in tasks resolver
 pub fn resolve<'step>(ctx_step: &'step Context) -> (Option<&'step str>, Option<BuiltIn>) {
@@ -74,7 +160,8 @@ pub fn resolve<'step>(ctx_step: &'step Context) -> (Option<&'step str>, Option<B
             "sys_vars" => (Some(action), Some(vars)),
             "sys_fork" => (Some(action), Some(fork)),
             "sys_ctxdump" => (Some(action), Some(ctxdump)),
-            _ => (Some(action), None)
+            "sys_include" => (Some(action), Some(include)),
+            _ => (Some(action), REGISTRY.with(|cell| cell.borrow().get(action)))
         }
     }
     else { (None, None) }
@@ -90,7 +177,11 @@ pub fn resolve<'step>(ctx_step: &'step Context) -> (Option<&'step str>, Option<B
 /// exit_code: 1
 /// ```
 pub fn exit(ctx: Context) -> TransientContext {
-    TransientContext::Diverging(ExitCode::Any(if let Ok(exit_code) = ctx.unpack("exit_code") { exit_code } else { 0 }))
+    // * A clean exit is a success, which does not trigger the `on_failure` handlers.
+    TransientContext::Diverging(match ctx.unpack("exit_code") {
+        Ok(0) | Err(_) => ExitCode::Success,
+        Ok(exit_code) => ExitCode::Any(exit_code)
+    })
 }
 
 /// Enter a shell (this must be in a container context)
@@ -117,8 +208,7 @@ pub fn shell(ctx: Context) -> TransientContext {
                     TransientContext::Diverging(ExitCode::Success)
                 },
                 Err(_) => {
-                    error!("Docker crashed.");
-                    TransientContext::Diverging(ExitCode::ErrYML)
+                    TransientContext::Diverging(fail!(ExitCode::ErrYML, "Docker crashed."))
                 }
             }
         }
@@ -129,15 +219,13 @@ pub fn shell(ctx: Context) -> TransientContext {
                     TransientContext::Diverging(ExitCode::Success)
                 },
                 Err(_) => {
-                    error!("Docker crashed.");
-                    TransientContext::Diverging(ExitCode::ErrYML)
+                    TransientContext::Diverging(fail!(ExitCode::ErrYML, "Docker crashed."))
                 }
             }
         }
     }
     else {
-        error!("Docker context not found!");
-        TransientContext::Diverging(ExitCode::ErrYML)
+        TransientContext::Diverging(fail!(ExitCode::ErrYML, "Docker context not found!"))
     }
 }
 
@@ -164,8 +252,7 @@ pub fn fork(ctx: Context) -> TransientContext {
     let grid = match ctx.list_contexts("grid") {
         Some(params) => params,
         None => {
-            error!("Key `grid` is required.");
-            return TransientContext::Diverging(ExitCode::ErrYML);
+            return TransientContext::Diverging(fail!(ExitCode::ErrYML, "Key `grid` is required."));
         }
     };
     if let Some(resources) = ctx.subcontext("resource") {
@@ -212,7 +299,7 @@ fn ctxdump(ctx: Context) -> TransientContext {
         let path = Path::new(ctxdump).to_path_buf();
         match File::create(path.join(format!("ctxdump-{}.yml", uuid_from_ctx(&ctx)))) {
             Ok(mut file) => {
-                let contents = format!("{}", ctx);
+                let contents = crate::secrets::redact(&format!("{}", ctx));
                 match file.write_all(contents.as_bytes()) {
                     Err(why) => {
                         eprintln!("Warning: Failed to dump context: {}", why);
@@ -238,24 +325,25 @@ fn fork_nolimit(grid: Vec<Context>) -> TransientContext {
                         .set("fork_uuid", CtxObj::Str(uuid_from_ctx(&ctx))))
             }
             Ok(ForkResult::Parent { child, .. }) => {
-                children.push(child);
+                children.push((child, ctx));
             }
             Err(_) => {
-                error!("Failed to fork a new process.");
-                return TransientContext::Diverging(ExitCode::ErrSys);
+                return TransientContext::Diverging(fail!(ExitCode::ErrSys, "Failed to fork a new process."));
             }
         }
     }
     let mut exitcode = ExitCode::Success;
-    for child in children {
+    for (child, params) in children {
         match waitpid(child, None) {
             Ok(status) => match status {
                 WaitStatus::Exited(_, exit_code) => {
+                    fork_child(journal::Child { params, exit_code: Some(exit_code), signal: None });
                     if exit_code != 0 {
                         exitcode = ExitCode::ErrTask
                     }
                 },
-                WaitStatus::Signaled(_, _sig, _core_dump) => {
+                WaitStatus::Signaled(_, sig, _core_dump) => {
+                    fork_child(journal::Child { params, exit_code: None, signal: Some(format!("{:?}", sig)) });
                     exitcode = ExitCode::ErrTask
                 },
                 WaitStatus::Stopped(_, _sig) => unreachable!(),
@@ -272,6 +360,12 @@ fn fork_nolimit(grid: Vec<Context>) -> TransientContext {
     TransientContext::Diverging(exitcode)
 }
 
+/// Tell the journal and the observers about a child process that has exited
+fn fork_child(child: journal::Child) {
+    crate::observer::notify(|o| o.on_fork_child(&child));
+    journal::add_child(child);
+}
+
 fn fork_pool(grid: Vec<Context>, pool: &Vec<CtxObj>) -> TransientContext {
     unimplemented!();
     let nproc = pool.len();
@@ -304,8 +398,7 @@ pub fn vars(ctx: Context) -> TransientContext {
             let contents = match super::read_contents(src_path) {
                 Ok(v) => v,
                 Err(e) => {
-                    error!("IO Error: {}", e);
-                    return TransientContext::Diverging(ExitCode::ErrSys);
+                    return TransientContext::Diverging(fail!(ExitCode::ErrSys, "IO Error: {}", e));
                 }
             };
             return match YamlLoader::load_from_str(&contents) {
@@ -321,3 +414,130 @@ pub fn vars(ctx: Context) -> TransientContext {
     }
     TransientContext::Stateless(Context::new())
 }
+
+/// Include the steps of another playbook, as if they were written in place of this step
+///
+/// **Example(s)**
+/// ```yaml
+/// action: sys_include
+/// from: common/data_prep.yml
+/// ---
+/// action: sys_include
+/// from: common/data_prep.yml
+/// vars:
+///   dataset: imagenet
+/// when: "mode == 'train'"
+/// tags: [prep]
+/// needs: [download]
+/// ```
+///
+/// The path is relative to the including playbook, and so are the `src` of the whitelist
+/// of the included playbook relative to the included playbook. The `vars` are overlaid onto
+/// each of the included steps. The `when` of this step is combined with that of each of the
+/// included steps, its `tags` are added to theirs, and its `needs` are given to the first of them.
+/// Sub-playbooks are spliced in as the playbook is loaded, see `expand_includes`,
+/// so this action never runs by itself.
+fn include(_ctx: Context) -> TransientContext {
+    TransientContext::Diverging(fail!(ExitCode::ErrApp, "A sys_include step has not been expanded as the playbook was loaded."))
+}
+
+/// Splice in the steps of the sub-playbooks included by `sys_include` steps, recursively.
+pub fn expand_includes(steps: Vec<Context>, playbook: &str) -> Result<Vec<Context>, ExitCode> {
+    let playbook_dir = Path::new(playbook).parent().unwrap_or(Path::new("."));
+    expand(steps, playbook_dir, Path::new(""), &mut vec![canonical(Path::new(playbook))])
+}
+
+/// Identify a playbook by its canonical path, so that it is recognized however it is referred to
+fn canonical(path: &Path) -> PathBuf {
+    let path_str = path.to_string_lossy();
+    let (fname, stage) = super::split_stage(&path_str);
+    let fname = std::fs::canonicalize(fname).unwrap_or_else(|_| PathBuf::from(fname));
+    match stage {
+        Some(stage) => PathBuf::from(format!("{}#{}", fname.display(), stage)),
+        None => fname
+    }
+}
+
+/// Both conditions of `when`, i.e. that of a `sys_include` step and that of an included step
+fn conjoin(a: Option<&CtxObj>, b: Option<&CtxObj>) -> Option<CtxObj> {
+    match (a, b) {
+        (Some(CtxObj::Str(a)), Some(CtxObj::Str(b))) => Some(CtxObj::Str(format!("({}) and ({})", a, b))),
+        (Some(CtxObj::Bool(false)), _) | (_, Some(CtxObj::Bool(false))) => Some(CtxObj::Bool(false)),
+        (Some(CtxObj::Bool(true)), b) | (b, Some(CtxObj::Bool(true))) | (b, None) | (None, b) => b.cloned(),
+        (Some(a), Some(_)) => Some(a.clone())
+    }
+}
+
+/// Both lists of `tags` or `needs`, either of which may be a single string
+fn concat(a: Option<&CtxObj>, b: Option<&CtxObj>) -> Option<CtxObj> {
+    let items = |x: Option<&CtxObj>| match x {
+        Some(CtxObj::Array(items)) => items.to_owned(),
+        Some(item) => vec![item.to_owned()],
+        None => Vec::new()
+    };
+    match (a, b) {
+        (None, None) => None,
+        _ => Some(CtxObj::Array(items(a).into_iter().chain(items(b)).collect()))
+    }
+}
+
+/// * `base` @param directory of the including playbook, relative to that of the top-level playbook
+/// * `stack` @param playbooks that are being included, to detect cycles
+fn expand(steps: Vec<Context>, playbook_dir: &Path, base: &Path, stack: &mut Vec<PathBuf>) -> Result<Vec<Context>, ExitCode> {
+    let mut ret = Vec::new();
+    for ctx_step in steps {
+        let url = match (ctx_step.get("action"), ctx_step.get("from")) {
+            (Some(CtxObj::Str(action)), Some(CtxObj::Str(url))) if action == "sys_include" => url.to_owned(),
+            (Some(CtxObj::Str(action)), _) if action == "sys_include" => {
+                return Err(fail!(ExitCode::ErrYML, "Syntax Error: Key `from` of sys_include must be the path to a playbook."));
+            },
+            _ => {
+                ret.push(ctx_step);
+                continue;
+            }
+        };
+        let rel = base.join(&url);
+        let sub_dir = rel.parent().unwrap_or(Path::new("")).to_path_buf();
+        let path = playbook_dir.join(&rel);
+        if stack.contains(&canonical(&path)) {
+            return Err(fail!(ExitCode::ErrYML, "Syntax Error: Playbook {:?} includes itself.", path));
+        }
+        debug!("Including {:?}.", path);
+        let raw = super::load_yaml(&path).map_err(|e| e.into_exit_code())?;
+        let mut ctx_global = raw.hide("steps").hide("on_failure").hide("finally");
+        if let Some(whitelist) = ctx_global.list_contexts("whitelist") {
+            // * Relocate the sources so that they are found relative to the top-level playbook.
+            let whitelist = whitelist.into_iter().map(|ctx_source| match ctx_source.get("src") {
+                Some(CtxObj::Str(src)) => {
+                    let src = sub_dir.join(src).to_str().unwrap().to_owned();
+                    CtxObj::Context(ctx_source.set("src", CtxObj::Str(src)))
+                },
+                _ => CtxObj::Context(ctx_source)
+            }).collect();
+            ctx_global = ctx_global.set("whitelist", CtxObj::Array(whitelist));
+        }
+        let sub_steps = match raw.list_contexts("steps") {
+            Some(sub_steps) => sub_steps,
+            None => {
+                return Err(fail!(ExitCode::ErrYML, "Syntax Error: Key `steps` of {:?} is not an array.", path));
+            }
+        };
+        let ctx_vars = ctx_step.subcontext("vars");
+        let sub_steps = sub_steps.iter().enumerate().map(|(k, sub_step)| {
+            let sub_step = ctx_global.overlay(sub_step);
+            let sub_step = match ctx_vars {
+                Some(ref ctx_vars) => sub_step.overlay(ctx_vars),
+                None => sub_step
+            };
+            let sub_step = sub_step
+                .set_opt("when", conjoin(ctx_step.get("when"), sub_step.get("when")))
+                .set_opt("tags", concat(ctx_step.get("tags"), sub_step.get("tags")));
+            if k == 0 { sub_step.set_opt("needs", concat(ctx_step.get("needs"), sub_step.get("needs"))) }
+            else { sub_step }
+        }).collect();
+        stack.push(canonical(&path));
+        ret.extend(expand(sub_steps, playbook_dir, &sub_dir, stack)?);
+        stack.pop();
+    }
+    Ok(ret)
+}
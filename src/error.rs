@@ -0,0 +1,188 @@
+//! Errors of a playbook, as told to the callers of `run_playbook`
+//!
+//! **Example(s)**
+//! ```rust,ignore
+//! match playbook_api::run_playbook(raw, ctx_args) {
+//!     Ok(()) => {},
+//!     Err(e) => match e.kind {
+//!         ErrorKind::Task => eprintln!("Step {:?} has failed: {}", e.step.map(|step| step.index), e.message),
+//!         _ => std::process::exit(e.exit_code().into())
+//!     }
+//! }
+//! ```
+//!
+//! The cause of a failed step, e.g. a `TaskError`, is available through `std::error::Error::source`.
+
+use std::fmt;
+use crate::builtins::ExitCode;
+use crate::{Failure, set_failure};
+
+/// Kinds of errors, which correspond to the exit codes of the `playbook` binary
+#[derive(Debug, Clone, PartialEq)]
+pub enum ErrorKind {
+    /// The operating system has failed us, e.g. an IO error (exit code 1)
+    System,
+    /// playbook-rs cannot carry on, e.g. an undefined infrastructure (exit code 2)
+    Application,
+    /// The playbook is malformed (exit code 3)
+    Syntax,
+    /// A step has failed (exit code 4)
+    Task,
+    /// A step has exited with a code of its own, e.g. by `sys_exit`
+    Exit(i32)
+}
+
+/// Where an error is in the playbook
+#[derive(Debug, Clone, PartialEq)]
+pub struct Location {
+    pub file: String,
+    /// 1-based line number
+    pub line: usize,
+    /// 1-based column number
+    pub col: usize
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.col)
+    }
+}
+
+/// The step that has failed
+#[derive(Debug, Clone, PartialEq)]
+pub struct StepRef {
+    /// 1-based index of the step
+    pub index: usize,
+    pub name: Option<String>
+}
+
+#[derive(Debug)]
+pub struct Error {
+    pub kind: ErrorKind,
+    pub message: String,
+    pub step: Option<StepRef>,
+    pub location: Option<Location>,
+    source: Option<Box<dyn std::error::Error + Send + Sync>>
+}
+
+impl Error {
+    pub fn new(kind: ErrorKind, message: String) -> Error {
+        Error { kind, message, step: None, location: None, source: None }
+    }
+
+    /// Attribute the error to a step, given its 0-based index
+    pub fn with_step(mut self, step_ptr: usize, step_name: Option<String>) -> Error {
+        self.step = Some(StepRef { index: step_ptr + 1, name: step_name });
+        self
+    }
+
+    pub fn with_location(mut self, location: Option<Location>) -> Error {
+        self.location = location;
+        self
+    }
+
+    pub fn with_source<E>(mut self, source: E) -> Error
+      where E: std::error::Error + Send + Sync + 'static
+    {
+        self.source = Some(Box::new(source));
+        self
+    }
+
+    pub fn exit_code(&self) -> ExitCode {
+        match self.kind {
+            ErrorKind::System => ExitCode::ErrSys,
+            ErrorKind::Application => ExitCode::ErrApp,
+            ErrorKind::Syntax => ExitCode::ErrYML,
+            ErrorKind::Task => ExitCode::ErrTask,
+            ErrorKind::Exit(x) => ExitCode::Any(x)
+        }
+    }
+
+    /// Turn the error into its exit code, remembering it as the reason of the exit code for it to be told later on
+    pub(crate) fn into_exit_code(self) -> ExitCode {
+        let exit_code = self.exit_code();
+        set_failure(Some(Failure::new(self.message)));
+        exit_code
+    }
+
+    /// The error that a step has diverged with, given why it has failed if known
+    pub(crate) fn from_failure(exit_code: ExitCode, failure: Option<Failure>) -> Error {
+        let kind = match exit_code {
+            ExitCode::ErrSys => ErrorKind::System,
+            ExitCode::ErrApp => ErrorKind::Application,
+            ExitCode::ErrYML => ErrorKind::Syntax,
+            ExitCode::ErrTask => ErrorKind::Task,
+            ExitCode::Success => ErrorKind::Exit(0),
+            ExitCode::Any(x) => ErrorKind::Exit(x)
+        };
+        match failure {
+            Some(failure) => {
+                let err = Error::new(kind, failure.message);
+                match failure.cause {
+                    Some(cause) => err.with_source(cause),
+                    None => err
+                }
+            },
+            None => {
+                let message = match kind {
+                    ErrorKind::System => String::from("A system error has occurred."),
+                    ErrorKind::Application => String::from("An application error has occurred."),
+                    ErrorKind::Syntax => String::from("The playbook is malformed."),
+                    ErrorKind::Task => String::from("A step has failed."),
+                    ErrorKind::Exit(x) => format!("A step has exited with code {}.", x)
+                };
+                Error::new(kind, message)
+            }
+        }
+    }
+}
+
+/// An exit code alone does not tell why, so the error has the generic message of its kind.
+impl From<ExitCode> for Error {
+    fn from(exit_code: ExitCode) -> Self {
+        Error::from_failure(exit_code, None)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.step {
+            Some(StepRef { index, name: Some(ref name) }) => write!(f, "Step {} ({}): ", index, name)?,
+            Some(StepRef { index, name: None }) => write!(f, "Step {}: ", index)?,
+            None => {}
+        }
+        write!(f, "{}", self.message)?;
+        if let Some(ref location) = self.location {
+            write!(f, " (at {})", location)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self.source {
+            Some(ref source) => Some(source.as_ref()),
+            None => None
+        }
+    }
+}
+
+#[test]
+fn test_error() {
+    use crate::{TaskError, TaskErrorSource};
+    let failure = Failure::from(&TaskError { msg: String::from("Container has crashed."), src: TaskErrorSource::ExitCode(137) });
+    let e = Error::from_failure(ExitCode::ErrTask, Some(failure))
+        .with_step(2, Some(String::from("Train")))
+        .with_location(Some(Location { file: String::from("some.yml"), line: 12, col: 3 }));
+    assert_eq!(e.kind, ErrorKind::Task);
+    assert_eq!(e.to_string(), "Step 3 (Train): Container has crashed. (at some.yml:12:3)");
+    assert!(std::error::Error::source(&e).is_some());
+    let exit_code: i32 = e.exit_code().into();
+    assert_eq!(exit_code, 4);
+    assert_eq!(Error::from_failure(ExitCode::Any(7), None).kind, ErrorKind::Exit(7));
+    // * Converting an exit code leaves the reason of the last failure alone.
+    set_failure(Some(Failure::new(String::from("Container has crashed."))));
+    assert_eq!(Error::from(ExitCode::ErrYML).message, "The playbook is malformed.");
+    assert!(crate::take_failure().is_some());
+}
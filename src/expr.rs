@@ -0,0 +1,314 @@
+//! A tiny expression language to make decisions upon a context, e.g.
+//!
+//! ```yaml
+//! when: "gpus > 0 and mode == 'train'"
+//! ```
+//!
+//! * Literals: `'string'`, `"string"`, `42`, `0.1`, `true`, `false`, `none`, `[1, 2, 3]`
+//! * Keys: `gpus`, `docker.image` (nested contexts are reached with dots)
+//! * Comparisons: `==`, `!=`, `<`, `<=`, `>`, `>=`, `in`, `not in`
+//! * Key existence: `key is defined`, `key is not defined`
+//! * Boolean logic: `and`, `or`, `not`, parentheses
+
+use ymlctx::context::{Context, CtxObj};
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Str(String),
+    Int(i64),
+    Real(f64),
+    Op(&'static str),
+}
+
+const OPERATORS: [&str; 11] = ["==", "!=", "<=", ">=", "<", ">", "(", ")", "[", "]", ","];
+
+fn tokenize(src: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = src.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    'scan: while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+        for op in OPERATORS.iter() {
+            let op_chars: Vec<char> = op.chars().collect();
+            if chars[i..].starts_with(&op_chars) {
+                tokens.push(Token::Op(op));
+                i += op_chars.len();
+                continue 'scan;
+            }
+        }
+        if c == '\'' || c == '"' {
+            let mut s = String::new();
+            i += 1;
+            loop {
+                match chars.get(i) {
+                    Some(&'\\') => {
+                        if let Some(&escaped) = chars.get(i+1) { s.push(escaped); }
+                        i += 2;
+                    },
+                    Some(&q) if q == c => { i += 1; break; },
+                    Some(&other) => { s.push(other); i += 1; },
+                    None => { return Err(String::from("Unterminated string literal.")); }
+                }
+            }
+            tokens.push(Token::Str(s));
+        }
+        else if c.is_ascii_digit() || (c == '-' && chars.get(i+1).is_some_and(|d| d.is_ascii_digit())) {
+            let start = i;
+            i += 1;
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') { i += 1; }
+            let literal: String = chars[start..i].iter().collect();
+            if let Ok(v) = literal.parse::<i64>() { tokens.push(Token::Int(v)); }
+            else if let Ok(v) = literal.parse::<f64>() { tokens.push(Token::Real(v)); }
+            else { return Err(format!("Invalid number `{}`.", literal)); }
+        }
+        else if c.is_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_' || chars[i] == '-' || chars[i] == '.') { i += 1; }
+            tokens.push(Token::Ident(chars[start..i].iter().collect()));
+        }
+        else {
+            return Err(format!("Unexpected character `{}`.", c));
+        }
+    }
+    Ok(tokens)
+}
+
+/// Look up a key in a context, where dots reach into nested contexts
+pub fn lookup<'a>(ctx: &'a Context, path: &str) -> Option<&'a CtxObj> {
+    let mut keys = path.split('.');
+    let mut current = ctx.get(keys.next()?)?;
+    for key in keys {
+        if let CtxObj::Context(subctx) = current {
+            current = subctx.get(key)?;
+        }
+        else { return None; }
+    }
+    Some(current)
+}
+
+fn truthy(val: &CtxObj) -> bool {
+    match val {
+        CtxObj::None => false,
+        CtxObj::Bool(b) => *b,
+        CtxObj::Int(i) => *i != 0,
+        CtxObj::Real(r) => *r != 0.0,
+        CtxObj::Str(s) => !s.is_empty(),
+        CtxObj::Bin(b) => !b.is_empty(),
+        CtxObj::Array(a) => !a.is_empty(),
+        CtxObj::Context(c) => c.keys().next().is_some()
+    }
+}
+
+fn equals(a: &CtxObj, b: &CtxObj) -> bool {
+    match (a, b) {
+        (CtxObj::Int(x), CtxObj::Real(y)) | (CtxObj::Real(y), CtxObj::Int(x)) => (*x as f64) == *y,
+        _ => a == b
+    }
+}
+
+fn compare(a: &CtxObj, b: &CtxObj) -> Result<std::cmp::Ordering, String> {
+    let ordering = match (a, b) {
+        (CtxObj::Int(x), CtxObj::Int(y)) => Some(x.cmp(y)),
+        (CtxObj::Int(x), CtxObj::Real(y)) => (*x as f64).partial_cmp(y),
+        (CtxObj::Real(x), CtxObj::Int(y)) => x.partial_cmp(&(*y as f64)),
+        (CtxObj::Real(x), CtxObj::Real(y)) => x.partial_cmp(y),
+        (CtxObj::Str(x), CtxObj::Str(y)) => Some(x.cmp(y)),
+        _ => None
+    };
+    ordering.ok_or_else(|| format!("Cannot compare {:?} with {:?}.", a, b))
+}
+
+fn contains(haystack: &CtxObj, needle: &CtxObj) -> Result<bool, String> {
+    match (haystack, needle) {
+        (CtxObj::Array(items), _) => Ok(items.iter().any(|item| equals(item, needle))),
+        (CtxObj::Str(s), CtxObj::Str(sub)) => Ok(s.contains(sub as &str)),
+        (CtxObj::Context(ctx), CtxObj::Str(key)) => Ok(ctx.get(key).is_some()),
+        _ => Err(format!("Cannot test whether {:?} is in {:?}.", needle, haystack))
+    }
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    pos: usize,
+    ctx: &'a Context
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn accept_word(&mut self, word: &str) -> bool {
+        if let Some(Token::Ident(w)) = self.peek() {
+            if w == word {
+                self.pos += 1;
+                return true;
+            }
+        }
+        false
+    }
+
+    fn accept_op(&mut self, op: &str) -> bool {
+        if let Some(Token::Op(o)) = self.peek() {
+            if *o == op {
+                self.pos += 1;
+                return true;
+            }
+        }
+        false
+    }
+
+    fn expect_op(&mut self, op: &str) -> Result<(), String> {
+        if self.accept_op(op) { Ok(()) }
+        else { Err(format!("Expected `{}`.", op)) }
+    }
+
+    fn or(&mut self) -> Result<CtxObj, String> {
+        let mut lhs = self.and()?;
+        while self.accept_word("or") {
+            let rhs = self.and()?;
+            lhs = CtxObj::Bool(truthy(&lhs) || truthy(&rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn and(&mut self) -> Result<CtxObj, String> {
+        let mut lhs = self.not()?;
+        while self.accept_word("and") {
+            let rhs = self.not()?;
+            lhs = CtxObj::Bool(truthy(&lhs) && truthy(&rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn not(&mut self) -> Result<CtxObj, String> {
+        if self.accept_word("not") {
+            Ok(CtxObj::Bool(!truthy(&self.not()?)))
+        }
+        else { self.comparison() }
+    }
+
+    fn comparison(&mut self) -> Result<CtxObj, String> {
+        if let (Some(Token::Ident(key)), Some(Token::Ident(is))) = (self.tokens.get(self.pos).cloned(), self.tokens.get(self.pos+1)) {
+            if is == "is" {
+                self.pos += 2;
+                let negated = self.accept_word("not");
+                if !self.accept_word("defined") {
+                    return Err(String::from("Expected `defined` after `is`."));
+                }
+                return Ok(CtxObj::Bool(lookup(self.ctx, &key).is_some() != negated));
+            }
+        }
+        let lhs = self.primary()?;
+        if self.accept_word("in") {
+            let rhs = self.primary()?;
+            return contains(&rhs, &lhs).map(CtxObj::Bool);
+        }
+        if let (Some(Token::Ident(not)), Some(Token::Ident(is_in))) = (self.tokens.get(self.pos), self.tokens.get(self.pos+1)) {
+            if not == "not" && is_in == "in" {
+                self.pos += 2;
+                let rhs = self.primary()?;
+                return contains(&rhs, &lhs).map(|b| CtxObj::Bool(!b));
+            }
+        }
+        let op = match self.peek() {
+            Some(Token::Op(op)) if ["==", "!=", "<", "<=", ">", ">="].contains(op) => *op,
+            _ => { return Ok(lhs); }
+        };
+        self.pos += 1;
+        let rhs = self.primary()?;
+        let result = match op {
+            "==" => equals(&lhs, &rhs),
+            "!=" => !equals(&lhs, &rhs),
+            "<" => compare(&lhs, &rhs)? == std::cmp::Ordering::Less,
+            "<=" => compare(&lhs, &rhs)? != std::cmp::Ordering::Greater,
+            ">" => compare(&lhs, &rhs)? == std::cmp::Ordering::Greater,
+            ">=" => compare(&lhs, &rhs)? != std::cmp::Ordering::Less,
+            _ => unreachable!()
+        };
+        Ok(CtxObj::Bool(result))
+    }
+
+    fn primary(&mut self) -> Result<CtxObj, String> {
+        match self.next() {
+            Some(Token::Str(s)) => Ok(CtxObj::Str(s)),
+            Some(Token::Int(i)) => Ok(CtxObj::Int(i)),
+            Some(Token::Real(r)) => Ok(CtxObj::Real(r)),
+            Some(Token::Op("(")) => {
+                let val = self.or()?;
+                self.expect_op(")")?;
+                Ok(val)
+            },
+            Some(Token::Op("[")) => {
+                let mut items = Vec::new();
+                if !self.accept_op("]") {
+                    loop {
+                        items.push(self.primary()?);
+                        if self.accept_op("]") { break; }
+                        self.expect_op(",")?;
+                    }
+                }
+                Ok(CtxObj::Array(items))
+            },
+            Some(Token::Ident(word)) => match word.as_str() {
+                "true" | "True" => Ok(CtxObj::Bool(true)),
+                "false" | "False" => Ok(CtxObj::Bool(false)),
+                "none" | "None" | "null" => Ok(CtxObj::None),
+                key => match lookup(self.ctx, key) {
+                    Some(val) => Ok(val.clone()),
+                    None => Err(format!("Key `{}` is not defined.", key))
+                }
+            },
+            Some(token) => Err(format!("Unexpected token {:?}.", token)),
+            None => Err(String::from("Unexpected end of expression."))
+        }
+    }
+}
+
+/// Evaluate an expression against a context, and tell whether the result is truthy.
+pub fn eval_bool(expr: &str, ctx: &Context) -> Result<bool, String> {
+    let mut parser = Parser { tokens: tokenize(expr)?, pos: 0, ctx };
+    let val = parser.or()?;
+    if parser.pos < parser.tokens.len() {
+        return Err(format!("Unexpected token {:?}.", parser.tokens[parser.pos]));
+    }
+    Ok(truthy(&val))
+}
+
+#[test]
+fn test_eval_comparisons() {
+    let ctx = Context::from("gpus: 2\nmode: train\nlr: 0.01");
+    assert_eq!(eval_bool("gpus > 0 and mode == 'train'", &ctx), Ok(true));
+    assert_eq!(eval_bool("gpus >= 2.0", &ctx), Ok(true));
+    assert_eq!(eval_bool("lr < 0.001 or mode != \"train\"", &ctx), Ok(false));
+    assert_eq!(eval_bool("not (gpus == 2)", &ctx), Ok(false));
+}
+
+#[test]
+fn test_eval_existence() {
+    let ctx = Context::from("docker:\n  image: ubuntu\ntags: [a, b]");
+    assert_eq!(eval_bool("docker.image is defined", &ctx), Ok(true));
+    assert_eq!(eval_bool("docker.runtime is not defined", &ctx), Ok(true));
+    assert_eq!(eval_bool("'a' in tags and 'image' in docker", &ctx), Ok(true));
+    assert_eq!(eval_bool("'c' not in tags", &ctx), Ok(true));
+}
+
+#[test]
+fn test_eval_errors() {
+    let ctx = Context::from("gpus: 2");
+    assert!(eval_bool("missing == 1", &ctx).is_err());
+    assert!(eval_bool("gpus >", &ctx).is_err());
+    assert!(eval_bool("gpus == 2 2", &ctx).is_err());
+    assert!(eval_bool("gpus > 'x'", &ctx).is_err());
+}
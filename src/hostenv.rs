@@ -0,0 +1,199 @@
+//! Host environment variables that a playbook imports into its context, by an allowlist
+//!
+//! **Example(s)**
+//! ```yaml
+//! env:
+//! - DATA_DIR              # required
+//! - SCRATCH:-/tmp         # with a default
+//! - CACHE_DIR:-           # optional
+//! dataset: ${DATA_DIR}/mnist
+//! steps:
+//! - action: train
+//!   log_dir: ${SCRATCH}/logs
+//!   cache: ${CACHE_DIR:-/tmp/cache}
+//! ```
+//!
+//! Each variable of the allowlist becomes a global variable of the playbook. `${VAR}` and `${VAR:-default}`
+//! are expanded in the strings of the playbook, the global variables and the steps, including those of the
+//! included playbooks. Only the variables of the allowlist may be expanded; `$${` stands for a literal `${`.
+//! Playbooks without an `env` are left as they are. The variables are handed down to containers,
+//! where the playbook is read again.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use regex::Regex;
+use ymlctx::context::{Context, CtxObj};
+use crate::builtins::ExitCode;
+
+/// Variables of the allowlist, by their names, with their values on the host or their defaults
+pub struct HostEnv {
+    vars: HashMap<String, String>
+}
+
+/// Parse an entry of the allowlist, which is either `NAME` or `NAME:-default`
+fn parse_entry(entry: &str) -> Result<(String, Option<String>), String> {
+    let (name, default) = match entry.find(":-") {
+        Some(pos) => (&entry[..pos], Some(entry[pos+2..].to_owned())),
+        None => (entry, None)
+    };
+    let re_name = Regex::new(r"^[A-Za-z_][A-Za-z0-9_]*$").unwrap();
+    if re_name.is_match(name) {
+        Ok((name.to_owned(), default))
+    }
+    else {
+        Err(format!("`{}` is not the name of an environment variable.", name))
+    }
+}
+
+impl HostEnv {
+    /// Read the variables of an allowlist from the host, given a lookup such as `std::env::var`
+    pub fn from_ctx<F>(ctx: &Context, lookup: F) -> Result<HostEnv, String>
+      where F: Fn(&str) -> Option<String>
+    {
+        let entries = match ctx.get("env") {
+            None => Vec::new(),
+            Some(CtxObj::Array(entries)) => entries.to_owned(),
+            Some(_) => { return Err(String::from("Key `env` must be an array of the names of environment variables.")); }
+        };
+        let mut vars = HashMap::new();
+        let mut missing = Vec::new();
+        for entry in entries.iter() {
+            let (name, default) = match entry {
+                CtxObj::Str(entry) => parse_entry(entry)?,
+                _ => { return Err(String::from("Key `env` must be an array of the names of environment variables.")); }
+            };
+            match lookup(&name).or(default) {
+                Some(value) => { vars.insert(name, value); },
+                None => { missing.push(name); }
+            }
+        }
+        if !missing.is_empty() {
+            return Err(format!("Missing environment variable(s) required by the playbook: {}", missing.join(", ")));
+        }
+        Ok(HostEnv { vars })
+    }
+
+    /// Expand the references to the variables in a string
+    pub fn expand_str(&self, s: &str) -> Result<String, String> {
+        let mut ret = String::new();
+        let mut rest = s;
+        while let Some(pos) = rest.find('$') {
+            ret.push_str(&rest[..pos]);
+            rest = &rest[pos..];
+            if rest.starts_with("$${") {
+                ret.push_str("${");
+                rest = &rest[3..];
+                continue;
+            }
+            if !rest.starts_with("${") {
+                ret.push('$');
+                rest = &rest[1..];
+                continue;
+            }
+            let end = match rest.find('}') {
+                Some(end) => end,
+                None => { return Err(format!("`{}` is missing a closing brace.", rest)); }
+            };
+            let (name, default) = parse_entry(&rest[2..end])?;
+            let value = match self.vars.get(&name) {
+                Some(value) => value,
+                None => { return Err(format!("Environment variable {} is not allowed; add it to the `env` of the playbook.", name)); }
+            };
+            // * Like a shell, the default takes place of an empty value as well.
+            match default {
+                Some(ref default) if value.is_empty() => ret.push_str(default),
+                _ => ret.push_str(value)
+            }
+            rest = &rest[end+1..];
+        }
+        ret.push_str(rest);
+        Ok(ret)
+    }
+
+    pub fn expand(&self, val: &CtxObj) -> Result<CtxObj, String> {
+        Ok(match val {
+            CtxObj::Str(s) => CtxObj::Str(self.expand_str(s)?),
+            CtxObj::Array(items) => CtxObj::Array(items.iter().map(|item| self.expand(item)).collect::<Result<Vec<CtxObj>, String>>()?),
+            CtxObj::Context(ctx) => CtxObj::Context(self.expand_ctx(ctx)?),
+            _ => val.clone()
+        })
+    }
+
+    pub fn expand_ctx(&self, ctx: &Context) -> Result<Context, String> {
+        let mut ret = ctx.clone();
+        for key in ctx.keys() {
+            ret = ret.set(key, self.expand(ctx.get(key).unwrap())?);
+        }
+        Ok(ret)
+    }
+
+    /// Set the variables as those of a context
+    pub fn import(&self, ctx: Context) -> Context {
+        self.vars.iter().fold(ctx, |ctx, (name, value)| ctx.set(name, CtxObj::Str(value.to_owned())))
+    }
+}
+
+thread_local! {
+    static HOST_ENV: RefCell<Option<HostEnv>> = const { RefCell::new(None) };
+}
+
+/// Import the allowlist of a playbook into its global context, and expand the references to the variables in it
+pub(crate) fn resolve(ctx_global: Context) -> Result<Context, ExitCode> {
+    HOST_ENV.with(|host_env| host_env.replace(None));
+    if ctx_global.get("env").is_none() {
+        // * Playbooks without an allowlist are left as they are, `${` and all.
+        return Ok(ctx_global);
+    }
+    let host_env = match HostEnv::from_ctx(&ctx_global, |name| std::env::var(name).ok()) {
+        Ok(host_env) => host_env,
+        Err(e) => { return Err(fail!(ExitCode::ErrApp, "{}", e)); }
+    };
+    let ctx_global = match host_env.expand_ctx(&ctx_global.hide("env")) {
+        Ok(ctx_global) => host_env.import(ctx_global),
+        Err(e) => { return Err(fail!(ExitCode::ErrYML, "Syntax Error: {}", e)); }
+    };
+    HOST_ENV.with(|cell| cell.replace(Some(host_env)));
+    Ok(ctx_global)
+}
+
+/// Expand the references to the variables of the allowlist in some steps
+pub(crate) fn expand_steps(steps: Vec<Context>) -> Result<Vec<Context>, ExitCode> {
+    HOST_ENV.with(|host_env| match *host_env.borrow() {
+        Some(ref host_env) => match steps.iter().map(|ctx_step| host_env.expand_ctx(ctx_step)).collect() {
+            Ok(steps) => Ok(steps),
+            Err(e) => Err(fail!(ExitCode::ErrYML, "Syntax Error: {}", e))
+        },
+        None => Ok(steps)
+    })
+}
+
+/// Names of the variables to be handed to a container, given as `docker run -e NAME`
+pub(crate) fn docker_env() -> Vec<String> {
+    HOST_ENV.with(|host_env| match *host_env.borrow() {
+        Some(ref host_env) => {
+            let mut names: Vec<String> = host_env.vars.keys().cloned().collect();
+            names.sort();
+            names
+        },
+        None => Vec::new()
+    })
+}
+
+#[test]
+fn test_expand() {
+    let ctx = Context::from("env:\n- DATA_DIR\n- SCRATCH:-/tmp\n- EMPTY");
+    let lookup = |name: &str| match name {
+        "DATA_DIR" => Some(String::from("/data")),
+        "EMPTY" => Some(String::new()),
+        _ => None
+    };
+    let host_env = HostEnv::from_ctx(&ctx, lookup).unwrap();
+    assert_eq!(host_env.expand_str("${DATA_DIR}/mnist:${SCRATCH}").unwrap(), "/data/mnist:/tmp");
+    assert_eq!(host_env.expand_str("${EMPTY:-x} ${DATA_DIR:-x} $HOME $${HOME}").unwrap(), "x /data $HOME ${HOME}");
+    assert!(host_env.expand_str("${HOME}").is_err());
+    assert!(host_env.expand_str("${DATA_DIR").is_err());
+    assert_eq!(host_env.import(Context::new()).get("SCRATCH"), Some(&CtxObj::Str(String::from("/tmp"))));
+    let e = HostEnv::from_ctx(&Context::from("env:\n- DATA_DIR\n- MODELS"), |_| None).err().unwrap();
+    assert!(e.ends_with("DATA_DIR, MODELS"));
+    assert!(HostEnv::from_ctx(&Context::from("env: DATA_DIR"), lookup).is_err());
+}
@@ -0,0 +1,332 @@
+//! Incremental runs, which skip the steps whose inputs and outputs are up to date, like `make` does
+//!
+//! **Example(s)**
+//! ```yaml
+//! steps:
+//! - name: preprocess
+//!   action: preprocess
+//!   inputs:
+//!   - data/raw/**/*.csv
+//!   - ctx: sample_rate
+//!   outputs:
+//!   - data/clean.parquet
+//! - name: train
+//!   action: train
+//!   inputs: [data/clean.parquet, src/model.py]
+//!   outputs: ["models/{{ model_name }}.pt"]
+//!   docker:
+//!     image: acme/train:latest
+//! ```
+//!
+//! A step that declares `inputs` or `outputs` is fingerprinted before it runs: the files of its inputs, by their
+//! sizes and modification times, along with the keys written in the step and its docker settings as they are
+//! resolved, including the ID of its docker image. Globals and states that the action reads on its own are to be
+//! declared as context keys among the inputs, which narrow the context down to those keys, the action and the
+//! docker image. The step is skipped when its outputs exist and the fingerprint matches that of its last
+//! successful run, in which case its stateful context is replayed. Paths and globs are relative to the working
+//! directory.
+//!
+//! Fingerprints are kept next to the playbook, e.g. `.some.fingerprints.json` for `some.yml`. A step that returns
+//! a secret in its stateful context is not fingerprinted, so that the secret is never written there.
+//! `playbook --rebuild some.yml` runs every step regardless.
+
+use std::path::{Path, PathBuf};
+use std::collections::BTreeMap;
+use regex::Regex;
+use ymlctx::context::{Context, CtxObj};
+use crate::builtins::{ExitCode, TransientContext};
+use crate::expr::lookup;
+use crate::journal::Outcome;
+use crate::systems::docker;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Entry {
+    fingerprint: String,
+    time: String,
+    /// The stateful context of the step, to be replayed when it is skipped
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    states: Option<Context>
+}
+
+/// Whether a step has to run, as far as its inputs and outputs are concerned
+pub(crate) enum Freshness {
+    /// The step does not declare any inputs or outputs.
+    Untracked,
+    /// The step has to run, and is to be stamped once it has succeeded.
+    Stale(Stamp),
+    /// The step may be skipped, given the outcome of its last successful run.
+    UpToDate(Outcome)
+}
+
+/// The fingerprint of a step that is about to run
+pub(crate) struct Stamp {
+    state_file: PathBuf,
+    key: String,
+    fingerprint: String,
+    outputs: Vec<String>
+}
+
+/// Where the fingerprints of a playbook are kept
+fn state_file(playbook: &str) -> PathBuf {
+    let (fname, _stage) = crate::split_stage(playbook);
+    let path = Path::new(fname);
+    let stem = path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+    path.with_file_name(format!(".{}.fingerprints.json", stem))
+}
+
+fn load(state_file: &Path) -> BTreeMap<String, Entry> {
+    match std::fs::read_to_string(state_file) {
+        Ok(contents) => match serde_json::from_str(&contents) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("The fingerprints in {:?} are discarded: {}", state_file, e);
+                BTreeMap::new()
+            }
+        },
+        Err(_) => BTreeMap::new()
+    }
+}
+
+/// Strings of a list, e.g. `inputs` or `outputs`
+fn strings(ctx_step: &Context, key: &str) -> Result<Vec<String>, String> {
+    let items = match ctx_step.get(key) {
+        None => { return Ok(Vec::new()); },
+        Some(CtxObj::Array(items)) => items.to_owned(),
+        Some(_) => { return Err(format!("Key `{}` must be a list.", key)); }
+    };
+    let mut ret = Vec::new();
+    for item in items.iter() {
+        match item {
+            CtxObj::Str(s) => ret.push(s.to_owned()),
+            CtxObj::Context(ctx) if key == "inputs" && ctx.get("ctx").is_some() => {},
+            _ => { return Err(format!("Key `{}` must be a list of paths.", key)); }
+        }
+    }
+    Ok(ret)
+}
+
+/// Context keys among the inputs of a step, e.g. `- ctx: learning_rate`
+fn context_keys(ctx_step: &Context) -> Result<Vec<String>, String> {
+    let mut ret = Vec::new();
+    if let Some(CtxObj::Array(items)) = ctx_step.get("inputs") {
+        for item in items.iter() {
+            if let CtxObj::Context(ctx) = item {
+                match ctx.get("ctx") {
+                    Some(CtxObj::Str(key)) => ret.push(key.to_owned()),
+                    _ => { return Err(String::from("A context key among `inputs` must be given as `ctx: key`.")); }
+                }
+            }
+        }
+    }
+    Ok(ret)
+}
+
+/// A value as JSON with its keys sorted, so that it is the same from one run to the next
+fn canonical(val: &CtxObj) -> serde_json::Value {
+    match val {
+        CtxObj::Str(s) => serde_json::Value::from(s.to_owned()),
+        CtxObj::Bin(bytes) => serde_json::Value::from(bytes.to_owned()),
+        CtxObj::Int(x) => serde_json::Value::from(*x),
+        CtxObj::Real(x) => serde_json::Value::from(*x),
+        CtxObj::Bool(x) => serde_json::Value::from(*x),
+        CtxObj::Array(items) => serde_json::Value::Array(items.iter().map(canonical).collect()),
+        CtxObj::Context(ctx) => {
+            let map: BTreeMap<String, serde_json::Value> = ctx.keys().map(|key| (key.to_owned(), canonical(ctx.get(key).unwrap()))).collect();
+            serde_json::to_value(map).unwrap()
+        },
+        CtxObj::None => serde_json::Value::Null
+    }
+}
+
+/// Translate a glob into a regular expression, where `**` matches any number of directories
+fn glob_regex(pattern: &str) -> Regex {
+    let mut re = String::from("^");
+    let mut rest = pattern;
+    while let Some(c) = rest.chars().next() {
+        if rest.starts_with("**/") {
+            re.push_str("(?:.*/)?");
+            rest = &rest[3..];
+            continue;
+        }
+        if rest.starts_with("**") {
+            re.push_str(".*");
+            rest = &rest[2..];
+            continue;
+        }
+        match c {
+            '*' => re.push_str("[^/]*"),
+            '?' => re.push_str("[^/]"),
+            _ => re.push_str(&regex::escape(&c.to_string()))
+        }
+        rest = &rest[c.len_utf8()..];
+    }
+    re.push('$');
+    Regex::new(&re).unwrap()
+}
+
+/// Files under a directory, recursively, without following symbolic links to directories
+fn walk(dir: &Path, files: &mut Vec<PathBuf>) {
+    if let Ok(entries) = std::fs::read_dir(dir) {
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            let path = entry.path();
+            match entry.file_type() {
+                Ok(file_type) if file_type.is_dir() => walk(&path, files),
+                Ok(_) => files.push(path),
+                Err(_) => {}
+            }
+        }
+    }
+}
+
+/// Files of an input, which is either a path to a file or a directory, or a glob
+fn files(input: &str) -> Result<Vec<PathBuf>, String> {
+    let mut ret = Vec::new();
+    if !input.contains(['*', '?']) {
+        let path = Path::new(input);
+        if path.is_dir() { walk(path, &mut ret); }
+        else if path.exists() { ret.push(path.to_path_buf()); }
+        else { return Err(format!("Cannot find the input `{}`.", input)); }
+        return Ok(ret);
+    }
+    // * Walk from the deepest directory that is free of wildcards.
+    let base: Vec<&str> = input.split('/').take_while(|part| !part.contains(['*', '?'])).collect();
+    let base = base.join("/");
+    let re = glob_regex(input);
+    let mut candidates = Vec::new();
+    walk(Path::new(if base.is_empty() { "." } else { &base }), &mut candidates);
+    for path in candidates {
+        let path_str = path.to_string_lossy();
+        let path_str = if base.is_empty() { path_str.trim_start_matches("./") } else { &path_str };
+        if re.is_match(path_str) { ret.push(path); }
+    }
+    Ok(ret)
+}
+
+/// Fingerprint the inputs and the resolved context of a step, as far as the step reads it
+pub fn fingerprint(ctx_step: &Context, ctx_step_raw: &Context) -> Result<String, String> {
+    let keys = context_keys(ctx_step)?;
+    let image = match lookup(ctx_step, "docker.image") {
+        Some(CtxObj::Str(image)) => Some(docker::image_id(image).unwrap_or_else(|| image.to_owned())),
+        _ => None
+    };
+    let ctx = if keys.is_empty() {
+        ctx_step_raw.keys().map(String::as_str).chain(std::iter::once("docker"))
+            .fold(Context::new(), |ctx, key| ctx.set_opt(key, ctx_step.get_clone(key)))
+    }
+    else {
+        keys.iter().fold(Context::new(), |ctx, key| ctx.set_opt(key, lookup(ctx_step, key).cloned()))
+            .set_opt("action", ctx_step.get_clone("action"))
+    };
+    let mut files_in: Vec<PathBuf> = Vec::new();
+    for input in strings(ctx_step, "inputs")? {
+        files_in.extend(files(&input)?);
+    }
+    files_in.sort();
+    files_in.dedup();
+    let mut stats = Vec::new();
+    for path in files_in.iter() {
+        let metadata = match std::fs::metadata(path) {
+            Ok(metadata) => metadata,
+            Err(e) => { return Err(format!("IO Error (while reading the input {:?}): {}", path, e)); }
+        };
+        let mtime = metadata.modified().ok()
+            .and_then(|mtime| mtime.duration_since(std::time::UNIX_EPOCH).ok())
+            .map(|mtime| mtime.as_nanos()).unwrap_or(0);
+        stats.push(serde_json::json!([path.to_string_lossy(), metadata.len(), mtime.to_string()]));
+    }
+    let seed = serde_json::json!({ "context": canonical(&CtxObj::Context(ctx)), "image": image, "inputs": stats });
+    Ok(uuid::Uuid::new_v5(&uuid::Uuid::NAMESPACE_URL, seed.to_string().as_bytes()).to_string())
+}
+
+/// Tell whether a step has to run, given its deduced context and its 0-based index
+pub(crate) fn freshness(ctx_step: &Context, ctx_step_raw: &Context, ctx_args: &Context, i: usize) -> Result<Freshness, ExitCode> {
+    if ctx_step.get("inputs").is_none() && ctx_step.get("outputs").is_none() {
+        return Ok(Freshness::Untracked);
+    }
+    let ctx_step = match crate::template::render(ctx_step, ctx_step_raw) {
+        Ok(ctx_step) => ctx_step,
+        Err(e) => { return Err(fail!(ExitCode::ErrYML, "Syntax Error: {}", e)); }
+    };
+    let playbook = match ctx_args.get("playbook") {
+        Some(CtxObj::Str(playbook)) => playbook.as_str(),
+        _ => ""
+    };
+    // * The arguments of a run are not part of a step, except for the overrides that have been applied.
+    let ctx_fingerprinted = ctx_args.keys().fold(ctx_step.clone(), |ctx, key| ctx.hide(key));
+    let (outputs, fingerprint) = match strings(&ctx_step, "outputs").and_then(|outputs| Ok((outputs, fingerprint(&ctx_fingerprinted, ctx_step_raw)?))) {
+        Ok(ret) => ret,
+        Err(e) => { return Err(fail!(ExitCode::ErrApp, "Step {}: {}", i+1, e)); }
+    };
+    let key = match ctx_step.get("name") {
+        Some(CtxObj::Str(name)) => name.to_owned(),
+        _ => format!("Step {}", i+1)
+    };
+    let stamp = Stamp { state_file: state_file(playbook), key, fingerprint, outputs };
+    if let Some(CtxObj::Bool(true)) = ctx_args.get("rebuild") {
+        return Ok(Freshness::Stale(stamp));
+    }
+    match load(&stamp.state_file).remove(&stamp.key) {
+        Some(entry) if entry.fingerprint == stamp.fingerprint && stamp.outputs_exist() => Ok(Freshness::UpToDate(match entry.states {
+            Some(ctx_states) => Outcome::Stateful(ctx_states),
+            None => Outcome::Stateless
+        })),
+        _ => Ok(Freshness::Stale(stamp))
+    }
+}
+
+impl Stamp {
+    fn outputs_exist(&self) -> bool {
+        self.outputs.iter().all(|output| Path::new(output).exists())
+    }
+
+    /// Keep the fingerprint of a step that has succeeded and produced its outputs
+    pub(crate) fn record(&self, ret: &TransientContext) {
+        let states = match ret {
+            TransientContext::Stateful(ctx_states) => Some(ctx_states.to_owned()),
+            TransientContext::Stateless(_) => None,
+            TransientContext::Diverging(_) => { return; }
+        };
+        if !self.outputs_exist() {
+            warn!("{}: Some of the outputs are missing, so the step will run again next time.", self.key);
+            return;
+        }
+        let mut entries = load(&self.state_file);
+        // * States are kept in plain text to be replayed, which is no place for a secret.
+        if states.as_ref().is_some_and(|ctx_states| crate::secrets::reveals(&CtxObj::Context(ctx_states.to_owned()))) {
+            info!("{}: The step has returned a secret, so it is not fingerprinted and will run again next time.", self.key);
+            if entries.remove(&self.key).is_none() { return; }
+        }
+        else {
+            entries.insert(self.key.to_owned(), Entry { fingerprint: self.fingerprint.to_owned(), time: chrono::Local::now().to_rfc3339(), states });
+        }
+        let contents = serde_json::to_string_pretty(&entries).unwrap();
+        if let Err(e) = std::fs::write(&self.state_file, contents) {
+            warn!("IO Error (while writing the fingerprints {:?}): {}", self.state_file, e);
+        }
+    }
+}
+
+#[test]
+fn test_glob() {
+    let re = glob_regex("data/**/*.csv");
+    assert!(re.is_match("data/a.csv"));
+    assert!(re.is_match("data/raw/2019/a.csv"));
+    assert!(!re.is_match("data/raw/a.csv.gz"));
+    assert!(!glob_regex("src/*.py").is_match("src/models/a.py"));
+    assert!(glob_regex("log?.txt").is_match("log1.txt"));
+}
+
+#[test]
+fn test_fingerprint() {
+    let ctx = Context::from("action: train\nlearning_rate: 0.1\nepochs: 10\ninputs:\n- ctx: learning_rate\n- Cargo.toml");
+    let fingerprint0 = fingerprint(&ctx, &ctx).unwrap();
+    assert_eq!(fingerprint(&ctx.set("epochs", CtxObj::Int(20)), &ctx).unwrap(), fingerprint0);
+    assert_ne!(fingerprint(&ctx.set("learning_rate", CtxObj::Real(0.01)), &ctx).unwrap(), fingerprint0);
+    let ctx_missing = ctx.set("inputs", CtxObj::Array(vec![CtxObj::Str(String::from("missing.txt"))]));
+    assert!(fingerprint(&ctx_missing, &ctx_missing).is_err());
+    let ctx = Context::from("action: train\nlearning_rate: 0.1\nepochs: 10");
+    assert_ne!(fingerprint(&ctx.set("epochs", CtxObj::Int(20)), &ctx).unwrap(), fingerprint(&ctx, &ctx).unwrap());
+    // * Without context keys among the inputs, only the keys written in the step count.
+    let ctx_raw = Context::from("action: train\nepochs: 10");
+    assert_eq!(fingerprint(&ctx.set("learning_rate", CtxObj::Real(0.01)), &ctx_raw).unwrap(), fingerprint(&ctx, &ctx_raw).unwrap());
+}
@@ -0,0 +1,274 @@
+//! Durable journal of a run, so that it can be resumed after a crash
+//!
+//! Runs are persisted under `~/.playbook-rs/runs/<run-id>/` after every step, unless given `--no-journal`,
+//! including the stateful context accumulated so far and the outcome of each step.
+//!
+//! **Example(s)**
+//! ```sh
+//! playbook some.yml
+//! playbook --resume 20190412-153000-4242
+//! ```
+//!
+//! A resumed run continues from the first incomplete step, with the stateful context restored.
+//! Resuming is a flag rather than a `resume` subcommand, because the first positional argument
+//! is always the playbook, and a playbook named `resume` must still be runnable.
+
+use std::path::PathBuf;
+use std::collections::HashMap;
+use std::time::Instant;
+use ymlctx::context::{Context, CtxObj};
+use crate::builtins::{TransientContext, ExitCode};
+use crate::Failure;
+use crate::error::Error;
+
+const JOURNAL_FILE: &str = "journal.json";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Status {
+    Running,
+    Succeeded,
+    Failed
+}
+
+/// The outcome of a step, as it has been journaled
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Outcome {
+    Stateful(Context),
+    Stateless,
+    Skipped,
+    Diverging(i32)
+}
+
+/// A child process that a step has forked, e.g. by `sys_fork`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Child {
+    pub params: Context,
+    pub exit_code: Option<i32>,
+    pub signal: Option<String>
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Record {
+    pub step_ptr: usize,
+    pub outcome: Outcome,
+    /// When the step has completed
+    pub time: String,
+    #[serde(default)]
+    pub started: Option<String>,
+    /// In seconds
+    #[serde(default)]
+    pub duration: Option<f64>,
+    #[serde(default)]
+    pub(crate) failure: Option<Failure>,
+    #[serde(default)]
+    pub children: Vec<Child>
+}
+
+thread_local! {
+    static CHILDREN: std::cell::RefCell<Vec<Child>> = const { std::cell::RefCell::new(Vec::new()) };
+}
+
+/// Remember a child process that the current step has forked, to be journaled along with its outcome
+pub(crate) fn add_child(child: Child) {
+    CHILDREN.with(|children| children.borrow_mut().push(child));
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Journal {
+    #[serde(skip)]
+    dir: Option<PathBuf>,
+    /// Working directory of the run, against which the paths in the arguments are resolved
+    pub cwd: PathBuf,
+    /// Command line arguments of the run
+    pub args: Context,
+    pub ctx_states: Context,
+    pub steps: Vec<Record>,
+    pub status: Status,
+    /// When each of the running steps has started
+    #[serde(skip)]
+    started: HashMap<usize, (String, Instant)>
+}
+
+/// Allocate an identifier for a new run
+pub fn new_run_id() -> String {
+    format!("{}-{}", chrono::Local::now().format("%Y%m%d-%H%M%S"), std::process::id())
+}
+
+fn run_dir(run_id: &str) -> Result<PathBuf, ExitCode> {
+    match dirs::home_dir() {
+        Some(home) => Ok(home.join(".playbook-rs").join("runs").join(run_id)),
+        None => {
+            Err(fail!(ExitCode::ErrSys, "Cannot determine the HOME directory."))
+        }
+    }
+}
+
+impl Journal {
+    /// The journal of the run given by the `run-id` argument, which is restored if it has been persisted.
+    /// Without a `run-id`, nothing is journaled.
+    pub fn open(ctx_args: &Context) -> Result<Journal, Error> {
+        let run_id = match ctx_args.get("run-id") {
+            Some(CtxObj::Str(run_id)) => run_id,
+            _ => {
+                return Ok(Journal {
+                    dir: None,
+                    cwd: PathBuf::new(),
+                    args: ctx_args.clone(),
+                    ctx_states: Context::new(),
+                    steps: Vec::new(),
+                    status: Status::Running,
+                    started: HashMap::new()
+                });
+            }
+        };
+        let dir = run_dir(run_id).map_err(crate::diverged)?;
+        if dir.join(JOURNAL_FILE).exists() {
+            let mut journal = Journal::load(run_id).map_err(crate::diverged)?;
+            info!("Resuming run {} from {} journaled step(s).", run_id, journal.steps.len());
+            journal.dir = Some(dir);
+            journal.status = Status::Running;
+            return Ok(journal);
+        }
+        if let Err(e) = std::fs::create_dir_all(&dir) {
+            return Err(failed!(ExitCode::ErrSys, "IO Error (while allocating {:?}): {}", dir, e));
+        }
+        info!("Run ID: {}", run_id);
+        let journal = Journal {
+            dir: Some(dir),
+            cwd: std::env::current_dir().unwrap_or_default(),
+            args: ctx_args.clone(),
+            ctx_states: Context::new(),
+            steps: Vec::new(),
+            status: Status::Running,
+            started: HashMap::new()
+        };
+        journal.persist();
+        Ok(journal)
+    }
+
+    /// Read the journal of a run
+    pub fn load(run_id: &str) -> Result<Journal, ExitCode> {
+        let path = run_dir(run_id)?.join(JOURNAL_FILE);
+        let contents = match std::fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) => {
+                return Err(fail!(ExitCode::ErrSys, "IO Error (while loading the journal {:?}): {}", path, e));
+            }
+        };
+        match serde_json::from_str::<Journal>(&contents) {
+            Ok(journal) => Ok(journal),
+            Err(e) => {
+                Err(fail!(ExitCode::ErrApp, "Cannot parse the journal {:?}: {}", path, e))
+            }
+        }
+    }
+
+    pub fn run_id(&self) -> Option<&str> {
+        match self.args.get("run-id") {
+            Some(CtxObj::Str(run_id)) if self.dir.is_some() => Some(run_id),
+            _ => None
+        }
+    }
+
+    /// The latest outcome of a step, if it has completed
+    pub fn completed(&self, step_ptr: usize) -> Option<&Outcome> {
+        match self.latest(step_ptr) {
+            Some(Record { outcome: Outcome::Diverging(_), .. }) | None => None,
+            Some(record) => Some(&record.outcome)
+        }
+    }
+
+    /// Take note of a step that has started
+    pub fn start(&mut self, step_ptr: usize) {
+        self.started.insert(step_ptr, (chrono::Local::now().to_rfc3339(), Instant::now()));
+    }
+
+    /// Journal the outcome of a step, given the stateful context accumulated so far.
+    /// The outcomes are kept in memory even if the run is not persisted.
+    pub fn record(&mut self, step_ptr: usize, outcome: Outcome, ctx_states: &Context) {
+        // * Processes forked by sys_fork do not own the journal.
+        if let Some(CtxObj::Bool(true)) = ctx_states.get("_exit") {
+            return;
+        }
+        let (started, duration) = match self.started.remove(&step_ptr) {
+            Some((started, t0)) => (Some(started), Some(t0.elapsed().as_secs_f64())),
+            None => (None, None)
+        };
+        let failure = match outcome {
+            Outcome::Diverging(_) => crate::peek_failure(),
+            _ => None
+        };
+        let children = CHILDREN.with(|children| children.borrow_mut().drain(..).collect());
+        self.ctx_states = ctx_states.clone();
+        self.steps.push(Record { step_ptr, outcome, time: chrono::Local::now().to_rfc3339(), started, duration, failure, children });
+        self.persist();
+    }
+
+    /// The latest record of a step, if it has run
+    pub fn latest(&self, step_ptr: usize) -> Option<&Record> {
+        self.steps.iter().rev().find(|record| record.step_ptr == step_ptr)
+    }
+
+    pub fn finish(&mut self, status: Status) {
+        if self.dir.is_none() {
+            return;
+        }
+        if status == Status::Failed {
+            if let Some(run_id) = self.run_id() {
+                warn!("To resume this run: playbook --resume {}", run_id);
+            }
+        }
+        self.status = status;
+        self.persist();
+    }
+
+    /// Write the journal to a temporary file before renaming it, so that it is never left half-written.
+    fn persist(&self) {
+        let dir = match self.dir {
+            Some(ref dir) => dir,
+            None => { return; }
+        };
+        let tmp = dir.join(format!("{}.tmp", JOURNAL_FILE));
+        let ret = serde_json::to_string(self)
+            .map_err(|e| e.to_string())
+            .and_then(|contents| std::fs::write(&tmp, contents).map_err(|e| e.to_string()))
+            .and_then(|()| std::fs::rename(&tmp, dir.join(JOURNAL_FILE)).map_err(|e| e.to_string()));
+        if let Err(e) = ret {
+            warn!("Failed to journal the run: {}", e);
+        }
+    }
+}
+
+impl From<&TransientContext> for Outcome {
+    fn from(x: &TransientContext) -> Self {
+        match x {
+            TransientContext::Stateful(ctx) => Outcome::Stateful(ctx.clone()),
+            TransientContext::Stateless(_) => Outcome::Stateless,
+            TransientContext::Diverging(exit_code) => Outcome::Diverging(exit_code.clone().into())
+        }
+    }
+}
+
+#[test]
+fn test_journal_completed() {
+    let mut journal = Journal {
+        dir: None,
+        cwd: PathBuf::new(),
+        args: Context::new(),
+        ctx_states: Context::new(),
+        steps: Vec::new(),
+        status: Status::Running,
+        started: HashMap::new()
+    };
+    let record = |step_ptr, outcome| Record { step_ptr, outcome, time: String::new(), started: None, duration: None, failure: None, children: Vec::new() };
+    journal.steps.push(record(0, Outcome::Stateless));
+    journal.steps.push(record(1, Outcome::Diverging(4)));
+    journal.steps.push(record(2, Outcome::Diverging(4)));
+    journal.steps.push(record(2, Outcome::Skipped));
+    assert_eq!(journal.completed(0), Some(&Outcome::Stateless));
+    assert_eq!(journal.completed(1), None);
+    assert_eq!(journal.completed(2), Some(&Outcome::Skipped));
+    assert_eq!(journal.completed(3), None);
+    let restored: Journal = serde_json::from_str(&serde_json::to_string(&journal).unwrap()).unwrap();
+    assert_eq!(restored.steps, journal.steps);
+}
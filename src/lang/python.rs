@@ -7,7 +7,7 @@ use std::io::Write; // for flushing stdio
 #[cfg(feature = "lang_python")]
 use pyo3::prelude::*;
 #[cfg(feature = "lang_python")]
-use pyo3::types::PyList;
+use pyo3::types::{PyObjectRef, PyList, PyTuple, PyDict, PyString, PyBool};
 
 /// Flush stdout & stderr
 #[allow(unused_must_use)]
@@ -18,8 +18,51 @@ fn flush_stdio(py: pyo3::Python) {
     std::io::stdout().flush();
 }
 
+/// Convert a Python object returned by an action into a context element
 #[cfg(feature = "lang_python")]
-pub fn invoke(src: Context, ctx_step: Context) -> Result<(), TaskError> {
+fn to_ctxobj(obj: &PyObjectRef) -> Option<CtxObj> {
+    if obj.is_none() {
+        Some(CtxObj::None)
+    }
+    else if let Ok(val) = obj.cast_as::<PyBool>() {
+        Some(CtxObj::Bool(val.is_true()))
+    }
+    else if let Ok(val) = obj.cast_as::<PyString>() {
+        val.to_string().ok().map(|s| CtxObj::Str(s.into_owned()))
+    }
+    else if let Ok(val) = obj.cast_as::<PyDict>() {
+        to_context(val).map(CtxObj::Context)
+    }
+    else if let Ok(val) = obj.cast_as::<PyList>() {
+        val.iter().map(to_ctxobj).collect::<Option<Vec<CtxObj>>>().map(CtxObj::Array)
+    }
+    else if let Ok(val) = obj.cast_as::<PyTuple>() {
+        val.iter().map(to_ctxobj).collect::<Option<Vec<CtxObj>>>().map(CtxObj::Array)
+    }
+    else if let Ok(val) = obj.extract::<i64>() {
+        Some(CtxObj::Int(val))
+    }
+    else if let Ok(val) = obj.extract::<f64>() {
+        Some(CtxObj::Real(val))
+    }
+    else { None }
+}
+
+/// Convert a Python dict into a context, which requires all keys to be strings
+#[cfg(feature = "lang_python")]
+fn to_context(dict: &PyDict) -> Option<Context> {
+    let mut ctx = Context::new();
+    for (k, v) in dict.iter() {
+        let key = k.cast_as::<PyString>().ok()?.to_string().ok()?;
+        ctx = ctx.set(&key, to_ctxobj(v)?);
+    }
+    Some(ctx)
+}
+
+/// Invoke a Python action.
+/// A dict returned by the action becomes its resulting context, whereas `None` results in an empty one.
+#[cfg(feature = "lang_python")]
+pub fn invoke(src: Context, ctx_step: Context) -> Result<Context, TaskError> {
     let gil = Python::acquire_gil();
     let py = gil.python();
     let syspath: &PyList = py.import("sys").unwrap().get("path").unwrap().try_into().unwrap();
@@ -50,10 +93,21 @@ pub fn invoke(src: Context, ctx_step: Context) -> Result<(), TaskError> {
 
     if let Ok(mod_py) = py.import(mod_name) {
         let ref action: String = ctx_step.unpack("action").unwrap();
-        match mod_py.call_method1(action, (ctx_step.to_object(py), )) {
-            Ok(_) => {
+        // * An action may be an alias of its function.
+        let ref function: String = src.unpack("function").unwrap_or_else(|_| action.to_owned());
+        match mod_py.call_method1(function, (ctx_step.to_object(py), )) {
+            Ok(ret) => {
                 flush_stdio(py);
-                Ok(())
+                if ret.is_none() {
+                    Ok(Context::new())
+                }
+                else if let Some(ctx_ret) = ret.cast_as::<PyDict>().ok().and_then(to_context) {
+                    Ok(ctx_ret)
+                }
+                else {
+                    error!("The step action `{}` should return either a dict with string keys or None.", action);
+                    Err(TaskError { msg: String::from("The step action has returned an unsupported value."), src: TaskErrorSource::Internal })
+                }
             },
             Err(e) => {
                 flush_stdio(py);
@@ -66,3 +120,16 @@ pub fn invoke(src: Context, ctx_step: Context) -> Result<(), TaskError> {
         Err(TaskError { msg: String::from("Failed to import the step action source module."), src: TaskErrorSource::Internal })
     }
 }
+
+#[cfg(feature = "lang_python")]
+#[test]
+fn test_to_context() {
+    let gil = Python::acquire_gil();
+    let py = gil.python();
+    let ctx = Context::from("checkpoint: /scratch/model.ckpt\nepochs: 10\nbest: true\nmetrics:\n  accuracy: 0.9\n  losses: [0.5, 0.25]");
+    let obj = ctx.to_object(py);
+    assert_eq!(obj.cast_as::<PyDict>(py).ok().and_then(to_context), Some(ctx));
+    let dict = PyDict::new(py);
+    dict.set_item(1, "one").unwrap();
+    assert_eq!(to_context(dict), None);
+}
@@ -16,6 +16,8 @@ extern crate impersonate;
 extern crate serde_json;
 extern crate uuid;
 extern crate libc;
+extern crate chrono;
+extern crate dirs;
 
 #[cfg(feature = "lang_python")]
 extern crate pyo3;
@@ -24,23 +26,67 @@ extern crate pyo3;
 extern crate handlebars;
 
 pub use ymlctx::context::{Context, CtxObj};
+pub use error::{Error, ErrorKind};
+pub use observer::{RunObserver, ConsoleObserver};
+pub use builder::{Playbook, Step, DockerSpec, WhitelistEntry};
+pub use builtins::Registry;
+/// Log an error, and remember it as the reason why the playbook has failed.
+/// This evaluates to the given exit code.
+macro_rules! fail {
+    ($exit_code:expr, $($arg:tt)+) => {{
+        let msg = format!($($arg)+);
+        error!("{}", msg);
+        crate::set_failure(Some(crate::Failure::new(msg)));
+        $exit_code
+    }}
+}
+
+/// Log why the playbook cannot carry on, and tell it as an `Error`
+macro_rules! failed {
+    ($exit_code:expr, $($arg:tt)+) => {{
+        let msg = format!($($arg)+);
+        error!("{}", msg);
+        crate::error::Error::from_failure($exit_code, Some(crate::Failure::new(msg)))
+    }}
+}
+
 pub mod lang;
 pub mod builtins;
 pub mod systems;
+pub mod expr;
+pub mod scheduler;
+pub mod policy;
+pub mod watchdog;
+pub mod journal;
+pub mod selection;
+pub mod template;
+pub mod lint;
+pub mod error;
+pub mod report;
+pub mod observer;
+pub mod builder;
+pub mod symbols;
+pub mod secrets;
+pub mod hostenv;
+pub mod overrides;
+pub mod incremental;
 
 use std::str;
 use std::path::Path;
 use std::fs::File;
 use std::io::prelude::*;
-use std::io::{BufReader, Write};
+use std::io::Write;
 use std::collections::HashMap;
 use std::result::Result;
-use std::collections::HashSet;
+use std::rc::Rc;
+use std::time::Instant;
 use yaml_rust::YamlLoader;
 use colored::*;
-use regex::Regex;
 use builtins::{TransientContext, ExitCode};
 use systems::Infrastructure;
+use policy::RetryPolicy;
+use watchdog::Limits;
+use journal::Journal;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum TaskErrorSource {
@@ -48,7 +94,25 @@ pub enum TaskErrorSource {
     ExitCode(i32),
     Signal(nix::sys::signal::Signal),
     Internal,
-    ExternalAPIError
+    ExternalAPIError,
+    Timeout
+}
+
+impl TaskErrorSource {
+    /// Names of the classes of failures
+    pub const TAGS: [&'static str; 6] = ["nix", "exit_code", "signal", "internal", "external_api", "timeout"];
+
+    /// Name of the class of this failure
+    pub fn tag(&self) -> &'static str {
+        match self {
+            TaskErrorSource::NixError(_) => "nix",
+            TaskErrorSource::ExitCode(_) => "exit_code",
+            TaskErrorSource::Signal(_) => "signal",
+            TaskErrorSource::Internal => "internal",
+            TaskErrorSource::ExternalAPIError => "external_api",
+            TaskErrorSource::Timeout => "timeout"
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -57,12 +121,89 @@ pub struct TaskError {
     src: TaskErrorSource
 }
 
+impl TaskError {
+    pub fn src(&self) -> &TaskErrorSource {
+        &self.src
+    }
+}
+
 impl std::fmt::Display for TaskError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(f, "{}", &self.msg)
     }
 }
 
+impl std::error::Error for TaskError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self.src {
+            TaskErrorSource::NixError(ref e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+/// What went wrong with the last step that has failed, as told to the `on_failure` handlers
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Failure {
+    message: String,
+    source: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    exit_code: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    signal: Option<String>,
+    /// The error itself, which does not outlive the process
+    #[serde(skip)]
+    cause: Option<TaskError>
+}
+
+impl Failure {
+    pub(crate) fn new(message: String) -> Failure {
+        Failure { message, source: None, exit_code: None, signal: None, cause: None }
+    }
+}
+
+impl From<&TaskError> for Failure {
+    fn from(e: &TaskError) -> Self {
+        Failure {
+            message: e.msg.to_owned(),
+            source: Some(e.src.tag().to_owned()),
+            exit_code: if let TaskErrorSource::ExitCode(exit_code) = e.src { Some(exit_code) } else { None },
+            signal: if let TaskErrorSource::Signal(sig) = e.src { Some(format!("{:?}", sig)) } else { None },
+            cause: Some(e.to_owned())
+        }
+    }
+}
+
+thread_local! {
+    static FAILURE: std::cell::RefCell<Option<Failure>> = const { std::cell::RefCell::new(None) };
+}
+
+/// Remember why a step has failed
+pub(crate) fn set_failure(failure: Option<Failure>) {
+    FAILURE.with(|f| *f.borrow_mut() = failure);
+}
+
+/// Tell why the last step has failed, without forgetting it
+pub(crate) fn peek_failure() -> Option<Failure> {
+    FAILURE.with(|f| f.borrow().clone())
+}
+
+/// Forget why the last step has failed, and tell why
+pub(crate) fn take_failure() -> Option<Failure> {
+    FAILURE.with(|f| f.borrow_mut().take())
+}
+
+/// The error that a step or a part of the playbook has diverged with, along with why it has failed
+pub(crate) fn diverged(exit_code: ExitCode) -> Error {
+    Error::from_failure(exit_code, take_failure())
+}
+
+/// Where the playbook has come to a halt
+pub(crate) struct Halt {
+    exit_code: ExitCode,
+    step: Option<usize>
+}
+
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Closure {
     #[serde(rename = "c")]
@@ -71,6 +212,18 @@ pub struct Closure {
     step_ptr: usize,
     #[serde(rename = "s")]
     pub ctx_states: Context,
+    #[serde(rename = "i", default, skip_serializing_if = "Option::is_none")]
+    iteration: Option<usize>,
+}
+
+impl Closure {
+    /// Label of the step, e.g. "Step 3", or "Step 3.1" for the first iteration of a loop
+    fn step_label(&self) -> String {
+        match self.iteration {
+            Some(k) => format!("Step {}.{}", self.step_ptr+1, k+1),
+            None => format!("Step {}", self.step_ptr+1)
+        }
+    }
 }
 
 #[test]
@@ -79,7 +232,8 @@ fn test_closure_deserialize00() {
     assert_eq!(serde_json::from_str::<Closure>(closure_str).unwrap(), Closure {
         container: 1,
         step_ptr: 0,
-        ctx_states: Context::new()
+        ctx_states: Context::new(),
+        iteration: None
     });
 }
 
@@ -89,7 +243,8 @@ fn test_closure_deserialize01() {
     assert_eq!(serde_json::from_str::<Closure>(closure_str).unwrap(), Closure {
         container: 1,
         step_ptr: 0,
-        ctx_states: Context::new().set("playbook", CtxObj::Str(String::from("tests/test1/say_hi.yml")))
+        ctx_states: Context::new().set("playbook", CtxObj::Str(String::from("tests/test1/say_hi.yml"))),
+        iteration: None
     });
 }
 
@@ -101,10 +256,90 @@ fn test_closure_deserialize02() {
         step_ptr: 1,
         ctx_states: Context::new()
             .set("playbook", CtxObj::Str(String::from("tests/test1/test_sys_vars.yml")))
-            .set("message", CtxObj::Str(String::from("Salut!")))
+            .set("message", CtxObj::Str(String::from("Salut!"))),
+        iteration: None
     });
 }
 
+#[test]
+fn test_closure_deserialize03() {
+    let closure_str = r#"{"c":1,"p":2,"s":{"data":{"item":{"Int":10}}},"i":1}"#;
+    assert_eq!(serde_json::from_str::<Closure>(closure_str).unwrap(), Closure {
+        container: 1,
+        step_ptr: 2,
+        ctx_states: Context::new().set("item", CtxObj::Int(10)),
+        iteration: Some(1)
+    });
+}
+
+/// Location of the file through which a containerized step pipes its resulting context back to the host
+const PIPE_FILE: &str = "ctx.json";
+
+/// Host directory to be mounted into the container for the resulting context to be piped back.
+/// Only the docker infrastructure mounts the pipe, so steps that run on others, e.g. `--as k8s`,
+/// do not pipe their results back; steps that run on the host return them directly.
+fn pipe_path(closure: &Closure) -> std::path::PathBuf {
+    std::env::temp_dir().join(format!("playbook-pipe-{}-{}", std::process::id(), closure.step_ptr))
+}
+
+/// Allocate the pipe of a containerized step
+fn pipe_alloc(closure: &Closure) -> Option<std::path::PathBuf> {
+    let pipe = pipe_path(closure);
+    match std::fs::create_dir_all(&pipe) {
+        Ok(()) => Some(pipe),
+        Err(e) => {
+            warn!("IO Error (while allocating a pipe {:?}): {}", pipe, e);
+            None
+        }
+    }
+}
+
+/// Collect the context piped back from a container and release the pipe
+fn pipe_collect(pipe: &Path) -> Option<Context> {
+    let ctx_pipe = match read_contents(pipe.join(PIPE_FILE)) {
+        Ok(contents) => match serde_json::from_str::<Context>(&contents) {
+            Ok(ctx_pipe) => Some(ctx_pipe),
+            Err(e) => {
+                warn!("Failed to deserialize the piped context: {}", e);
+                None
+            }
+        },
+        Err(_) => None // nothing has been piped back
+    };
+    if let Err(e) = std::fs::remove_dir_all(pipe) {
+        warn!("IO Error (while releasing the pipe {:?}): {}", pipe, e);
+    }
+    ctx_pipe
+}
+
+/// Pipe the resulting context back to the host, if we are inside of a container that has been given a pipe
+fn pipe_send(ctx_pipe: &Context) {
+    if let Ok(pipe) = std::env::var(systems::docker::ENV_PIPE) {
+        let ret = match serde_json::to_string(ctx_pipe) {
+            Ok(contents) => File::create(Path::new(&pipe).join(PIPE_FILE)).and_then(|mut file| file.write_all(contents.as_bytes())),
+            Err(e) => Err(std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+        };
+        if let Err(e) = ret {
+            error!("IO Error (while piping the context back to the host): {}", e);
+        }
+    }
+}
+
+#[test]
+fn test_pipe() {
+    let closure = Closure { container: 0, step_ptr: 9999, ctx_states: Context::new(), iteration: None };
+    let pipe = pipe_alloc(&closure).unwrap();
+    assert_eq!(pipe_collect(&pipe), None);
+    assert!(!pipe.exists());
+    let pipe = pipe_alloc(&closure).unwrap();
+    let ctx_pipe = Context::from("checkpoint: /scratch/model.ckpt\nmetrics:\n  accuracy: 0.9");
+    std::env::set_var(systems::docker::ENV_PIPE, &pipe);
+    pipe_send(&ctx_pipe);
+    std::env::remove_var(systems::docker::ENV_PIPE);
+    assert_eq!(pipe_collect(&pipe), Some(ctx_pipe));
+    assert!(!pipe.exists());
+}
+
 pub fn copy_user_info(facts: &mut HashMap<String, String>, user: &str) {
     if let Some(output) = std::process::Command::new("getent").args(&["passwd", &user]).output().ok() {
         let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
@@ -129,61 +364,46 @@ pub fn format_cmd<I>(cmd: I) -> String
     cmd.into_iter().map(|s| { if s.contains(" ") { format!("\"{}\"", s) } else { s.to_owned() } }).collect::<Vec<String>>().join(" ")
 }
 
-type TaskSpawner = fn(src: Context, ctx_step: Context) -> Result<(), TaskError>;
+type TaskSpawner = fn(src: Context, ctx_step: Context) -> Result<Context, TaskError>;
 
 #[cfg(not(feature = "sandbox"))] // protect the host by removing the entrance to all user codes!
-fn invoke(src: Context, ctx_step: Context) -> Result<Context, ExitCode> {
+fn invoke(src: Context, ctx_step: Context) -> Result<Context, TaskError> {
     let ref action: String = ctx_step.unpack("action").unwrap();
     let ref src_path_str: String = src.unpack("src").unwrap();
-    if !cfg!(feature = "ci_only") {
-        eprintln!("{}", "== Context ======================".cyan());
-        eprintln!("# ctx({}@{}) =\n{}", action.cyan(), src_path_str.dimmed(), ctx_step);
-        eprintln!("{}", "== EOF ==========================".cyan());
-        match std::io::stderr().flush() {
-            Ok(_) => {},
-            Err(_) => {}
-        }
-    }
+    observer::notify(|o| o.on_action_start(action, Some(src_path_str), &ctx_step));
     let src_path = Path::new(src_path_str);
     if let Some(ext_os) = src_path.extension() {
         let ext = ext_os.to_str().unwrap();
         #[allow(unused_variables)]
-        let wrapper = |whichever: TaskSpawner| -> Result<(), Option<String>> {
+        let wrapper = |whichever: TaskSpawner| -> Result<Context, TaskError> {
             let last_words;
-            #[cfg(not(feature = "ci_only"))]
-            println!("{}", "== Output =======================".blue());
-            last_words = if let Err(e) = whichever(src, ctx_step) {
+            last_words = whichever(src, ctx_step);
+            if let Err(ref e) = last_words {
                 match e.src {
                     TaskErrorSource::NixError(_) | TaskErrorSource::ExitCode(_) | TaskErrorSource::Signal(_) => {
-                        Err(Some(format!("{}", e)))
+                        error!("{}", e);
                     },
-                    TaskErrorSource::Internal => Err(None),
-                    TaskErrorSource::ExternalAPIError => unreachable!()
+                    TaskErrorSource::Internal => {},
+                    TaskErrorSource::ExternalAPIError | TaskErrorSource::Timeout => unreachable!()
                 }
             }
-            else { Ok(()) };
-            #[cfg(not(feature = "ci_only"))]
-            println!("{}", "== EOF ==========================".blue());
-            match std::io::stdout().flush() {
-                Ok(_) => {},
-                Err(_) => {}
-            }
             return last_words;
         };
-        let ret: Result<(), Option<String>> = match ext {
+        let ret = match ext {
             #[cfg(feature = "lang_python")]
             "py" => wrapper(lang::python::invoke),
-            _ => Err(Some(format!("It is not clear how to run {}.", src_path_str)))
-        };
-        if let Err(last_words) = ret {
-            if let Some(msg) = last_words {
+            _ => {
+                let msg = format!("It is not clear how to run {}.", src_path_str);
                 error!("{}", msg);
+                Err(TaskError { msg, src: TaskErrorSource::Internal })
             }
-            Err(ExitCode::ErrTask)
-        }
-        else {
-            Ok(Context::new()) // TODO pass return value back as a context
+        };
+        observer::notify(|o| o.on_action_end(action, Some(src_path_str), ret.is_ok()));
+        match std::io::stdout().flush() {
+            Ok(_) => {},
+            Err(_) => {}
         }
+        ret
     }
     else {
         // TODO C-style FFI invocation
@@ -191,17 +411,44 @@ fn invoke(src: Context, ctx_step: Context) -> Result<Context, ExitCode> {
     }
 }
 
-fn symbols<P: AsRef<Path>>(src: P) -> Result<HashSet<String>, std::io::Error> {
-    let mut ret = HashSet::new();
-    let file = File::open(src)?;
-    let re = Regex::new(r"^#\[playbook\((\w+)\)\]").unwrap();
-    for line in BufReader::new(file).lines() {
-        let ref line = line?;
-        if let Some(caps) = re.captures(line){
-            ret.insert(caps.get(1).unwrap().as_str().to_owned());
+/// Invoke an action in a child process if it is subject to time limits
+#[cfg(not(feature = "sandbox"))]
+fn invoke_limited(src: Context, ctx_step: Context, limits: &Limits) -> Result<Context, TaskError> {
+    if limits.is_empty() {
+        return invoke(src, ctx_step);
+    }
+    let outcome = std::env::temp_dir().join(format!("playbook-watchdog-{}.json", std::process::id()));
+    let ret = watchdog::run(|| {
+        match invoke(src, ctx_step) {
+            Ok(ctx_ret) => match serde_json::to_string(&ctx_ret).map(|contents| std::fs::write(&outcome, contents)) {
+                Ok(Ok(())) => 0,
+                _ => ExitCode::ErrSys.into()
+            },
+            Err(_) => ExitCode::ErrTask.into()
+        }
+    }, limits, |_| {});
+    let ctx_ret = std::fs::read_to_string(&outcome).ok().and_then(|contents| serde_json::from_str::<Context>(&contents).ok());
+    let _ = std::fs::remove_file(&outcome);
+    match ret {
+        Ok(nix::sys::wait::WaitStatus::Exited(_, 0)) => match ctx_ret {
+            Some(ctx_ret) => Ok(ctx_ret),
+            None => Err(TaskError { msg: String::from("Failed to collect the results of the action."), src: TaskErrorSource::Internal })
+        },
+        Ok(nix::sys::wait::WaitStatus::Exited(_, exit_code)) => Err(TaskError {
+            msg: format!("The action has returned a non-zero exit code ({}).", exit_code),
+            src: TaskErrorSource::ExitCode(exit_code)
+        }),
+        Ok(nix::sys::wait::WaitStatus::Signaled(_, sig, _core_dump)) => {
+            let e = TaskError { msg: format!("The action has received a signal ({:?}).", sig), src: TaskErrorSource::Signal(sig) };
+            error!("{}", e);
+            Err(e)
+        },
+        Ok(_) => unreachable!(),
+        Err(e) => {
+            error!("{}", e);
+            Err(e)
         }
     }
-    Ok(ret)
 }
 
 fn resolve<'step>(ctx_step: &'step Context, whitelist: &Vec<Context>) -> (Option<&'step str>, Option<Context>) {
@@ -223,10 +470,12 @@ fn resolve<'step>(ctx_step: &'step Context, whitelist: &Vec<Context>) -> (Option
                 let ref src_path = playbook_dir.join(src);
                 let src_path_str = src_path.to_str().unwrap();
                 debug!("Searching \"{}\" for `{}`.", src_path_str, action);
-                if let Ok(src_synbols) = symbols(src_path) {
-                    if src_synbols.contains(action) {
+                if let Ok(src_symbols) = symbols::cached(src_path) {
+                    if let Some(function) = src_symbols.function(action) {
                         debug!("Action `{}` has been found.", action);
-                        return(Some(action), Some(ctx_source.set("src", CtxObj::Str(src_path_str.to_owned()))));
+                        return(Some(action), Some(ctx_source
+                            .set("src", CtxObj::Str(src_path_str.to_owned()))
+                            .set("function", CtxObj::Str(function.to_owned()))));
                     }
                 }
                 else {
@@ -246,43 +495,135 @@ fn try_as_builtin(ctx_step: &Context, closure: &Closure) -> TransientContext {
         (Some(action), Some(sys_func)) => {
             let ctx_sys = ctx_step.overlay(&closure.ctx_states).hide("whitelist");
             info!("{}: {}", "Built-in".magenta(), action);
-            if !cfg!(feature = "ci_only") {
-                eprintln!("{}", "== Context ======================".cyan());
-                eprintln!("# ctx({}) =\n{}", action.cyan(), ctx_sys);
-                eprintln!("{}", "== EOF ==========================".cyan());
-            }
-            sys_func(ctx_sys)
+            observer::notify(|o| o.on_action_start(action, None, &ctx_sys));
+            let ret = sys_func(ctx_sys);
+            let succeeded = matches!(ret, TransientContext::Stateful(_) | TransientContext::Stateless(_) | TransientContext::Diverging(ExitCode::Success));
+            observer::notify(|o| o.on_action_end(action, None, succeeded));
+            ret
         },
         (Some(action), None) => {
-            error!("Action not recognized: {}", action);
-            TransientContext::Diverging(ExitCode::ErrYML)
+            let hint = match symbols::suggest(action, ctx_step) {
+                Some(candidate) => format!(" Did you mean `{}`?", candidate),
+                None => String::new()
+            };
+            TransientContext::Diverging(fail!(ExitCode::ErrYML, "Action not recognized: {}.{}", action, hint))
         },
         (None, _) => {
-            error!("Syntax Error: Key `whitelist` should be a list of mappings.");
-            TransientContext::Diverging(ExitCode::ErrYML)
+            TransientContext::Diverging(fail!(ExitCode::ErrYML, "Syntax Error: Key `whitelist` should be a list of mappings."))
+        }
+    }
+}
+
+fn show_step_header(ctx_step: &Context, step_header: ColoredString, note: Option<ColoredString>) {
+    let note = match note {
+        Some(note) => format!(" ({})", note),
+        None => String::new()
+    };
+    if let Some(CtxObj::Str(step_name)) = ctx_step.get("name") {
+        info!("{}: {}{}", step_header, step_name, note);
+    }
+    else {
+        info!("{}{}", step_header, note);
+    }
+}
+
+/// Evaluate the `when` condition of a step, if there is one
+fn step_condition(ctx_step: &Context) -> Result<bool, ExitCode> {
+    match ctx_step.get("when") {
+        None => Ok(true),
+        Some(CtxObj::Bool(b)) => Ok(*b),
+        Some(CtxObj::Str(condition)) => match expr::eval_bool(condition, ctx_step) {
+            Ok(b) => Ok(b),
+            Err(e) => {
+                Err(fail!(ExitCode::ErrYML, "Syntax Error: Cannot evaluate `when: {}`. {}", condition, e))
+            }
+        },
+        Some(_) => {
+            Err(fail!(ExitCode::ErrYML, "Syntax Error: Key `when` must be a string or a boolean."))
         }
     }
 }
 
-fn run_step(ctx_step: Context, closure: Closure) -> TransientContext {
+/// The docker context of a step, with what the infrastructure needs to know from the step
+fn docker_context(ctx_step: &Context, ctx_docker: Context, pipe: Option<&Path>) -> Context {
+    ctx_docker
+        .set_opt("playbook-from", ctx_step.get_clone("playbook"))
+        .set_opt("timeout", ctx_step.get_clone("timeout"))
+        .set_opt("idle_timeout", ctx_step.get_clone("idle_timeout"))
+        .set_opt("pipe", pipe.map(|p| CtxObj::Str(p.to_str().unwrap().to_owned())))
+}
+
+/// Command line arguments to resume a step inside of a container
+fn resume_params(ctx_step: &Context, ctx_docker: &Context, closure: &Closure) -> Result<Vec<String>, ExitCode> {
+    let mut closure1 = closure.clone();
+    closure1.container = 1;
+    // Register any reassignment of "playbook" to the ctx_states to prolong its lifetime
+    if let Some(ctx_docker_vars) = ctx_docker.subcontext("vars") {
+        closure1.ctx_states = closure1.ctx_states.set_opt("playbook", ctx_docker_vars.get_clone("playbook"));
+    }
+    let mut resume_params = vec! [
+        String::from("--arg-resume"),
+        match serde_json::to_string(&closure1) {
+            Ok(s) => s,
+            Err(_) => {
+                return Err(fail!(ExitCode::ErrApp, "Failed to serialize states."));
+            }
+        },
+        ctx_step.unpack("playbook").unwrap()
+    ];
+    if let Some(ctx_overrides) = overrides::current() {
+        resume_params.push(String::from("--arg-overrides"));
+        match serde_json::to_string(&ctx_overrides) {
+            Ok(s) => resume_params.push(s),
+            Err(_) => {
+                return Err(fail!(ExitCode::ErrApp, "Failed to serialize overrides."));
+            }
+        }
+    }
+    let verbose_unpack = ctx_step.unpack("verbose-fern");
+    if let Ok(verbose) = verbose_unpack {
+        if verbose > 0 {
+            resume_params.push(format!("-{}", "v".repeat(verbose)));
+        }
+    }
+    Ok(resume_params)
+}
+
+fn run_step(ctx_step: Context, ctx_step_raw: &Context, closure: Closure, deadline: Option<Instant>) -> TransientContext {
+    if closure.iteration.is_none() {
+        match scheduler::foreach(&ctx_step) {
+            Ok(Some(items)) => { return scheduler::run_foreach(ctx_step, ctx_step_raw, closure, deadline, items); },
+            Ok(None) => {},
+            Err(e) => { return TransientContext::Diverging(e); }
+        }
+    }
+    let ctx_step = match template::render(&ctx_step, ctx_step_raw) {
+        Ok(ctx_step) => ctx_step,
+        Err(e) => {
+            return TransientContext::Diverging(fail!(ExitCode::ErrYML, "Syntax Error: {}", e));
+        }
+    };
+    // Containerized steps are retried as a whole from outside of the container.
+    let retry = match RetryPolicy::from_step(&ctx_step) {
+        Ok(Some(retry)) if closure.container == 0 => retry,
+        Ok(_) => RetryPolicy::once(),
+        Err(e) => {
+            return TransientContext::Diverging(fail!(ExitCode::ErrYML, "Syntax Error: {}", e));
+        }
+    };
     if let Some(whitelist) = ctx_step.list_contexts("whitelist") {
         match resolve(&ctx_step, &whitelist) {
             (_, Some(ctx_source)) => {
                 let show_step = |for_real: bool| {
-                    let step_header = format!("Step {}", closure.step_ptr+1).cyan();
-                    if let Some(CtxObj::Str(step_name)) = ctx_step.get("name") {
-                        info!("{}: {}", if for_real { step_header } else { step_header.dimmed() }, step_name);
-                    }
-                    else {
-                        info!("{}", if for_real { step_header } else { step_header.dimmed() });
-                    }
+                    let step_header = closure.step_label().cyan();
+                    show_step_header(&ctx_step, if for_real { step_header } else { step_header.dimmed() }, None);
                 };
                 if closure.container == 1 {
                     #[cfg(feature = "sandbox")] unreachable!();
                     #[cfg(not(feature = "sandbox"))]
                     {
                         show_step(true);
-                        TransientContext::from(invoke(ctx_source, ctx_step.hide("whitelist")))
+                        stateful(invoke(ctx_source, ctx_step.hide("whitelist")))
                     }
                 }
                 else {
@@ -290,37 +631,26 @@ fn run_step(ctx_step: Context, closure: Closure) -> TransientContext {
                         show_step(false);
                         if let Some(CtxObj::Str(image_name)) = ctx_docker.get("image") {
                             info!("Entering Docker: {}", image_name.purple());
-                            let mut closure1 = closure.clone();
-                            closure1.container = 1;
-                            // Register any reassignment of "playbook" to the ctx_states to prolong its lifetime
-                            if let Some(ctx_docker_vars) = ctx_docker.subcontext("vars") {
-                                closure1.ctx_states = closure1.ctx_states.set_opt("playbook", ctx_docker_vars.get_clone("playbook"));
-                            }
-                            let mut resume_params = vec! [
-                                String::from("--arg-resume"),
-                                match serde_json::to_string(&closure1) {
-                                    Ok(s) => s,
-                                    Err(_) => {
-                                        error!("Failed to serialize states.");
-                                        return TransientContext::Diverging(ExitCode::ErrApp)
-                                    }
-                                },
-                                ctx_step.unpack("playbook").unwrap()
-                            ];
-                            let verbose_unpack = ctx_step.unpack("verbose-fern");
-                            if let Ok(verbose) = verbose_unpack {
-                                if verbose > 0 {
-                                    resume_params.push(format!("-{}", "v".repeat(verbose)));
-                                }
-                            }
+                            let resume_params = match resume_params(&ctx_step, &ctx_docker, &closure) {
+                                Ok(resume_params) => resume_params,
+                                Err(e) => { return TransientContext::Diverging(e); }
+                            };
                             let infrastructure_str = if let Some(CtxObj::Str(s)) = ctx_step.get("as-switch") { s } else { "docker" };
                             info!("Selected infrastructure: {}", infrastructure_str);
                             if let Some(infrastructure) = systems::abstract_infrastructures(&infrastructure_str) {
-                                match infrastructure.start(ctx_docker.set_opt("playbook-from", ctx_step.get_clone("playbook")), resume_params) {
+                                let pipe = pipe_alloc(&closure);
+                                let ctx_docker = docker_context(&ctx_step, ctx_docker, pipe.as_deref());
+                                let ret = retry.run(&closure.step_label(), deadline, || infrastructure.start(ctx_docker.clone(), resume_params.clone()));
+                                let ctx_pipe = pipe.and_then(|p| pipe_collect(&p));
+                                match ret {
                                     Ok(_docker_cmd) => {
-                                        TransientContext::from(Ok(Context::new())) // TODO pass return value back as a context
+                                        match ctx_pipe {
+                                            Some(ctx_pipe) => TransientContext::Stateful(ctx_pipe),
+                                            None => TransientContext::Stateless(Context::new())
+                                        }
                                     },
                                     Err(e) => {
+                                        set_failure(Some(Failure::from(&e)));
                                         match e.src {
                                             TaskErrorSource::NixError(_) | TaskErrorSource::ExitCode(_) | TaskErrorSource::Signal(_) => {
                                                 error!("{}: {}", "Container has crashed".red().bold(), e);
@@ -330,6 +660,9 @@ fn run_step(ctx_step: Context, closure: Closure) -> TransientContext {
                                             },
                                             TaskErrorSource::ExternalAPIError => {
                                                 error!("{}: {}", "ExternalAPIError".red().bold(), e);
+                                            },
+                                            TaskErrorSource::Timeout => {
+                                                error!("{}: {}", "Timeout".red().bold(), e);
                                             }
                                         }
                                         TransientContext::Diverging(ExitCode::ErrTask)
@@ -337,21 +670,26 @@ fn run_step(ctx_step: Context, closure: Closure) -> TransientContext {
                                 }
                             }
                             else {
-                                error!("Undefined infrastructure.");
-                                TransientContext::Diverging(ExitCode::ErrApp)
+                                TransientContext::Diverging(fail!(ExitCode::ErrApp, "Undefined infrastructure."))
                             }
                         }
                         else {
-                            error!("Syntax Error: Cannot parse the name of the image.");
-                            TransientContext::Diverging(ExitCode::ErrYML)
+                            TransientContext::Diverging(fail!(ExitCode::ErrYML, "Syntax Error: Cannot parse the name of the image."))
                         }
                     }
                     else {
                         #[cfg(feature = "sandbox")] unreachable!();
                         #[cfg(not(feature = "sandbox"))]
                         {
+                            let limits = match Limits::from_ctx(&ctx_step) {
+                                Ok(limits) => limits,
+                                Err(e) => {
+                                    return TransientContext::Diverging(fail!(ExitCode::ErrYML, "Syntax Error: {}", e));
+                                }
+                            };
                             show_step(true);
-                            TransientContext::from(invoke(ctx_source, ctx_step.hide("whitelist")))
+                            let ctx_action = ctx_step.hide("whitelist");
+                            stateful(retry.run(&closure.step_label(), deadline, || invoke_limited(ctx_source.clone(), ctx_action.clone(), &limits)))
                         }
                     }
                 }
@@ -360,8 +698,7 @@ fn run_step(ctx_step: Context, closure: Closure) -> TransientContext {
                 try_as_builtin(&ctx_step, &closure)
             },
             (None, None) => {
-                error!("Syntax Error: Key `action` must be a string.");
-                TransientContext::Diverging(ExitCode::ErrYML)
+                TransientContext::Diverging(fail!(ExitCode::ErrYML, "Syntax Error: Key `action` must be a string."))
             }
         }
     }
@@ -370,25 +707,90 @@ fn run_step(ctx_step: Context, closure: Closure) -> TransientContext {
     }    
 }
 
+/// Shorten the timeout of a step to the remaining time of the playbook
+fn step_deadline(ctx_step: Context, deadline: Option<Instant>) -> Result<Context, ExitCode> {
+    match watchdog::apply_deadline(ctx_step, deadline) {
+        Ok(ctx_step) => Ok(ctx_step),
+        Err(e) => {
+            error!("{}: {}", "Timeout".red().bold(), e);
+            set_failure(Some(Failure::from(&e)));
+            Err(ExitCode::ErrTask)
+        }
+    }
+}
+
+/// Results of an action are piped back as a stateful context
+fn stateful(ret: Result<Context, TaskError>) -> TransientContext {
+    match ret {
+        Ok(ctx_ret) => TransientContext::Stateful(ctx_ret),
+        Err(e) => {
+            set_failure(Some(Failure::from(&e)));
+            TransientContext::Diverging(ExitCode::ErrTask)
+        }
+    }
+}
+
+/// Arguments that steer the run as a whole, which are kept out of the context of each step
+const RUN_ARGS: [&str; 9] = ["run-id", "only-steps", "from-step", "until-step", "only-tags", "keep-stateful", "rebuild", "report", "arg-overrides"];
+
 fn deduce_context(ctx_step_raw: &Context, ctx_global: &Context, ctx_args: &Context, closure: &Closure) -> Context {
-    let ctx_partial = ctx_global.overlay(ctx_step_raw).overlay(ctx_args).overlay(&closure.ctx_states);
-    debug!("ctx({}) =\n{}", "partial".dimmed(), ctx_partial);
-    if let Some(CtxObj::Str(_)) = ctx_partial.get("arg-resume") {
+    let ctx_args = RUN_ARGS.iter().fold(ctx_args.clone(), |ctx_args, key| ctx_args.hide(key));
+    let ctx_partial = ctx_global.overlay(ctx_step_raw).overlay(&ctx_args).overlay(&closure.ctx_states);
+    debug!("ctx({}) =\n{}", "partial".dimmed(), secrets::redact(&ctx_partial.to_string()));
+    let ctx_step = if let Some(CtxObj::Str(_)) = ctx_partial.get("arg-resume") {
         if let Some(ctx_docker_vars) = ctx_partial.subcontext("docker").unwrap().subcontext("vars") {
             ctx_partial.overlay(&ctx_docker_vars).hide("docker")
         }
         else { ctx_partial.hide("docker") }
     }
-    else { ctx_partial }
+    else { ctx_partial };
+    overrides::apply(ctx_step)
 }
 
-fn get_steps(raw: Context) -> Result<(Vec<Context>, Context), ExitCode> {
-    let ctx_global = raw.hide("steps");
+/// Handler steps of a playbook, which are numbered after its main steps
+///
+/// **Example(s)**
+/// ```yaml
+/// steps:
+/// - action: train
+/// on_failure:
+/// - action: notify
+/// - action: release_gpus
+///   when: "failure.source == 'timeout'"
+/// finally:
+/// - action: upload_logs
+/// ```
+///
+/// The `on_failure` handlers run when a step has failed, given a `failure` context with the `step`
+/// name, its 1-based `index`, the error `message`, the `source` of the error and the `exit_code`.
+/// The `finally` handlers always run at the end, also given the `failure` context if any.
+struct Handlers {
+    on_failure: Vec<Context>,
+    finally: Vec<Context>
+}
+
+fn get_steps(raw: Context, ctx_args: &Context) -> Result<(Vec<Context>, Handlers, Context), ExitCode> {
+    let playbook = match ctx_args.get("playbook") {
+        Some(CtxObj::Str(playbook)) => playbook.as_str(),
+        _ => ""
+    };
+    secrets::clear();
+    let ctx_global = hostenv::resolve(raw.hide("steps").hide("on_failure").hide("finally"))?;
+    let ctx_global = secrets::resolve(ctx_global, playbook)?;
+    let handlers = |key: &str| match raw.get(key) {
+        None => Ok(Vec::new()),
+        Some(_) => match raw.list_contexts(key) {
+            Some(handlers) => hostenv::expand_steps(builtins::expand_includes(handlers, playbook)?),
+            None => {
+                Err(fail!(ExitCode::ErrYML, "Syntax Error: Key `{}` is not an array.", key))
+            }
+        }
+    };
     if let Some(steps) = raw.list_contexts("steps") {
-        Ok((steps, ctx_global))
+        Ok((hostenv::expand_steps(builtins::expand_includes(steps, playbook)?)?, Handlers { on_failure: handlers("on_failure")?, finally: handlers("finally")? }, ctx_global))
     }
     else {
-        Err(ExitCode::ErrYML)
+        Err(fail!(ExitCode::ErrYML, "Syntax Error: Key `steps` is not an array."))
     }
 }
 
@@ -402,25 +804,246 @@ fn maybe_exit(exit_code: ExitCode, ctx_states: &Context) -> ExitCode {
     exit_code
 }
 
-pub fn run_playbook(raw: Context, ctx_args: Context) -> Result<(), ExitCode> {
-    let mut ctx_states = Box::new(Context::new());
-    let (steps, ctx_global) = match get_steps(raw) {
-        Ok(v) => v,
-        Err(e) => {
-            error!("Syntax Error: Key `steps` is not an array.");
-            return Err(e);
+/// Run the main steps one after another, skipping those that have completed according to the journal
+fn run_steps(steps: &[Context], selected: &[bool], ctx_global: &Context, ctx_args: &Context, journal: &mut Journal, deadline: Option<Instant>) -> (Context, Option<Halt>) {
+    let mut ctx_states = journal.ctx_states.clone();
+    for (i, ctx_step_raw) in steps.iter().enumerate() {
+        let closure = Closure { container: 0, step_ptr: i, ctx_states: ctx_states.clone(), iteration: None };
+        let ctx_step = deduce_context(ctx_step_raw, ctx_global, ctx_args, &closure);
+        if !selected[i] {
+            show_step_header(&ctx_step, format!("Step {}", i+1).dimmed(), Some("not selected".dimmed()));
+            continue;
         }
+        if journal.completed(i).is_some() {
+            show_step_header(&ctx_step, format!("Step {}", i+1).dimmed(), Some("done".dimmed()));
+            continue;
+        }
+        match step_condition(&ctx_step) {
+            Ok(true) => {},
+            Ok(false) => {
+                show_step_header(&ctx_step, format!("Step {}", i+1).dimmed(), Some("skipped".dimmed()));
+                journal.record(i, journal::Outcome::Skipped, &ctx_states);
+                observer::notify(|o| o.on_step_end(i+1, &journal::Outcome::Skipped));
+                continue;
+            },
+            Err(exit_code) => { return (ctx_states, Some(Halt { exit_code, step: Some(i) })); }
+        }
+        let stamp = match incremental::freshness(&ctx_step, ctx_step_raw, ctx_args, i) {
+            Ok(incremental::Freshness::Untracked) => None,
+            Ok(incremental::Freshness::Stale(stamp)) => Some(stamp),
+            Ok(incremental::Freshness::UpToDate(outcome)) => {
+                show_step_header(&ctx_step, format!("Step {}", i+1).dimmed(), Some("up to date".dimmed()));
+                if let journal::Outcome::Stateful(ref ctx_pipe) = outcome {
+                    ctx_states = ctx_states.overlay(ctx_pipe);
+                    observer::notify(|o| o.on_context_change(&ctx_states));
+                }
+                journal.record(i, outcome.clone(), &ctx_states);
+                observer::notify(|o| o.on_step_end(i+1, &outcome));
+                continue;
+            },
+            Err(exit_code) => { return (ctx_states, Some(Halt { exit_code, step: Some(i) })); }
+        };
+        let ctx_step = match step_deadline(ctx_step, deadline) {
+            Ok(ctx_step) => ctx_step,
+            Err(exit_code) => { return (ctx_states, Some(Halt { exit_code, step: Some(i) })); }
+        };
+        journal.start(i);
+        observer::notify(|o| o.on_step_start(i+1, &ctx_step));
+        let ret = run_step(ctx_step, ctx_step_raw, closure, deadline);
+        if let Some(stamp) = stamp {
+            stamp.record(&ret);
+        }
+        if let TransientContext::Stateful(ref ctx_pipe) = ret {
+            ctx_states = ctx_states.overlay(ctx_pipe);
+            observer::notify(|o| o.on_context_change(&ctx_states));
+        }
+        let outcome = journal::Outcome::from(&ret);
+        journal.record(i, outcome.clone(), &ctx_states);
+        observer::notify(|o| o.on_step_end(i+1, &outcome));
+        if let TransientContext::Diverging(exit_code) = ret {
+            let exit_code = maybe_exit(exit_code, &ctx_states);
+            return (ctx_states, Some(Halt { exit_code, step: Some(i) }));
+        }
+    }
+    maybe_exit(ExitCode::Success, &ctx_states);
+    (ctx_states, None)
+}
+
+/// Describe the failure of a step for the handlers
+fn failure_context(steps: &[Context], halt: &Halt, failure: Option<&Failure>) -> Context {
+    let exit_code: i32 = halt.exit_code.clone().into();
+    let mut ctx_failure = Context::new().set("exit_code", CtxObj::Int(exit_code as i64));
+    if let Some(i) = halt.step {
+        let step_name = match steps[i].get("name") {
+            Some(CtxObj::Str(name)) => name.to_owned(),
+            _ => format!("Step {}", i+1)
+        };
+        ctx_failure = ctx_failure
+            .set("index", CtxObj::Int(i as i64 + 1))
+            .set("step", CtxObj::Str(step_name));
+    }
+    match failure {
+        Some(failure) => ctx_failure
+            .set("message", CtxObj::Str(failure.message.to_owned()))
+            .set_opt("source", failure.source.to_owned().map(CtxObj::Str)),
+        None => ctx_failure.set("message", CtxObj::Str(format!("The step has exited with code {}.", exit_code)))
+    }
+}
+
+/// Tell why the playbook has come to a halt, and where in the playbook if known
+fn halt_error(steps: &[Context], halt: Halt, failure: Option<Failure>, location: Option<error::Location>) -> Error {
+    let e = Error::from_failure(halt.exit_code, failure).with_location(location);
+    match halt.step {
+        Some(i) => {
+            let step_name = match steps[i].get("name") {
+                Some(CtxObj::Str(name)) => Some(name.to_owned()),
+                _ => None
+            };
+            e.with_step(i, step_name)
+        },
+        None => e
+    }
+}
+
+/// Run the handlers of a playbook, all of them even if some have failed
+fn run_handlers(section: &str, handlers: &[Context], offset: usize, ctx_global: &Context, ctx_args: &Context, ctx_states: &Context) -> Result<(), ExitCode> {
+    if handlers.is_empty() {
+        return Ok(());
+    }
+    info!("Running the `{}` handlers.", section);
+    let mut ctx_states = ctx_states.clone();
+    let mut ret = Ok(());
+    for (i, ctx_step_raw) in handlers.iter().enumerate() {
+        let closure = Closure { container: 0, step_ptr: offset + i, ctx_states: ctx_states.clone(), iteration: None };
+        let ctx_step = deduce_context(ctx_step_raw, ctx_global, ctx_args, &closure);
+        match step_condition(&ctx_step) {
+            Ok(true) => {},
+            Ok(false) => {
+                show_step_header(&ctx_step, closure.step_label().dimmed(), Some("skipped".dimmed()));
+                continue;
+            },
+            Err(exit_code) => {
+                if ret.is_ok() { ret = Err(exit_code); }
+                continue;
+            }
+        }
+        match run_step(ctx_step, ctx_step_raw, closure, None) {
+            TransientContext::Stateless(_) => { }
+            TransientContext::Stateful(ctx_pipe) => {
+                ctx_states = ctx_states.overlay(&ctx_pipe);
+            }
+            TransientContext::Diverging(ExitCode::Success) => { break; }
+            TransientContext::Diverging(exit_code) => {
+                warn!("A `{}` handler has failed.", section);
+                take_failure();
+                if ret.is_ok() { ret = Err(exit_code); }
+            }
+        }
+    }
+    ret
+}
+
+/// How a playbook runs on behalf of a library user
+///
+/// **Example(s)**
+/// ```rust,ignore
+/// let options = RunOptions::new().observer(progress.clone()).registry(registry);
+/// playbook_api::run_playbook_with(raw, ctx_args, options)?;
+/// ```
+pub struct RunOptions {
+    /// Observers of the run, which are the console banners by default
+    pub observers: Vec<Rc<dyn RunObserver>>,
+    /// Custom built-in actions
+    pub registry: builtins::Registry,
+    /// Overrides of the context, on top of those of the `arg-overrides` argument
+    pub overrides: Context
+}
+
+impl RunOptions {
+    pub fn new() -> RunOptions {
+        RunOptions { observers: vec![Rc::new(ConsoleObserver)], registry: builtins::Registry::new(), overrides: Context::new() }
+    }
+
+    /// Add an observer, along with those that are already there
+    pub fn observer(mut self, observer: Rc<dyn RunObserver>) -> RunOptions {
+        self.observers.push(observer);
+        self
+    }
+
+    /// Replace the observers, e.g. to silence the console
+    pub fn observers(mut self, observers: Vec<Rc<dyn RunObserver>>) -> RunOptions {
+        self.observers = observers;
+        self
+    }
+
+    pub fn registry(mut self, registry: builtins::Registry) -> RunOptions {
+        self.registry = registry;
+        self
+    }
+
+    /// Override variables of the context, as `-e` does on the command line
+    pub fn overrides(mut self, overrides: Context) -> RunOptions {
+        self.overrides = overrides;
+        self
+    }
+}
+
+impl Default for RunOptions {
+    fn default() -> Self {
+        RunOptions::new()
+    }
+}
+
+/// Run a playbook, with the banners of actions on the console
+pub fn run_playbook(raw: Context, ctx_args: Context) -> Result<(), Error> {
+    run_playbook_with(raw, ctx_args, RunOptions::new())
+}
+
+/// Run a playbook with observers and custom built-in actions of its own
+pub fn run_playbook_with(raw: Context, ctx_args: Context, options: RunOptions) -> Result<(), Error> {
+    let observers = observer::replace(options.observers);
+    let registry = builtins::replace_registry(options.registry);
+    let ctx_args = if options.overrides.keys().next().is_some() {
+        let ctx_overrides = overrides::merge(&ctx_args.subcontext("arg-overrides").unwrap_or_else(Context::new), &options.overrides);
+        ctx_args.set("arg-overrides", CtxObj::Context(ctx_overrides))
+    }
+    else { ctx_args };
+    let ret = run(raw, ctx_args);
+    builtins::replace_registry(registry);
+    observer::replace(observers);
+    ret
+}
+
+fn run(raw: Context, ctx_args: Context) -> Result<(), Error> {
+    set_failure(None);
+    symbols::clear_cache();
+    overrides::install(&ctx_args);
+    // * Steps can be located in the playbook up until the first sys_include, which renumbers those after it.
+    let located = match raw.list_contexts("steps") {
+        Some(steps) => steps.iter().take_while(|ctx_step| !matches!(ctx_step.get("action"), Some(CtxObj::Str(action)) if action == "sys_include")).count(),
+        None => 0
     };
+    let (steps, handlers, ctx_global) = get_steps(raw, &ctx_args).map_err(diverged)?;
     if let Some(CtxObj::Str(closure_str)) = ctx_args.get("arg-resume") {
         // ^^ Then we must be in a docker container because main() has guaranteed that.
         match serde_json::from_str::<Closure>(closure_str) {
             Ok(closure) => {
-                let ctx_step = deduce_context(&steps[closure.step_ptr], &ctx_global, &ctx_args, &closure);
-                match run_step(ctx_step, closure) {
-                    TransientContext::Stateful(_) | TransientContext::Stateless(_) => Ok(()),
+                let ctx_step_raw = match steps.iter().chain(handlers.on_failure.iter()).chain(handlers.finally.iter()).nth(closure.step_ptr) {
+                    Some(ctx_step_raw) => ctx_step_raw,
+                    None => {
+                        return Err(failed!(ExitCode::ErrApp, "Cannot find step {} of the playbook.", closure.step_ptr+1));
+                    }
+                };
+                let ctx_step = deduce_context(ctx_step_raw, &ctx_global, &ctx_args, &closure);
+                match run_step(ctx_step, ctx_step_raw, closure, None) {
+                    TransientContext::Stateful(ctx_pipe) => {
+                        pipe_send(&ctx_pipe);
+                        Ok(())
+                    },
+                    TransientContext::Stateless(_) => Ok(()),
                     TransientContext::Diverging(exit_code) => match exit_code {
                         ExitCode::Success => Ok(()),
-                        _ => Err(exit_code)
+                        _ => Err(diverged(exit_code))
                     }
                 }
             }
@@ -428,46 +1051,278 @@ pub fn run_playbook(raw: Context, ctx_args: Context) -> Result<(), ExitCode> {
                 error!("Syntax Error: Cannot parse the `--arg-resume` flag. {}", closure_str.underline());
                 #[cfg(feature = "ci_only")]
                 eprintln!("{}", _e);
-                Err(ExitCode::ErrApp)
+                Err(Error::new(ErrorKind::Application, String::from("Cannot parse the `--arg-resume` flag.")).with_source(_e))
             }
         }
     }
     else {
-        for (i, ctx_step_raw) in steps.iter().enumerate() {
-            let closure = Closure { container: 0, step_ptr: i, ctx_states: ctx_states.as_ref().clone() };
-            let ctx_step = deduce_context(ctx_step_raw, &ctx_global, &ctx_args, &closure);
-            match run_step(ctx_step, closure) {
-                TransientContext::Stateless(_) => { }
-                TransientContext::Stateful(ctx_pipe) => {
-                    ctx_states = Box::new(ctx_states.overlay(&ctx_pipe));
+        // * The playbook timeout is not meant to be inherited by each step as is.
+        let deadline = match ctx_global.get("timeout") {
+            Some(val) => match policy::parse_duration(val) {
+                Some(timeout) => Some(Instant::now() + timeout),
+                None => {
+                    let e = failed!(ExitCode::ErrYML, "Syntax Error: Key `timeout` must be a duration, e.g. 12h.");
+                    return Err(e.with_location(ctx_args.get("playbook").and_then(|playbook| match playbook {
+                        CtxObj::Str(playbook) => lint::locate(playbook, "timeout"),
+                        _ => None
+                    })));
                 }
-                TransientContext::Diverging(exit_code) => match maybe_exit(exit_code, &ctx_states) {
-                    ExitCode::Success => { return Ok(()); }
-                    exit_code @ _ => { return Err(exit_code); }
+            },
+            None => None
+        };
+        let ctx_global = ctx_global.hide("timeout");
+        let selected = match selection::select(&steps, &ctx_args) {
+            Ok(selected) => selected,
+            Err(e) => {
+                return Err(failed!(ExitCode::ErrApp, "Cannot select the steps to run. {}", e));
+            }
+        };
+        let started = chrono::Local::now();
+        let mut journal = Journal::open(&ctx_args)?;
+        let (ctx_states, halt) = match scheduler::dependencies(&steps) {
+            Ok(Some(deps)) => scheduler::run_graph(&steps, &deps, &selected, &ctx_global, &ctx_args, &mut journal, deadline),
+            Ok(None) => run_steps(&steps, &selected, &ctx_global, &ctx_args, &mut journal, deadline),
+            Err(e) => {
+                return Err(failed!(ExitCode::ErrYML, "Syntax Error: {}", e));
+            }
+        };
+        journal.finish(match halt {
+            None | Some(Halt { exit_code: ExitCode::Success, .. }) => journal::Status::Succeeded,
+            Some(_) => journal::Status::Failed
+        });
+        let mut ret = Ok(());
+        let mut ctx_states = ctx_states;
+        if let Some(halt) = halt {
+            match halt.exit_code {
+                ExitCode::Success => {},
+                _ => {
+                    let failure = take_failure();
+                    ctx_states = ctx_states.set("failure", CtxObj::Context(failure_context(&steps, &halt, failure.as_ref())));
+                    let _ = run_handlers("on_failure", &handlers.on_failure, steps.len(), &ctx_global, &ctx_args, &ctx_states);
+                    let location = match (halt.step, ctx_args.get("playbook")) {
+                        (Some(i), Some(CtxObj::Str(playbook))) if i < located => lint::locate(playbook, &format!("steps.{}", i)),
+                        _ => None
+                    };
+                    ret = Err(halt_error(&steps, halt, failure, location));
                 }
             }
         }
-        maybe_exit(ExitCode::Success, &ctx_states);
-        Ok(())
+        let offset = steps.len() + handlers.on_failure.len();
+        let ret = match run_handlers("finally", &handlers.finally, offset, &ctx_global, &ctx_args, &ctx_states) {
+            Err(exit_code) if ret.is_ok() => Err(diverged(exit_code)),
+            _ => ret
+        };
+        let exit_code: i32 = match ret {
+            Ok(()) => 0,
+            Err(ref e) => e.exit_code().into()
+        };
+        report::Report::new(&steps, &ctx_global, &ctx_args, &journal, started, exit_code).write(&ctx_args);
+        ret
+    }
+}
+
+/// Describe what a step would do, without doing it
+fn plan_step(ctx_step: &Context, ctx_step_raw: &Context, closure: &Closure, section: Option<&str>) -> Result<(), ExitCode> {
+    let step_label = match section {
+        Some(section) => format!("{} ({})", closure.step_label(), section),
+        None => closure.step_label()
+    };
+    let step_name = match ctx_step.get("name") {
+        Some(CtxObj::Str(name)) => format!(": {}", name),
+        _ => String::new()
+    };
+    println!("{}", format!("== {}{} ", step_label, step_name).cyan());
+    if let Some(CtxObj::Str(condition)) = ctx_step.get("when") {
+        match expr::eval_bool(condition, ctx_step) {
+            Ok(b) => println!("{} {} => {}", "when".dimmed(), condition, b),
+            Err(e) => {
+                return Err(fail!(ExitCode::ErrYML, "Syntax Error: Cannot evaluate `when: {}`. {}", condition, e));
+            }
+        }
+    }
+    let ctx_rendered;
+    let ctx_step = if let Some(spec) = scheduler::foreach(ctx_step)? {
+        // * The loop variable is only defined in each iteration.
+        println!("{} {} iteration(s)", "foreach".dimmed(), spec.items.len());
+        ctx_step
+    }
+    else {
+        match template::render(ctx_step, ctx_step_raw) {
+            Ok(ctx) => {
+                ctx_rendered = ctx;
+                &ctx_rendered
+            },
+            Err(e) => {
+                println!("{}", secrets::redact(&ctx_step.hide("whitelist").to_string()));
+                return Err(fail!(ExitCode::ErrYML, "Syntax Error: {}", e));
+            }
+        }
+    };
+    println!("{}", secrets::redact(&ctx_step.hide("whitelist").to_string()));
+    let whitelist = ctx_step.list_contexts("whitelist").unwrap_or_default();
+    match resolve(ctx_step, &whitelist) {
+        (Some(action), Some(ctx_source)) => {
+            let src: String = ctx_source.unpack("src").unwrap();
+            match ctx_step.subcontext("docker") {
+                Some(ctx_docker) if closure.container == 0 => {
+                    let resume_params = resume_params(ctx_step, &ctx_docker, closure)?;
+                    let infrastructure_str = if let Some(CtxObj::Str(s)) = ctx_step.get("as-switch") { s } else { "docker" };
+                    let infrastructure = match systems::abstract_infrastructures(infrastructure_str) {
+                        Some(infrastructure) => infrastructure,
+                        None => {
+                            return Err(fail!(ExitCode::ErrApp, "Undefined infrastructure."));
+                        }
+                    };
+                    let ctx_docker = docker_context(ctx_step, ctx_docker, Some(&pipe_path(closure)));
+                    match infrastructure.plan(ctx_docker, resume_params) {
+                        Ok(plan) => println!("{} {}@{} in {}:\n{}", "action".dimmed(), action, src, infrastructure_str, secrets::redact(&plan)),
+                        Err(e) => {
+                            error!("{}: {}", "InternalError".red().bold(), e);
+                            return Err(ExitCode::ErrTask);
+                        }
+                    }
+                },
+                _ => println!("{} {}@{} on the host", "action".dimmed(), action, src)
+            }
+        },
+        (Some(action), None) => match builtins::resolve(ctx_step) {
+            (_, Some(_)) => println!("{} {}", "built-in".dimmed(), action),
+            _ => {
+                return Err(fail!(ExitCode::ErrYML, "Cannot resolve the action `{}`.", action));
+            }
+        },
+        (None, _) => {
+            return Err(fail!(ExitCode::ErrYML, "Syntax Error: Key `action` must be a string."));
+        }
     }
+    Ok(())
 }
 
-pub fn load_yaml<P: AsRef<Path>>(playbook: P) -> Result<Context, ExitCode> {
-    let fname = playbook.as_ref();
+/// Walk through the steps of a playbook and describe what each of them would do, without running any.
+///
+/// Because the states of the workflow are only known as the steps run, every step is
+/// described as if there were no states.
+pub fn plan_playbook(raw: Context, ctx_args: Context) -> Result<(), Error> {
+    overrides::install(&ctx_args);
+    let (steps, handlers, ctx_global) = get_steps(raw, &ctx_args).map_err(diverged)?;
+    if let Err(e) = scheduler::dependencies(&steps) {
+        return Err(failed!(ExitCode::ErrYML, "Syntax Error: {}", e));
+    }
+    let selected = match selection::select(&steps, &ctx_args) {
+        Ok(selected) => selected,
+        Err(e) => {
+            return Err(failed!(ExitCode::ErrApp, "Cannot select the steps to run. {}", e));
+        }
+    };
+    let sections = steps.iter().zip(selected.iter()).map(|(ctx_step_raw, &selected)| (ctx_step_raw, if selected { None } else { Some("not selected") }))
+        .chain(handlers.on_failure.iter().map(|ctx_step_raw| (ctx_step_raw, Some("on_failure"))))
+        .chain(handlers.finally.iter().map(|ctx_step_raw| (ctx_step_raw, Some("finally"))));
+    let mut ret = Ok(());
+    for (i, (ctx_step_raw, section)) in sections.enumerate() {
+        let closure = Closure { container: 0, step_ptr: i, ctx_states: Context::new(), iteration: None };
+        let ctx_step = deduce_context(ctx_step_raw, &ctx_global, &ctx_args, &closure);
+        // * Keep going so that all of the problems are reported at once.
+        if let Err(e) = plan_step(&ctx_step, ctx_step_raw, &closure, section) {
+            ret = Err(diverged(e).with_step(i, match ctx_step_raw.get("name") {
+                Some(CtxObj::Str(name)) => Some(name.to_owned()),
+                _ => None
+            }));
+        }
+    }
+    ret
+}
+
+/// Load a playbook, or one of its stages given after a `#`, e.g. `some.yml#train`
+///
+/// **Example(s)**
+/// ```yaml
+/// ---
+/// docker:
+///   image: aleozlx/tkstack2:latest
+/// ---
+/// stage: train
+/// steps:
+/// - action: train
+/// ---
+/// stage: eval
+/// steps:
+/// - action: eval
+/// ```
+///
+/// The first document holds the global defaults, and each of the following documents is a stage,
+/// which is named by its `stage` key or else numbered from 1. The selected stage is overlaid onto the defaults.
+pub fn load_yaml<P: AsRef<Path>>(playbook: P) -> Result<Context, Error> {
+    let playbook_str = playbook.as_ref().to_string_lossy().into_owned();
+    let (fname, stage) = split_stage(&playbook_str);
     let contents = match read_contents(fname) {
         Ok(v) => v,
         Err(e) => {
-            error!("IO Error (while loading the playbook {:?}): {}", playbook.as_ref(), e);
-            return Err(ExitCode::ErrSys);
+            return Err(failed!(ExitCode::ErrSys, "IO Error (while loading the playbook {:?}): {}", fname, e));
         }
     };
     match YamlLoader::load_from_str(&contents) {
-        Ok(yml_global) => {
-            Ok(Context::from(yml_global[0].to_owned()))
-        },
+        Ok(yml_global) => select_stage(yml_global.into_iter().map(Context::from).collect(), fname, stage),
         Err(e) => {
             error!("{}: {}", e, "Some YAML parsing error has occurred.");
-            Err(ExitCode::ErrYML)
+            let location = error::Location { file: fname.to_owned(), line: e.marker().line(), col: e.marker().col()+1 };
+            Err(Error::new(ErrorKind::Syntax, e.to_string()).with_location(Some(location)).with_source(e))
+        }
+    }
+}
+
+/// Split the path to a playbook from the stage that is selected, e.g. `some.yml#train`,
+/// unless the `#` belongs to the path, e.g. `runs/#3/main.yml` or a file named `some.yml#train`
+fn split_stage(playbook: &str) -> (&str, Option<&str>) {
+    match playbook.rfind('#') {
+        Some(i) if !playbook[i+1..].contains('/') && !Path::new(playbook).is_file() => (&playbook[..i], Some(&playbook[i+1..])),
+        _ => (playbook, None)
+    }
+}
+
+#[test]
+fn test_split_stage() {
+    assert_eq!(split_stage("tests/test6/stages.yml#train"), ("tests/test6/stages.yml", Some("train")));
+    assert_eq!(split_stage("tests/test6/stages.yml"), ("tests/test6/stages.yml", None));
+    assert_eq!(split_stage("runs/#3/main.yml"), ("runs/#3/main.yml", None));
+    assert_eq!(split_stage("runs/#3/main.yml#2"), ("runs/#3/main.yml", Some("2")));
+    let scratch = std::env::temp_dir().join(format!("playbook-split-stage-{}#train", std::process::id()));
+    std::fs::write(&scratch, "steps: []").unwrap();
+    assert_eq!(split_stage(scratch.to_str().unwrap()), (scratch.to_str().unwrap(), None));
+    std::fs::remove_file(&scratch).unwrap();
+}
+
+fn stage_name(i: usize, ctx_stage: &Context) -> String {
+    match ctx_stage.get("stage") {
+        Some(CtxObj::Str(name)) => name.to_owned(),
+        _ => i.to_string()
+    }
+}
+
+/// The document of a stage, given by its name or number
+fn stage_document(docs: &[Context], stage: &str) -> Option<usize> {
+    (1..docs.len()).find(|&i| stage_name(i, &docs[i]) == stage || i.to_string() == stage)
+}
+
+fn select_stage(docs: Vec<Context>, fname: &str, stage: Option<&str>) -> Result<Context, Error> {
+    let ctx_defaults = match docs.first() {
+        Some(ctx_defaults) => ctx_defaults,
+        None => {
+            return Err(failed!(ExitCode::ErrYML, "Syntax Error: The playbook {} is empty.", fname));
+        }
+    };
+    let stage_names = || (1..docs.len()).map(|i| stage_name(i, &docs[i])).collect::<Vec<String>>().join(", ");
+    match stage {
+        Some(stage) => match stage_document(&docs, stage) {
+            Some(i) => Ok(ctx_defaults.overlay(&docs[i].hide("stage"))),
+            None => {
+                Err(failed!(ExitCode::ErrYML, "Cannot find the stage `{}` of the playbook {}. Stages: {}", stage, fname, if docs.len() > 1 { stage_names() } else { String::from("(none)") }))
+            }
+        },
+        None => {
+            if docs.len() > 1 {
+                warn!("Only the first document of the playbook {} is used. Select a stage with {}#<stage> among: {}", fname, fname, stage_names());
+            }
+            Ok(ctx_defaults.to_owned())
         }
     }
 }
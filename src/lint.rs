@@ -0,0 +1,689 @@
+//! Validation of a playbook before any of its steps runs
+//!
+//! **Example(s)**
+//! ```sh
+//! playbook --lint some.yml
+//! playbook --lint some.yml#train
+//! ```
+//!
+//! Each diagnostic is reported at the line and column of the offending key, e.g.
+//! ```text
+//! some.yml:7:3: warning: Unknown key `acton`, did you mean `action`?
+//! some.yml:9:5: error: Key `docker.interactive` must be a boolean.
+//! ```
+//!
+//! Steps take arbitrary parameters besides the keys known to playbook-rs, so an unknown key of
+//! a step is only flagged when it looks like a misspelled known key.
+
+use std::path::Path;
+use std::collections::HashMap;
+use yaml_rust::{YamlLoader, ScanError};
+use yaml_rust::parser::{Parser, Event, MarkedEventReceiver};
+use yaml_rust::scanner::Marker;
+use ymlctx::context::{Context, CtxObj};
+use colored::*;
+use crate::builtins::{self, ExitCode};
+use crate::error::{Error, ErrorKind, Location};
+use crate::policy::{RetryPolicy, parse_duration};
+use crate::template::is_template;
+use crate::scheduler;
+use crate::hostenv::HostEnv;
+
+const PLAYBOOK_KEYS: &[&str] = &["steps", "whitelist", "docker", "on_failure", "finally", "timeout", "stage", "secrets", "env"];
+const STEP_KEYS: &[&str] = &[
+    "name", "action", "docker", "whitelist", "when", "needs", "tags", "retry", "timeout", "idle_timeout",
+    "foreach", "with_items", "loop_var", "concurrency", "register",
+    "exit_code", "bash", "states", "grid", "resource", "from", "vars", "inputs", "outputs"
+];
+const DOCKER_KEYS: &[&str] = &[
+    "image", "interactive", "impersonate", "volumes", "ports", "environment", "runtime", "ipc", "network", "gui", "name", "gpus", "vars"
+];
+const WHITELIST_KEYS: &[&str] = &["src"];
+const RETRY_KEYS: &[&str] = &["attempts", "backoff", "initial", "on"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning
+}
+
+/// A finding about the key at a dotted path of the playbook, e.g. `steps.0.docker.image`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub path: String,
+    pub message: String
+}
+
+fn join(path: &str, key: &str) -> String {
+    if path.is_empty() { key.to_owned() } else { format!("{}.{}", path, key) }
+}
+
+enum Frame {
+    Mapping { path: String, key: Option<String> },
+    Sequence { path: String, index: usize }
+}
+
+/// Where the keys and the items of each YAML document are, by their dotted paths
+#[derive(Default)]
+pub struct Positions {
+    docs: Vec<HashMap<String, Marker>>,
+    stack: Vec<Frame>
+}
+
+impl Positions {
+    pub fn parse(contents: &str) -> Result<Positions, ScanError> {
+        let mut positions = Positions::default();
+        Parser::new(contents.chars()).load(&mut positions, true)?;
+        Ok(positions)
+    }
+
+    /// Where a path is in a document, or else where its closest parent is
+    pub fn locate(&self, doc: usize, path: &str) -> Option<Marker> {
+        let markers = self.docs.get(doc)?;
+        let mut path = path;
+        loop {
+            if let Some(marker) = markers.get(path) {
+                return Some(*marker);
+            }
+            match path.rfind('.') {
+                Some(i) => { path = &path[..i]; },
+                None => { return None; }
+            }
+        }
+    }
+
+    /// Whether a path is in a document
+    pub fn contains(&self, doc: usize, path: &str) -> bool {
+        self.docs.get(doc).is_some_and(|markers| markers.contains_key(path))
+    }
+
+    /// The path of a node that has just started, unless it is the key of a mapping.
+    /// Mappings are not `located` where they start, but at their first key.
+    fn node_path(&mut self, scalar: Option<&str>, mark: Marker, located: bool) -> Option<String> {
+        let path = match self.stack.last_mut() {
+            Some(Frame::Mapping { path, key }) => match key.take() {
+                Some(key) => join(path, &key),
+                None => {
+                    // * The value that follows is located at its key.
+                    let path_key = join(path, scalar.unwrap_or("?"));
+                    *key = Some(scalar.unwrap_or("?").to_owned());
+                    if let Some(markers) = self.docs.last_mut() {
+                        markers.entry(path.clone()).or_insert(mark);
+                        markers.insert(path_key, mark);
+                    }
+                    return None;
+                }
+            },
+            Some(Frame::Sequence { path, index }) => {
+                *index += 1;
+                join(path, &(*index - 1).to_string())
+            },
+            None => String::new()
+        };
+        if let Some(markers) = self.docs.last_mut().filter(|_| located) {
+            markers.entry(path.clone()).or_insert(mark);
+        }
+        Some(path)
+    }
+}
+
+impl MarkedEventReceiver for Positions {
+    fn on_event(&mut self, ev: Event, mark: Marker) {
+        match ev {
+            Event::DocumentStart => {
+                self.docs.push(HashMap::new());
+                self.stack.clear();
+            },
+            Event::Scalar(ref s, ..) => { self.node_path(Some(s), mark, true); },
+            Event::Alias(_) => { self.node_path(None, mark, true); },
+            Event::MappingStart(_) => {
+                let path = self.node_path(None, mark, false).unwrap_or_else(|| String::from("?"));
+                self.stack.push(Frame::Mapping { path, key: None });
+            },
+            Event::SequenceStart(_) => {
+                let path = self.node_path(None, mark, true).unwrap_or_else(|| String::from("?"));
+                self.stack.push(Frame::Sequence { path, index: 0 });
+            },
+            Event::MappingEnd | Event::SequenceEnd => { self.stack.pop(); },
+            _ => {}
+        }
+    }
+}
+
+/// Edit distance between two words
+fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut prev = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let cur = row[j+1];
+            row[j+1] = if ca == *cb { prev } else { 1 + prev.min(row[j]).min(row[j+1]) };
+            prev = cur;
+        }
+    }
+    row[b.len()]
+}
+
+/// The candidate that a word is most likely a misspelling of, if any
+pub(crate) fn suggest<'a, I>(word: &str, candidates: I) -> Option<&'a str>
+  where I: IntoIterator<Item = &'a str>
+{
+    let tolerance = std::cmp::min(2, (word.chars().count() + 1) / 3);
+    candidates.into_iter()
+        .map(|candidate| (distance(word, candidate), candidate))
+        .filter(|&(d, _)| d > 0 && d <= tolerance)
+        .min_by_key(|&(d, _)| d)
+        .map(|(_, candidate)| candidate)
+}
+
+fn type_name(val: &CtxObj) -> &'static str {
+    match val {
+        CtxObj::Str(_) => "a string",
+        CtxObj::Bin(_) => "binary",
+        CtxObj::Int(_) => "an integer",
+        CtxObj::Real(_) => "a number",
+        CtxObj::Bool(_) => "a boolean",
+        CtxObj::Array(_) => "a list",
+        CtxObj::Context(_) => "a mapping",
+        CtxObj::None => "null"
+    }
+}
+
+/// Whether a value is only known once the templates of its step are rendered
+fn templated(val: &CtxObj) -> bool {
+    matches!(val, CtxObj::Str(s) if is_template(s))
+}
+
+struct Linter<'a> {
+    playbook_dir: &'a Path,
+    diagnostics: Vec<Diagnostic>
+}
+
+impl<'a> Linter<'a> {
+    fn error(&mut self, path: &str, message: String) {
+        self.diagnostics.push(Diagnostic { severity: Severity::Error, path: path.to_owned(), message });
+    }
+
+    fn warning(&mut self, path: &str, message: String) {
+        self.diagnostics.push(Diagnostic { severity: Severity::Warning, path: path.to_owned(), message });
+    }
+
+    /// Flag the unknown keys of a mapping, which are all flagged if the set of keys is `closed`
+    fn keys(&mut self, path: &str, ctx: &Context, known: &[&str], closed: bool) {
+        let mut keys: Vec<&String> = ctx.keys().collect();
+        keys.sort();
+        for key in keys {
+            if known.contains(&key.as_str()) { continue; }
+            match suggest(key, known.iter().cloned()) {
+                Some(candidate) => self.warning(&join(path, key), format!("Unknown key `{}`, did you mean `{}`?", key, candidate)),
+                None if closed => self.warning(&join(path, key), format!("Unknown key `{}`. Known keys: {}", key, known.join(", "))),
+                None => {}
+            }
+        }
+    }
+
+    /// Check the type of an optional value
+    fn expect(&mut self, path: &str, key: &str, val: Option<&CtxObj>, what: &str, ok: fn(&CtxObj) -> bool) {
+        if let Some(val) = val {
+            if !ok(val) && !templated(val) {
+                self.error(path, format!("Key `{}` must be {}, not {}.", key, what, type_name(val)));
+            }
+        }
+    }
+
+    fn file(&mut self, path: &str, what: &str, fname: &str) {
+        // * Paths from the host environment are only known on the host.
+        if !is_template(fname) && !fname.contains("${") && !self.playbook_dir.join(fname).exists() {
+            self.error(path, format!("Cannot find the {} `{}`.", what, fname));
+        }
+    }
+
+    fn playbook(&mut self, raw: &Context) {
+        self.keys("", raw, PLAYBOOK_KEYS, false);
+        if let Some(val) = raw.get("timeout") {
+            if parse_duration(val).is_none() {
+                self.error("timeout", String::from("Key `timeout` must be a duration, e.g. 12h."));
+            }
+        }
+        if let Some(val) = raw.get("docker") {
+            self.docker("docker", val);
+        }
+        if raw.get("env").is_some() {
+            // * Whether the variables are set is up to the host that runs the playbook.
+            match HostEnv::from_ctx(raw, |_| Some(String::new())) {
+                Ok(host_env) => {
+                    if let Err(e) = host_env.expand_ctx(&raw.hide("env")) { self.error("", e); }
+                },
+                Err(e) => { self.error("env", e); }
+            }
+        }
+        if let Some(val) = raw.get("whitelist") {
+            self.whitelist("whitelist", val);
+        }
+        let ctx_global = raw.hide("steps").hide("on_failure").hide("finally");
+        match raw.get("steps") {
+            Some(val) => { self.steps("steps", val, &ctx_global); },
+            None => { self.error("", String::from("Key `steps` is required.")); }
+        }
+        for section in ["on_failure", "finally"].iter() {
+            if let Some(val) = raw.get(section) {
+                self.steps(section, val, &ctx_global);
+            }
+        }
+    }
+
+    fn steps(&mut self, path: &str, val: &CtxObj, ctx_global: &Context) {
+        let items = match val {
+            CtxObj::Array(items) => items,
+            _ => {
+                self.error(path, format!("Key `{}` must be a list of steps.", path));
+                return;
+            }
+        };
+        let mut steps = Vec::new();
+        for (i, item) in items.iter().enumerate() {
+            let path_step = join(path, &i.to_string());
+            match item {
+                CtxObj::Context(ctx_step) => {
+                    self.step(&path_step, ctx_step, ctx_global);
+                    steps.push(ctx_step.to_owned());
+                },
+                _ => { self.error(&path_step, format!("Step {} must be a mapping, not {}.", i+1, type_name(item))); }
+            }
+        }
+        if path == "steps" {
+            if let Err(e) = scheduler::dependencies(&steps) {
+                self.error(path, e);
+            }
+        }
+    }
+
+    fn step(&mut self, path: &str, ctx_step: &Context, ctx_global: &Context) {
+        self.keys(path, ctx_step, STEP_KEYS, false);
+        let at = |key: &str| join(path, key);
+        let action = match ctx_step.get("action") {
+            Some(CtxObj::Str(action)) => Some(action.as_str()),
+            Some(val) => {
+                self.error(&at("action"), format!("Key `action` must be a string, not {}.", type_name(val)));
+                None
+            },
+            None => {
+                self.error(path, String::from("Key `action` is required."));
+                None
+            }
+        };
+        self.expect(&at("name"), "name", ctx_step.get("name"), "a string", |v| matches!(v, CtxObj::Str(_)));
+        self.expect(&at("when"), "when", ctx_step.get("when"), "a string or a boolean", |v| matches!(v, CtxObj::Str(_) | CtxObj::Bool(_)));
+        self.expect(&at("tags"), "tags", ctx_step.get("tags"), "a string or a list of strings", |v| match v {
+            CtxObj::Str(_) => true,
+            CtxObj::Array(tags) => tags.iter().all(|tag| matches!(tag, CtxObj::Str(_))),
+            _ => false
+        });
+        self.expect(&at("inputs"), "inputs", ctx_step.get("inputs"), "a list of paths, globs or `ctx: key`", |v| match v {
+            CtxObj::Array(inputs) => inputs.iter().all(|input| match input {
+                CtxObj::Str(_) => true,
+                CtxObj::Context(ctx) => matches!(ctx.get("ctx"), Some(CtxObj::Str(_))),
+                _ => false
+            }),
+            _ => false
+        });
+        self.expect(&at("outputs"), "outputs", ctx_step.get("outputs"), "a list of paths", |v| match v {
+            CtxObj::Array(outputs) => outputs.iter().all(|output| matches!(output, CtxObj::Str(_))),
+            _ => false
+        });
+        for key in ["timeout", "idle_timeout"].iter() {
+            if let Some(val) = ctx_step.get(key) {
+                if parse_duration(val).is_none() && !templated(val) {
+                    self.error(&at(key), format!("Key `{}` must be a duration, e.g. 30m.", key));
+                }
+            }
+        }
+        if ctx_step.get("idle_timeout").is_some() {
+            if let Some(CtxObj::Context(ctx_docker)) = ctx_step.get("docker") {
+                if let Some(CtxObj::Bool(true)) = ctx_docker.get("interactive") {
+                    self.error(&at("docker.interactive"), String::from("Key `docker.interactive` cannot be combined with `idle_timeout`, which pipes the output of the container."));
+                }
+            }
+        }
+        if let Some(val) = ctx_step.get("retry") {
+            if let CtxObj::Context(ctx_retry) = val {
+                self.keys(&at("retry"), ctx_retry, RETRY_KEYS, true);
+            }
+            if let Err(e) = RetryPolicy::from_step(ctx_step) {
+                self.error(&at("retry"), e);
+            }
+        }
+        self.foreach(path, ctx_step);
+        if let Some(val) = ctx_step.get("docker") {
+            self.docker(&at("docker"), val);
+        }
+        if let Some(val) = ctx_step.get("whitelist") {
+            self.whitelist(&at("whitelist"), val);
+        }
+        let ctx = ctx_global.overlay(ctx_step);
+        match action {
+            Some(action) if action.starts_with("sys_") => self.builtin(path, action, &ctx),
+            Some(action) => self.action(path, action, &ctx),
+            None => {}
+        }
+    }
+
+    fn foreach(&mut self, path: &str, ctx_step: &Context) {
+        let at = |key: &str| join(path, key);
+        if ctx_step.get("foreach").is_some() && ctx_step.get("with_items").is_some() {
+            self.warning(&at("with_items"), String::from("Key `with_items` is ignored along with `foreach`."));
+        }
+        self.expect(&at("foreach"), "foreach", ctx_step.get("foreach"), "a list", |v| matches!(v, CtxObj::Array(_)));
+        self.expect(&at("with_items"), "with_items", ctx_step.get("with_items"), "a list", |v| matches!(v, CtxObj::Array(_)));
+        self.expect(&at("loop_var"), "loop_var", ctx_step.get("loop_var"), "a string", |v| matches!(v, CtxObj::Str(_)));
+        self.expect(&at("concurrency"), "concurrency", ctx_step.get("concurrency"), "a positive integer", |v| matches!(v, CtxObj::Int(n) if *n > 0));
+        self.expect(&at("register"), "register", ctx_step.get("register"), "a string", |v| matches!(v, CtxObj::Str(_)));
+    }
+
+    fn docker(&mut self, path: &str, val: &CtxObj) {
+        let ctx_docker = match val {
+            CtxObj::Context(ctx_docker) => ctx_docker,
+            _ => {
+                self.error(path, format!("Key `docker` must be a mapping, not {}.", type_name(val)));
+                return;
+            }
+        };
+        self.keys(path, ctx_docker, DOCKER_KEYS, true);
+        let at = |key: &str| join(path, key);
+        match ctx_docker.get("image") {
+            Some(_) => self.expect(&at("image"), "docker.image", ctx_docker.get("image"), "a string", |v| matches!(v, CtxObj::Str(_))),
+            None => self.error(path, String::from("Key `docker.image` is required."))
+        }
+        for key in ["interactive", "gui"].iter() {
+            self.expect(&at(key), &format!("docker.{}", key), ctx_docker.get(key), "a boolean", |v| matches!(v, CtxObj::Bool(_)));
+        }
+        for key in ["impersonate", "runtime", "ipc", "network", "name"].iter() {
+            self.expect(&at(key), &format!("docker.{}", key), ctx_docker.get(key), "a string", |v| matches!(v, CtxObj::Str(_)));
+        }
+        self.expect(&at("gpus"), "docker.gpus", ctx_docker.get("gpus"), "an integer", |v| matches!(v, CtxObj::Int(_)));
+        self.expect(&at("vars"), "docker.vars", ctx_docker.get("vars"), "a mapping", |v| matches!(v, CtxObj::Context(_)));
+        for key in ["volumes", "ports", "environment"].iter() {
+            let items = match ctx_docker.get(key) {
+                Some(CtxObj::Array(items)) => items,
+                Some(val) if !templated(val) => {
+                    self.error(&at(key), format!("Key `docker.{}` must be a list of strings, not {}.", key, type_name(val)));
+                    continue;
+                },
+                _ => { continue; }
+            };
+            for (i, item) in items.iter().enumerate() {
+                let path_item = join(&at(key), &i.to_string());
+                let s = match item {
+                    CtxObj::Str(s) => s,
+                    _ => {
+                        self.error(&path_item, format!("Each of `docker.{}` must be a string, not {}.", key, type_name(item)));
+                        continue;
+                    }
+                };
+                if *key == "volumes" && !is_template(s) {
+                    match s.find(':') {
+                        Some(i) => {
+                            let src = &s[..i];
+                            if !Path::new(src).exists() {
+                                self.warning(&path_item, format!("Volume `{}` will not be mounted, since `{}` does not exist.", s, src));
+                            }
+                        },
+                        None => { self.error(&path_item, format!("Volume `{}` must be of the form src:dst, optionally followed by :ro, :rw, :z or :Z.", s)); }
+                    }
+                }
+                if *key == "ports" && !is_template(s) && !s.split(':').all(|part| !part.is_empty()) {
+                    self.error(&path_item, format!("Port mapping `{}` is malformed, e.g. 8888:8888.", s));
+                }
+            }
+        }
+    }
+
+    fn whitelist(&mut self, path: &str, val: &CtxObj) {
+        let items = match val {
+            CtxObj::Array(items) => items,
+            _ => {
+                self.error(path, format!("Key `whitelist` must be a list, not {}.", type_name(val)));
+                return;
+            }
+        };
+        for (i, item) in items.iter().enumerate() {
+            let path_item = join(path, &i.to_string());
+            let ctx_source = match item {
+                CtxObj::Context(ctx_source) => ctx_source,
+                _ => {
+                    self.error(&path_item, format!("Each of `whitelist` must be a mapping, not {}.", type_name(item)));
+                    continue;
+                }
+            };
+            self.keys(&path_item, ctx_source, WHITELIST_KEYS, true);
+            match ctx_source.get("src") {
+                Some(CtxObj::Str(src)) => {
+                    self.file(&join(&path_item, "src"), "source", src);
+                    if let Ok(symbols) = crate::symbols::load(self.playbook_dir.join(src)) {
+                        for (line, problem) in symbols.problems {
+                            self.warning(&join(&path_item, "src"), format!("{}:{}: {}", src, line, problem));
+                        }
+                    }
+                },
+                Some(val) => self.error(&join(&path_item, "src"), format!("Key `src` must be a string, not {}.", type_name(val))),
+                None => self.error(&path_item, String::from("Key `src` is required."))
+            }
+        }
+    }
+
+    /// Check that an action can be found in the whitelist
+    fn action(&mut self, path: &str, action: &str, ctx: &Context) {
+        let path_action = join(path, "action");
+        if is_template(action) { return; }
+        let whitelist = match ctx.list_contexts("whitelist") {
+            Some(whitelist) => whitelist,
+            None => {
+                let hint = match suggest(action, builtins::NAMES.iter().cloned()) {
+                    Some(candidate) => format!(" Did you mean `{}`?", candidate),
+                    None => String::new()
+                };
+                self.error(&path_action, format!("Action `{}` is not a built-in, and there is no whitelist to find it in.{}", action, hint));
+                return;
+            }
+        };
+        let mut known = Vec::new();
+        for ctx_source in whitelist {
+            if let Some(CtxObj::Str(src)) = ctx_source.get("src") {
+                // * Missing sources are reported along with the whitelist.
+                if let Ok(symbols) = crate::symbols::load(self.playbook_dir.join(src)) {
+                    if symbols.function(action).is_some() { return; }
+                    known.extend(symbols.actions().map(String::from));
+                }
+            }
+        }
+        let hint = match suggest(action, known.iter().map(|s| s.as_str())) {
+            Some(candidate) => format!(" Did you mean `{}`?", candidate),
+            None => String::new()
+        };
+        self.error(&path_action, format!("Cannot find the action `{}` in the whitelist.{}", action, hint));
+    }
+
+    fn builtin(&mut self, path: &str, action: &str, ctx: &Context) {
+        let at = |key: &str| join(path, key);
+        match action {
+            "sys_exit" => {
+                self.expect(&at("exit_code"), "exit_code", ctx.get("exit_code"), "an integer", |v| matches!(v, CtxObj::Int(_)));
+            },
+            "sys_shell" => {
+                self.expect(&at("bash"), "bash", ctx.get("bash"), "a list of strings", |v| matches!(v, CtxObj::Array(args) if args.iter().all(|arg| matches!(arg, CtxObj::Str(_)))));
+                if ctx.get("docker").is_none() {
+                    self.error(&at("action"), String::from("Action `sys_shell` requires a `docker` context."));
+                }
+            },
+            "sys_vars" => match ctx.get("states") {
+                Some(CtxObj::Context(ctx_states)) => match ctx_states.get("from") {
+                    Some(CtxObj::Str(from)) => self.file(&at("states.from"), "file", from),
+                    Some(val) => self.error(&at("states.from"), format!("Key `states.from` must be a string, not {}.", type_name(val))),
+                    None => self.warning(&at("states"), String::from("Action `sys_vars` does nothing without `states.from`."))
+                },
+                Some(val) => self.error(&at("states"), format!("Key `states` must be a mapping, not {}.", type_name(val))),
+                None => self.warning(&at("action"), String::from("Action `sys_vars` does nothing without `states.from`."))
+            },
+            "sys_fork" => {
+                match ctx.get("grid") {
+                    Some(CtxObj::Array(params)) => {
+                        for (i, param) in params.iter().enumerate() {
+                            let ok = match param {
+                                CtxObj::Context(ctx_param) => {
+                                    let keys: Vec<&String> = ctx_param.keys().collect();
+                                    keys.len() == 1 && matches!(ctx_param.get(keys[0]), Some(CtxObj::Array(_)))
+                                },
+                                _ => false
+                            };
+                            if !ok {
+                                self.error(&join(&at("grid"), &i.to_string()), String::from("Each of `grid` must be a mapping from a single parameter to a list of values, e.g. `- lr: [0.1, 0.01]`."));
+                            }
+                        }
+                    },
+                    Some(val) => self.error(&at("grid"), format!("Key `grid` must be a list, not {}.", type_name(val))),
+                    None => self.error(&at("action"), String::from("Key `grid` is required by `sys_fork`."))
+                }
+                if ctx.get("resource").is_some() {
+                    self.warning(&at("resource"), String::from("Key `resource` is not supported by `sys_fork` yet."));
+                }
+            },
+            "sys_include" => match ctx.get("from") {
+                Some(CtxObj::Str(from)) => {
+                    self.file(&at("from"), "playbook", from);
+                    self.expect(&at("vars"), "vars", ctx.get("vars"), "a mapping", |v| matches!(v, CtxObj::Context(_)));
+                },
+                Some(val) => self.error(&at("from"), format!("Key `from` must be a string, not {}.", type_name(val))),
+                None => self.error(&at("action"), String::from("Key `from` is required by `sys_include`."))
+            },
+            _ => {
+                let step = Context::new().set("action", CtxObj::Str(action.to_owned()));
+                if builtins::resolve(&step).1.is_none() {
+                    let hint = match suggest(action, builtins::NAMES.iter().cloned()) {
+                        Some(candidate) => format!(" Did you mean `{}`?", candidate),
+                        None => String::new()
+                    };
+                    self.error(&at("action"), format!("Unknown built-in action `{}`.{}", action, hint));
+                }
+            }
+        }
+    }
+}
+
+/// Validate a playbook, whose relative paths are resolved against the directory of `playbook`
+pub fn check(raw: &Context, playbook: &str) -> Vec<Diagnostic> {
+    let playbook_dir = match Path::new(playbook).parent() {
+        Some(parent) => parent,
+        None => Path::new(".")
+    };
+    let mut linter = Linter { playbook_dir, diagnostics: Vec::new() };
+    linter.playbook(raw);
+    linter.diagnostics
+}
+
+/// The positions of a playbook, along with the document of the selected stage if any
+fn parse_stages(contents: &str, stage: Option<&str>) -> Result<(Positions, Option<usize>), ScanError> {
+    let positions = Positions::parse(contents)?;
+    let doc_stage = match stage {
+        Some(stage) => match YamlLoader::load_from_str(contents) {
+            Ok(docs) => crate::stage_document(&docs.into_iter().map(Context::from).collect::<Vec<Context>>(), stage),
+            Err(_) => None
+        },
+        None => None
+    };
+    Ok((positions, doc_stage))
+}
+
+/// Where a path is in the playbook, which the selected stage takes precedence over the first document for
+fn locate_in(positions: &Positions, doc_stage: Option<usize>, path: &str) -> Option<Marker> {
+    let top = path.split('.').next().unwrap_or("");
+    let doc = doc_stage.filter(|&i| positions.contains(i, top)).unwrap_or(0);
+    positions.locate(doc, path)
+}
+
+/// Where a path is in a playbook file, e.g. `steps.2`
+pub(crate) fn locate(playbook: &str, path: &str) -> Option<Location> {
+    let (fname, stage) = crate::split_stage(playbook);
+    let contents = crate::read_contents(fname).ok()?;
+    let (positions, doc_stage) = parse_stages(&contents, stage).ok()?;
+    locate_in(&positions, doc_stage, path).map(|marker| Location { file: fname.to_owned(), line: marker.line(), col: marker.col()+1 })
+}
+
+/// Validate a playbook and report the diagnostics, which fails if there is any error
+pub fn lint<P: AsRef<Path>>(playbook: P) -> Result<(), Error> {
+    let playbook_str = playbook.as_ref().to_string_lossy().into_owned();
+    let (fname, stage) = crate::split_stage(&playbook_str);
+    let contents = match crate::read_contents(fname) {
+        Ok(v) => v,
+        Err(e) => {
+            return Err(failed!(ExitCode::ErrSys, "IO Error (while loading the playbook {:?}): {}", fname, e));
+        }
+    };
+    let (positions, doc_stage) = match parse_stages(&contents, stage) {
+        Ok(ret) => ret,
+        Err(e) => {
+            let marker = *e.marker();
+            println!("{}:{}:{}: {}: {}", fname, marker.line(), marker.col()+1, "error".red().bold(), e);
+            let location = Location { file: fname.to_owned(), line: marker.line(), col: marker.col()+1 };
+            return Err(Error::new(ErrorKind::Syntax, e.to_string()).with_location(Some(location)).with_source(e));
+        }
+    };
+    let raw = crate::load_yaml(playbook.as_ref())?;
+    let mut diagnostics: Vec<(Option<Marker>, Diagnostic)> = check(&raw, fname).into_iter()
+        .map(|diagnostic| (locate_in(&positions, doc_stage, &diagnostic.path), diagnostic)).collect();
+    diagnostics.sort_by_key(|(marker, _)| marker.map(|marker| marker.index()));
+    let mut errors = 0;
+    for (marker, diagnostic) in diagnostics.iter() {
+        let severity = match diagnostic.severity {
+            Severity::Error => { errors += 1; "error".red().bold() },
+            Severity::Warning => "warning".yellow().bold()
+        };
+        match marker {
+            Some(marker) => println!("{}:{}:{}: {}: {}", fname, marker.line(), marker.col()+1, severity, diagnostic.message),
+            None => println!("{}: {}: {}", fname, severity, diagnostic.message)
+        }
+    }
+    let warnings = diagnostics.len() - errors;
+    if diagnostics.is_empty() {
+        info!("{}: no issues found.", fname);
+    }
+    else {
+        println!("{}: {} error(s), {} warning(s)", fname, errors, warnings);
+    }
+    if errors > 0 {
+        Err(Error::new(ErrorKind::Syntax, format!("The playbook {} has {} error(s).", fname, errors)))
+    }
+    else { Ok(()) }
+}
+
+#[test]
+fn test_positions() {
+    let positions = Positions::parse("steps:\n- action: train\n  docker:\n    image: x\n    volumes: [a, b]\n").unwrap();
+    let line = |path: &str| positions.locate(0, path).map(|marker| (marker.line(), marker.col()));
+    assert_eq!(line("steps"), Some((1, 0)));
+    assert_eq!(line("steps.0.action"), Some((2, 2)));
+    assert_eq!(line("steps.0.docker.image"), Some((4, 4)));
+    assert_eq!(line("steps.0.docker.volumes.1"), Some((5, 17)));
+    assert_eq!(line("steps.0.docker.gui"), Some((3, 2)));
+}
+
+#[test]
+fn test_check() {
+    let raw = Context::from("steps:\n- acton: train\n- action: sys_fork\n  grid: [lr]\n  docker:\n    image: x\n    interactive: 'yes'\n    imag: y\n    ports: ['8888:']");
+    let diagnostics = check(&raw, "playbook.yml");
+    let find = |path: &str| diagnostics.iter().find(|d| d.path == path).map(|d| (d.severity.clone(), d.message.as_str()));
+    assert_eq!(find("steps.0.acton"), Some((Severity::Warning, "Unknown key `acton`, did you mean `action`?")));
+    assert_eq!(find("steps.0"), Some((Severity::Error, "Key `action` is required.")));
+    assert_eq!(find("steps.1.grid.0").map(|d| d.0), Some(Severity::Error));
+    assert_eq!(find("steps.1.docker.interactive"), Some((Severity::Error, "Key `docker.interactive` must be a boolean, not a string.")));
+    assert_eq!(find("steps.1.docker.imag"), Some((Severity::Warning, "Unknown key `imag`, did you mean `image`?")));
+    assert_eq!(find("steps.1.docker.ports.0").map(|d| d.0), Some(Severity::Error));
+    assert_eq!(diagnostics.len(), 6);
+    assert_eq!(suggest("idle-timeout", STEP_KEYS.iter().cloned()), Some("idle_timeout"));
+    assert_eq!(suggest("lr", STEP_KEYS.iter().cloned()), None);
+    let raw = Context::from("steps:\n- action: train\n  idle_timeout: 10m\n  docker:\n    image: x\n    interactive: true");
+    let diagnostics = check(&raw, "playbook.yml");
+    assert!(diagnostics.iter().any(|d| d.path == "steps.0.docker.interactive" && d.severity == Severity::Error));
+}
@@ -23,7 +23,7 @@ fn setup_logger(verbose: u64) -> Result<(), fern::InitError> {
                 "{} {} {}",
                 chrono::Local::now().format("[%Y-%m-%d %H:%M:%S]"),
                 record.level(),
-                message
+                playbook_api::secrets::redact(&message.to_string())
             ))
         })
         .level(match verbose {
@@ -46,6 +46,13 @@ macro_rules! map_arg {
     }
 }
 
+macro_rules! map_args {
+    ($args:ident => $name:expr) => {
+        if let Some(values) = $args.values_of(stringify!{$name}) { Some(CtxObj::Array(values.map(|s| CtxObj::Str(s.to_owned())).collect())) }
+        else { None }
+    }
+}
+
 fn main() {
     #[cfg(all(feature = "sandbox", feature = "agent"))]
     compile_error!("features `playbook/sandbox` and `playbook/agent` are mutually exclusive");
@@ -57,6 +64,7 @@ fn main() {
             (about: crate_description!())
             (@arg RESUME: --("arg-resume") +takes_value "For playbook-rs use ONLY: indicator that we have entered a container")
             (@arg ASSERT_VER: --("arg-version") +takes_value "For playbook-rs use ONLY: to ensure the binary versions match")
+            (@arg OVERRIDES: --("arg-overrides") +takes_value "For playbook-rs use ONLY: overrides of the context from the host")
             (@arg VERBOSE: --verbose -v ... "Logging verbosity")
             (@arg PLAYBOOK: +required "YAML playbook")
         ).get_matches();
@@ -67,7 +75,21 @@ fn main() {
             (author: crate_authors!())
             (about: crate_description!())
             (@arg VERBOSE: --verbose -v ... "Logging verbosity")
-            (@arg PLAYBOOK: +required "YAML playbook")
+            (@arg ONLY: --only +takes_value +use_delimiter "Run only these steps, by name or index")
+            (@arg FROM: --from +takes_value "Run from this step on, by name or index")
+            (@arg UNTIL: --until +takes_value "Run until this step, by name or index")
+            (@arg TAGS: --tags +takes_value +use_delimiter "Run only the steps with any of these tags")
+            (@arg KEEP_STATEFUL: --("keep-stateful") "Run the steps that are known to contribute states, i.e. sys_vars and registered loops, even if they are not selected")
+            (@arg REBUILD: --rebuild "Run the steps even if their inputs and outputs are up to date")
+            (@arg REPORT: --report +takes_value +multiple number_of_values(1) "Write a report of the run, as JUnit XML if the path ends with .xml or else as JSON")
+            (@arg EXTRA_VARS: -e --("extra-var") +takes_value +multiple number_of_values(1) "Override a variable of the context, e.g. -e learning_rate=0.01 or -e docker.image=acme/train:v2")
+            (@arg VARS: --vars +takes_value +multiple number_of_values(1) "Override variables of the context with those of a YAML file")
+            (@arg PLAN: --plan conflicts_with[RESUME_RUN] "Show the resolved context and the container command of each step without running any")
+            (@arg LINT: --lint conflicts_with[RESUME_RUN] conflicts_with[PLAN] "Validate a playbook without running any step")
+            (@arg ACTIONS: --actions conflicts_with[RESUME_RUN] conflicts_with[PLAN] conflicts_with[LINT] "List the actions available to a playbook, with their sources")
+            (@arg NO_JOURNAL: --("no-journal") conflicts_with[PLAN] conflicts_with[RESUME_RUN] "Do not journal the run under ~/.playbook-rs/runs/, which makes it impossible to resume")
+            (@arg RESUME_RUN: --resume +takes_value conflicts_with[PLAYBOOK] "Resume a journaled run from its first incomplete step, given its run ID (a flag, since any playbook may be named `resume`)")
+            (@arg PLAYBOOK: required_unless[RESUME_RUN] "YAML playbook")
         ).get_matches();
     #[cfg(not(feature = "agent"))]
     #[cfg(feature = "as_switch")]
@@ -77,7 +99,21 @@ fn main() {
             (about: crate_description!())
             (@arg VERBOSE: --verbose -v ... "Logging verbosity")
             (@arg AS_SWITCH: --as +takes_value "Call into other types of infrastructures")
-            (@arg PLAYBOOK: +required "YAML playbook")
+            (@arg ONLY: --only +takes_value +use_delimiter "Run only these steps, by name or index")
+            (@arg FROM: --from +takes_value "Run from this step on, by name or index")
+            (@arg UNTIL: --until +takes_value "Run until this step, by name or index")
+            (@arg TAGS: --tags +takes_value +use_delimiter "Run only the steps with any of these tags")
+            (@arg KEEP_STATEFUL: --("keep-stateful") "Run the steps that are known to contribute states, i.e. sys_vars and registered loops, even if they are not selected")
+            (@arg REBUILD: --rebuild "Run the steps even if their inputs and outputs are up to date")
+            (@arg REPORT: --report +takes_value +multiple number_of_values(1) "Write a report of the run, as JUnit XML if the path ends with .xml or else as JSON")
+            (@arg EXTRA_VARS: -e --("extra-var") +takes_value +multiple number_of_values(1) "Override a variable of the context, e.g. -e learning_rate=0.01 or -e docker.image=acme/train:v2")
+            (@arg VARS: --vars +takes_value +multiple number_of_values(1) "Override variables of the context with those of a YAML file")
+            (@arg PLAN: --plan conflicts_with[RESUME_RUN] "Show the resolved context and the container command of each step without running any")
+            (@arg LINT: --lint conflicts_with[RESUME_RUN] conflicts_with[PLAN] "Validate a playbook without running any step")
+            (@arg ACTIONS: --actions conflicts_with[RESUME_RUN] conflicts_with[PLAN] conflicts_with[LINT] "List the actions available to a playbook, with their sources")
+            (@arg NO_JOURNAL: --("no-journal") conflicts_with[PLAN] conflicts_with[RESUME_RUN] "Do not journal the run under ~/.playbook-rs/runs/, which makes it impossible to resume")
+            (@arg RESUME_RUN: --resume +takes_value conflicts_with[PLAYBOOK] "Resume a journaled run from its first incomplete step, given its run ID (a flag, since any playbook may be named `resume`)")
+            (@arg PLAYBOOK: required_unless[RESUME_RUN] "YAML playbook")
         ).get_matches();
     setup_logger(args.occurrences_of("VERBOSE")).expect("Logger Error.");
     if let Some(ver) = args.value_of("ASSERT_VER") {
@@ -85,15 +121,76 @@ fn main() {
             warn!("The playbook binary versions do not match: host => {} vs container => {}", &ver, &crate_version!());
         }
     }
-    let ctx_args = Context::new()
-        .set_opt("arg-resume", map_arg!(args => RESUME))
-        .set_opt("playbook", map_arg!(args => PLAYBOOK))
-        .set_opt("verbose-fern", match args.occurrences_of("VERBOSE") {
-            0 => None,
-            v => Some(CtxObj::Int(v as i64))
-        })
-        .set_opt("as-switch", map_arg!(args => AS_SWITCH));
-    let mut playbook = Path::new(args.value_of("PLAYBOOK").unwrap()).to_path_buf();
+    if args.is_present("LINT") {
+        finalize(match playbook_api::lint::lint(args.value_of("PLAYBOOK").unwrap()) {
+            Ok(()) => ExitCode::Success,
+            Err(e) => e.exit_code()
+        });
+    }
+    if args.is_present("ACTIONS") {
+        finalize(match playbook_api::symbols::actions(args.value_of("PLAYBOOK").unwrap()) {
+            Ok(()) => ExitCode::Success,
+            Err(e) => e.exit_code()
+        });
+    }
+    let ctx_args = if let Some(run_id) = args.value_of("RESUME_RUN") {
+        let journal = match playbook_api::journal::Journal::load(run_id) {
+            Ok(journal) => journal,
+            Err(e) => finalize(e)
+        };
+        if journal.status == playbook_api::journal::Status::Succeeded {
+            warn!("Run {} has already succeeded; there is nothing to resume.", run_id);
+            finalize(ExitCode::Success);
+        }
+        if let Err(e) = std::env::set_current_dir(&journal.cwd) {
+            error!("IO Error (while entering {:?}): {}", journal.cwd, e);
+            finalize(ExitCode::ErrSys);
+        }
+        journal.args
+    }
+    else {
+        // * Overrides are parsed on the host, and handed down to containers as they are.
+        let ctx_overrides = match args.value_of("OVERRIDES") {
+            Some(overrides_str) => match serde_json::from_str::<Context>(overrides_str) {
+                Ok(ctx_overrides) => ctx_overrides,
+                Err(_e) => {
+                    error!("Syntax Error: Cannot parse the `--arg-overrides` flag. {}", overrides_str.underline());
+                    finalize(ExitCode::ErrApp);
+                }
+            },
+            None => match playbook_api::overrides::from_args(args.values_of("VARS").into_iter().flatten(), args.values_of("EXTRA_VARS").into_iter().flatten()) {
+                Ok(ctx_overrides) => ctx_overrides,
+                Err(e) => finalize(e.exit_code())
+            }
+        };
+        Context::new()
+            .set_opt("arg-resume", map_arg!(args => RESUME))
+            .set_opt("playbook", map_arg!(args => PLAYBOOK))
+            .set_opt("verbose-fern", match args.occurrences_of("VERBOSE") {
+                0 => None,
+                v => Some(CtxObj::Int(v as i64))
+            })
+            .set_opt("as-switch", map_arg!(args => AS_SWITCH))
+            .set_opt("only-steps", map_args!(args => ONLY))
+            .set_opt("from-step", map_arg!(args => FROM))
+            .set_opt("until-step", map_arg!(args => UNTIL))
+            .set_opt("only-tags", map_args!(args => TAGS))
+            .set_opt("keep-stateful", if args.is_present("KEEP_STATEFUL") { Some(CtxObj::Bool(true)) } else { None })
+            .set_opt("rebuild", if args.is_present("REBUILD") { Some(CtxObj::Bool(true)) } else { None })
+            .set_opt("report", map_args!(args => REPORT))
+            .set_opt("arg-overrides", if ctx_overrides.keys().next().is_some() { Some(CtxObj::Context(ctx_overrides)) } else { None })
+    };
+    // Runs are journaled unless told otherwise, except for plans and for the parts of runs that are inside containers
+    let planning = args.is_present("PLAN");
+    let journaled = !planning && !args.is_present("NO_JOURNAL") && ctx_args.get("arg-resume").is_none();
+    let ctx_args = if journaled && ctx_args.get("run-id").is_none() {
+        ctx_args.set("run-id", CtxObj::Str(playbook_api::journal::new_run_id()))
+    }
+    else { ctx_args };
+    let mut playbook = match ctx_args.get("playbook") {
+        Some(CtxObj::Str(playbook)) => Path::new(playbook).to_path_buf(),
+        _ => unreachable!()
+    };
     if let Some(CtxObj::Str(closure_str)) = ctx_args.get("arg-resume") {
         // ! BUG this does not seem to apply to k8s containers??
         // if !playbook_api::systems::docker::inside_docker() {
@@ -123,11 +220,11 @@ fn main() {
         }
     }
     finalize(match playbook_api::load_yaml(playbook) {
-        Ok(raw) => match playbook_api::run_playbook(raw, ctx_args) {
+        Ok(raw) => match if planning { playbook_api::plan_playbook(raw, ctx_args) } else { playbook_api::run_playbook(raw, ctx_args) } {
             Ok(()) => ExitCode::Success,
-            Err(e) => e
+            Err(e) => e.exit_code()
         },
-        Err(e) => e
+        Err(e) => e.exit_code()
     });
 }
 
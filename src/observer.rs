@@ -0,0 +1,96 @@
+//! Observers of the lifecycle of a run, for library users to drive a UI or metrics of their own
+//!
+//! **Example(s)**
+//! ```rust,ignore
+//! struct Progress { done: Cell<usize> }
+//!
+//! impl RunObserver for Progress {
+//!     fn on_step_end(&self, index: usize, outcome: &Outcome) {
+//!         self.done.set(self.done.get() + 1);
+//!     }
+//! }
+//!
+//! let progress = Rc::new(Progress { done: Cell::new(0) });
+//! playbook_api::run_playbook_with(raw, ctx_args, RunOptions::new().observer(progress.clone()))?;
+//! ```
+//!
+//! Steps start and end in the process of the playbook. Callbacks from within a step, e.g. `on_container_start`,
+//! are made from whichever process runs the step, which is a child process if the step runs concurrently.
+
+use std::cell::RefCell;
+use std::io::Write;
+use std::rc::Rc;
+use colored::*;
+use ymlctx::context::Context;
+use crate::journal::{Outcome, Child};
+
+/// Callbacks of a run, all of which do nothing by default
+pub trait RunObserver {
+    /// A main step is about to run, given its 1-based index and deduced context
+    fn on_step_start(&self, _index: usize, _ctx_step: &Context) {}
+
+    /// A main step has run, or has been skipped by its `when` condition
+    fn on_step_end(&self, _index: usize, _outcome: &Outcome) {}
+
+    /// An action is about to be invoked, either a built-in one or one from `src`
+    fn on_action_start(&self, _action: &str, _src: Option<&str>, _ctx: &Context) {}
+
+    /// An action has returned
+    fn on_action_end(&self, _action: &str, _src: Option<&str>, _succeeded: bool) {}
+
+    /// A container is about to start, given the `docker run` command
+    fn on_container_start(&self, _argv: &[String]) {}
+
+    /// A child process forked by `sys_fork` has exited
+    fn on_fork_child(&self, _child: &Child) {}
+
+    /// The stateful context has been updated by a step
+    fn on_context_change(&self, _ctx_states: &Context) {}
+}
+
+/// Banners of the contexts and outputs of actions on the console
+pub struct ConsoleObserver;
+
+impl RunObserver for ConsoleObserver {
+    fn on_action_start(&self, action: &str, src: Option<&str>, ctx: &Context) {
+        if cfg!(feature = "ci_only") { return; }
+        eprintln!("{}", "== Context ======================".cyan());
+        let ctx = crate::secrets::redact(&ctx.to_string());
+        match src {
+            Some(src) => eprintln!("# ctx({}@{}) =\n{}", action.cyan(), src.dimmed(), ctx),
+            None => eprintln!("# ctx({}) =\n{}", action.cyan(), ctx)
+        }
+        eprintln!("{}", "== EOF ==========================".cyan());
+        if src.is_some() {
+            let _ = std::io::stderr().flush();
+            println!("{}", "== Output =======================".blue());
+        }
+    }
+
+    fn on_action_end(&self, _action: &str, src: Option<&str>, _succeeded: bool) {
+        if cfg!(feature = "ci_only") { return; }
+        if src.is_some() {
+            println!("{}", "== EOF ==========================".blue());
+        }
+    }
+}
+
+thread_local! {
+    static OBSERVERS: RefCell<Vec<Rc<dyn RunObserver>>> = RefCell::new(vec![Rc::new(ConsoleObserver)]);
+}
+
+/// Replace the observers of the runs, and tell the previous ones
+pub(crate) fn replace(observers: Vec<Rc<dyn RunObserver>>) -> Vec<Rc<dyn RunObserver>> {
+    OBSERVERS.with(|cell| cell.replace(observers))
+}
+
+/// Make a callback to each of the observers
+pub(crate) fn notify<F>(callback: F)
+  where F: Fn(&dyn RunObserver)
+{
+    // * Observers are free to run another playbook from a callback.
+    let observers = OBSERVERS.with(|cell| cell.borrow().clone());
+    for observer in observers.iter() {
+        callback(observer.as_ref());
+    }
+}
@@ -0,0 +1,140 @@
+//! Overrides of the context from the command line, which take precedence over the playbook and the states
+//!
+//! **Example(s)**
+//! ```sh
+//! playbook -e learning_rate=0.01 -e docker.image=acme/train:v2 -e 'gpus=[0, 1]' --vars overrides.yml some.yml
+//! ```
+//!
+//! Values are integers, floats or booleans where they look like one, and JSON where they are a list, a map
+//! or a quoted string; anything else is a string. Dotted keys assign the variables of subcontexts, which are
+//! merged into the existing ones rather than replacing them. The files of `--vars` are layered in order,
+//! followed by each `-e` in order. Overrides are handed down to containers along with the step.
+
+use std::cell::RefCell;
+use std::path::Path;
+use yaml_rust::YamlLoader;
+use ymlctx::context::{Context, CtxObj};
+use crate::builtins::ExitCode;
+use crate::error::Error;
+
+/// Parse the value of an override
+pub fn parse_value(s: &str) -> Result<CtxObj, String> {
+    let s_trimmed = s.trim();
+    if s_trimmed.starts_with('[') || s_trimmed.starts_with('{') || s_trimmed.starts_with('"') {
+        return match YamlLoader::load_from_str(s_trimmed) {
+            Ok(ref mut docs) if docs.len() == 1 => Ok(CtxObj::from(docs.remove(0))),
+            _ => Err(format!("`{}` is not valid JSON.", s))
+        };
+    }
+    if let Ok(x) = s_trimmed.parse::<i64>() { return Ok(CtxObj::Int(x)); }
+    if let Ok(x) = s_trimmed.parse::<f64>() {
+        // * Leave the likes of `nan` and `inf` as strings.
+        if x.is_finite() { return Ok(CtxObj::Real(x)); }
+    }
+    match s_trimmed {
+        "true" => Ok(CtxObj::Bool(true)),
+        "false" => Ok(CtxObj::Bool(false)),
+        _ => Ok(CtxObj::Str(s.to_owned()))
+    }
+}
+
+/// Assign a variable of a context, or of its subcontexts given a dotted key
+pub fn assign(ctx: &Context, key: &str, val: CtxObj) -> Context {
+    match key.find('.') {
+        Some(pos) => {
+            let ctx_sub = match ctx.get(&key[..pos]) {
+                Some(CtxObj::Context(ctx_sub)) => ctx_sub.clone(),
+                _ => Context::new()
+            };
+            ctx.set(&key[..pos], CtxObj::Context(assign(&ctx_sub, &key[pos+1..], val)))
+        },
+        None => ctx.set(key, val)
+    }
+}
+
+/// Parse an override of the form `key=value` into a context of overrides
+pub fn parse(ctx_overrides: &Context, assignment: &str) -> Result<Context, String> {
+    let (key, value) = match assignment.find('=') {
+        Some(pos) => (&assignment[..pos], &assignment[pos+1..]),
+        None => { return Err(format!("`{}` must be of the form key=value.", assignment)); }
+    };
+    if key.is_empty() || key.split('.').any(|part| part.is_empty()) {
+        return Err(format!("`{}` is not a valid key.", key));
+    }
+    Ok(assign(ctx_overrides, key, parse_value(value)?))
+}
+
+/// Overlay `another` on top of a context, merging their subcontexts
+pub fn merge(ctx: &Context, another: &Context) -> Context {
+    let mut ret = ctx.clone();
+    for key in another.keys() {
+        let val = match (ctx.get(key), another.get(key)) {
+            (Some(CtxObj::Context(ctx_sub)), Some(CtxObj::Context(another_sub))) => CtxObj::Context(merge(ctx_sub, another_sub)),
+            (_, Some(val)) => val.clone(),
+            (_, None) => unreachable!()
+        };
+        ret = ret.set(key, val);
+    }
+    ret
+}
+
+/// Collect the overrides from the files of `--vars` and then each `-e`
+pub fn from_args<'a, I, J>(vars: I, assignments: J) -> Result<Context, Error>
+  where I: IntoIterator<Item = &'a str>, J: IntoIterator<Item = &'a str>
+{
+    let mut ret = Context::new();
+    for fname in vars {
+        let ctx_vars = crate::load_yaml(Path::new(fname))?;
+        ret = merge(&ret, &ctx_vars);
+    }
+    for assignment in assignments {
+        ret = match parse(&ret, assignment) {
+            Ok(ctx_overrides) => ctx_overrides,
+            Err(e) => { return Err(failed!(ExitCode::ErrApp, "Syntax Error: Cannot parse an override: {}", e)); }
+        };
+    }
+    Ok(ret)
+}
+
+thread_local! {
+    static OVERRIDES: RefCell<Context> = RefCell::new(Context::new());
+}
+
+/// Remember the overrides of a run, which arrive under the reserved `arg-overrides` argument
+pub(crate) fn install(ctx_args: &Context) {
+    let ctx_overrides = ctx_args.subcontext("arg-overrides").unwrap_or_else(Context::new);
+    OVERRIDES.with(|cell| cell.replace(ctx_overrides));
+}
+
+/// Overrides of the current run, to be handed down to containers
+pub(crate) fn current() -> Option<Context> {
+    OVERRIDES.with(|cell| {
+        let ctx_overrides = cell.borrow();
+        if ctx_overrides.keys().next().is_some() { Some(ctx_overrides.clone()) } else { None }
+    })
+}
+
+/// Apply the overrides of the current run to a context
+pub(crate) fn apply(ctx: Context) -> Context {
+    match current() {
+        Some(ctx_overrides) => merge(&ctx, &ctx_overrides),
+        None => ctx
+    }
+}
+
+#[test]
+fn test_overrides() {
+    assert_eq!(parse_value("0.01"), Ok(CtxObj::Real(0.01)));
+    assert_eq!(parse_value("8"), Ok(CtxObj::Int(8)));
+    assert_eq!(parse_value("true"), Ok(CtxObj::Bool(true)));
+    assert_eq!(parse_value("\"8\""), Ok(CtxObj::Str(String::from("8"))));
+    assert_eq!(parse_value("nan"), Ok(CtxObj::Str(String::from("nan"))));
+    assert_eq!(parse_value("[0, 1]"), Ok(CtxObj::Array(vec![CtxObj::Int(0), CtxObj::Int(1)])));
+    assert!(parse_value("[0, 1").is_err());
+    let ctx_overrides = parse(&Context::new(), "docker.image=acme/train:v2").unwrap();
+    let ctx_overrides = parse(&ctx_overrides, "docker.env={\"A\": 1}").unwrap();
+    assert!(parse(&ctx_overrides, "docker.=x").is_err());
+    assert!(parse(&ctx_overrides, "image").is_err());
+    let ctx = merge(&Context::from("docker:\n  image: acme/train:v1\n  runtime: nvidia\nlr: 0.1"), &ctx_overrides);
+    assert_eq!(ctx, Context::from("docker:\n  image: acme/train:v2\n  runtime: nvidia\n  env:\n    A: 1\nlr: 0.1"));
+}
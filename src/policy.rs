@@ -0,0 +1,219 @@
+//! Policies that govern how a step is executed
+
+use std::time::{Duration, Instant};
+use ymlctx::context::{Context, CtxObj};
+use crate::{TaskError, TaskErrorSource};
+
+/// Parse a duration, either as a number of seconds or as a string with a unit, e.g. `500ms`, `5s`, `2m`, `1h`
+pub fn parse_duration(val: &CtxObj) -> Option<Duration> {
+    match val {
+        CtxObj::Int(secs) if *secs >= 0 => Some(Duration::from_secs(*secs as u64)),
+        CtxObj::Real(secs) if *secs >= 0.0 => Some(Duration::from_millis((*secs * 1000.0) as u64)),
+        CtxObj::Str(s) => {
+            let s = s.trim();
+            let split = s.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(s.len());
+            let (value, unit) = s.split_at(split);
+            let value: f64 = value.parse().ok()?;
+            let millis = match unit.trim() {
+                "ms" => value,
+                "" | "s" => value * 1000.0,
+                "m" => value * 60_000.0,
+                "h" => value * 3_600_000.0,
+                _ => { return None; }
+            };
+            Some(Duration::from_millis(millis as u64))
+        },
+        _ => None
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Backoff {
+    Constant,
+    Linear,
+    Exponential
+}
+
+/// Retry policy of a step
+///
+/// **Example(s)**
+/// ```yaml
+/// retry:
+///   attempts: 3
+///   backoff: exponential # constant (default), linear, or exponential
+///   initial: 5s
+///   max_delay: 1m # defaults to 5m
+///   on: [signal, exit_code, external_api] # defaults to any kind of failure
+/// ```
+///
+/// The delay between attempts grows with the backoff up to `max_delay`, and there is no further attempt
+/// once the playbook would time out before it.
+/// The failure classes correspond to the variants of `TaskErrorSource`:
+/// `nix`, `exit_code`, `signal`, `internal`, `external_api`, and `timeout`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    pub attempts: usize,
+    pub backoff: Backoff,
+    pub initial: Duration,
+    pub max_delay: Duration,
+    pub on: Option<Vec<String>>
+}
+
+const DEFAULT_MAX_DELAY: Duration = Duration::from_secs(300);
+
+impl RetryPolicy {
+    /// A single attempt without retrying
+    pub fn once() -> RetryPolicy {
+        RetryPolicy { attempts: 1, backoff: Backoff::Constant, initial: Duration::from_secs(0), max_delay: DEFAULT_MAX_DELAY, on: None }
+    }
+
+    /// Read the retry policy of a step, if there is one
+    pub fn from_step(ctx_step: &Context) -> Result<Option<RetryPolicy>, String> {
+        let ctx_retry = match ctx_step.get("retry") {
+            Some(CtxObj::Context(ctx_retry)) => ctx_retry,
+            Some(_) => { return Err(String::from("Key `retry` must be a mapping.")); },
+            None => { return Ok(None); }
+        };
+        let attempts = match ctx_retry.get("attempts") {
+            Some(CtxObj::Int(n)) if *n > 0 => *n as usize,
+            None => 1,
+            Some(_) => { return Err(String::from("Key `retry.attempts` must be a positive integer.")); }
+        };
+        let backoff = match ctx_retry.get("backoff") {
+            None => Backoff::Constant,
+            Some(CtxObj::Str(s)) if s == "constant" => Backoff::Constant,
+            Some(CtxObj::Str(s)) if s == "linear" => Backoff::Linear,
+            Some(CtxObj::Str(s)) if s == "exponential" => Backoff::Exponential,
+            Some(_) => { return Err(String::from("Key `retry.backoff` must be one of constant, linear, or exponential.")); }
+        };
+        let initial = match ctx_retry.get("initial") {
+            None => Duration::from_secs(0),
+            Some(val) => match parse_duration(val) {
+                Some(initial) => initial,
+                None => { return Err(String::from("Key `retry.initial` must be a duration, e.g. 5s.")); }
+            }
+        };
+        let max_delay = match ctx_retry.get("max_delay") {
+            None => DEFAULT_MAX_DELAY,
+            Some(val) => match parse_duration(val) {
+                Some(max_delay) => max_delay,
+                None => { return Err(String::from("Key `retry.max_delay` must be a duration, e.g. 1m.")); }
+            }
+        };
+        let on = match ctx_retry.get("on") {
+            None => None,
+            Some(CtxObj::Array(classes)) => {
+                let mut on = Vec::new();
+                for class in classes {
+                    match class {
+                        CtxObj::Str(class) if TaskErrorSource::TAGS.contains(&class.as_str()) => { on.push(class.to_owned()); },
+                        _ => { return Err(format!("Key `retry.on` must be a list of failure classes: {}.", TaskErrorSource::TAGS.join(", "))); }
+                    }
+                }
+                Some(on)
+            },
+            Some(_) => { return Err(format!("Key `retry.on` must be a list of failure classes: {}.", TaskErrorSource::TAGS.join(", "))); }
+        };
+        Ok(Some(RetryPolicy { attempts, backoff, initial, max_delay, on }))
+    }
+
+    /// The delay before the next attempt, given the number of attempts that have failed so far
+    pub fn delay(&self, failed: usize) -> Duration {
+        let factor = match self.backoff {
+            Backoff::Constant => 1,
+            Backoff::Linear => failed as u32,
+            Backoff::Exponential => 2u32.saturating_pow(failed as u32 - 1)
+        };
+        match self.initial.checked_mul(factor) {
+            Some(delay) if delay < self.max_delay => delay,
+            _ => self.max_delay
+        }
+    }
+
+    /// Whether a failure is worth another attempt
+    pub fn applies(&self, src: &TaskErrorSource) -> bool {
+        match self.on {
+            Some(ref on) => on.iter().any(|class| class == src.tag()),
+            None => true
+        }
+    }
+
+    /// Run a task under this policy until it succeeds, it runs out of attempts, or the deadline is too close to retry
+    pub fn run<T, F>(&self, label: &str, deadline: Option<Instant>, mut task: F) -> Result<T, TaskError>
+      where F: FnMut() -> Result<T, TaskError>
+    {
+        let mut failed = 0;
+        loop {
+            if self.attempts > 1 {
+                info!("{}: attempt {}/{}", label, failed+1, self.attempts);
+            }
+            match task() {
+                Ok(v) => { return Ok(v); },
+                Err(e) => {
+                    failed += 1;
+                    if failed >= self.attempts || !self.applies(&e.src) {
+                        return Err(e);
+                    }
+                    let delay = self.delay(failed);
+                    if let Some(deadline) = deadline {
+                        if Instant::now() + delay >= deadline {
+                            warn!("{}: attempt {}/{} has failed ({}), and the playbook would time out before retrying.", label, failed, self.attempts, e.src.tag());
+                            return Err(e);
+                        }
+                    }
+                    warn!("{}: attempt {}/{} has failed ({}): {} Retrying in {:?}.", label, failed, self.attempts, e.src.tag(), e, delay);
+                    std::thread::sleep(delay);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_parse_duration() {
+    assert_eq!(parse_duration(&CtxObj::Int(3)), Some(Duration::from_secs(3)));
+    assert_eq!(parse_duration(&CtxObj::Real(0.5)), Some(Duration::from_millis(500)));
+    assert_eq!(parse_duration(&CtxObj::Str(String::from("250ms"))), Some(Duration::from_millis(250)));
+    assert_eq!(parse_duration(&CtxObj::Str(String::from("2m"))), Some(Duration::from_secs(120)));
+    assert_eq!(parse_duration(&CtxObj::Str(String::from("1.5h"))), Some(Duration::from_secs(5400)));
+    assert_eq!(parse_duration(&CtxObj::Str(String::from("soon"))), None);
+}
+
+#[test]
+fn test_retry_policy() {
+    let ctx_step = Context::from("retry:\n  attempts: 4\n  backoff: exponential\n  initial: 5s\n  on: [signal, exit_code]");
+    let policy = RetryPolicy::from_step(&ctx_step).unwrap().unwrap();
+    assert_eq!(policy.attempts, 4);
+    assert_eq!(policy.delay(1), Duration::from_secs(5));
+    assert_eq!(policy.delay(3), Duration::from_secs(20));
+    assert_eq!(policy.delay(100), Duration::from_secs(300));
+    assert!(policy.applies(&TaskErrorSource::ExitCode(1)));
+    assert!(!policy.applies(&TaskErrorSource::ExternalAPIError));
+    assert!(RetryPolicy::from_step(&Context::from("retry:\n  on: [flaky]")).is_err());
+    let ctx_step = Context::from("retry:\n  attempts: 4\n  backoff: linear\n  initial: 5s\n  max_delay: 8s");
+    let policy = RetryPolicy::from_step(&ctx_step).unwrap().unwrap();
+    assert_eq!(policy.delay(1), Duration::from_secs(5));
+    assert_eq!(policy.delay(2), Duration::from_secs(8));
+    assert!(RetryPolicy::from_step(&Context::from("retry:\n  max_delay: later")).is_err());
+}
+
+#[test]
+fn test_retry_run() {
+    let policy = RetryPolicy { attempts: 3, backoff: Backoff::Constant, initial: Duration::from_secs(0), max_delay: DEFAULT_MAX_DELAY, on: None };
+    let mut calls = 0;
+    let ret = policy.run("test", None, || {
+        calls += 1;
+        if calls < 3 { Err(TaskError { msg: String::from("flaky"), src: TaskErrorSource::ExitCode(1) }) }
+        else { Ok(calls) }
+    });
+    assert_eq!(ret, Ok(3));
+    // * No attempt is made that would have to wait past the deadline.
+    let policy = RetryPolicy { initial: Duration::from_secs(60), ..policy };
+    let mut calls = 0;
+    let ret: Result<(), TaskError> = policy.run("test", Some(Instant::now() + Duration::from_secs(30)), || {
+        calls += 1;
+        Err(TaskError { msg: String::from("flaky"), src: TaskErrorSource::ExitCode(1) })
+    });
+    assert!(ret.is_err());
+    assert_eq!(calls, 1);
+}
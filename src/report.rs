@@ -0,0 +1,263 @@
+//! Machine-readable report of a run, as JSON or as JUnit XML
+//!
+//! **Example(s)**
+//! ```sh
+//! playbook --report report.json --report junit.xml some.yml
+//! ```
+//!
+//! The format is JUnit XML if the path ends with `.xml`, or JSON otherwise.
+//! Each of the main steps is reported, along with the child processes forked by `sys_fork`.
+
+use std::path::Path;
+use ymlctx::context::{Context, CtxObj};
+use crate::journal::{Journal, Record, Child, Outcome};
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StepStatus {
+    Passed,
+    Failed,
+    Skipped,
+    /// The step has not been selected, or the playbook has halted before it
+    NotRun
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StepReport {
+    /// 1-based index of the step
+    pub index: usize,
+    pub name: Option<String>,
+    pub action: Option<String>,
+    /// `host`, `builtin`, or the infrastructure of the container, e.g. `docker`
+    pub infrastructure: String,
+    pub status: StepStatus,
+    pub started: Option<String>,
+    pub finished: Option<String>,
+    /// In seconds
+    pub duration: Option<f64>,
+    pub exit_code: Option<i32>,
+    pub signal: Option<String>,
+    pub message: Option<String>,
+    pub children: Vec<Child>
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Report {
+    pub playbook: String,
+    pub run_id: Option<String>,
+    pub status: StepStatus,
+    pub exit_code: i32,
+    pub started: String,
+    pub finished: String,
+    /// In seconds
+    pub duration: f64,
+    pub steps: Vec<StepReport>
+}
+
+/// Where a step runs, judging from its deduced context
+fn infrastructure(ctx_step: &Context) -> String {
+    if let (_, Some(_)) = crate::builtins::resolve(ctx_step) {
+        return String::from("builtin");
+    }
+    match (ctx_step.get("docker"), ctx_step.get("as-switch")) {
+        (Some(_), Some(CtxObj::Str(infrastructure))) => infrastructure.to_owned(),
+        (Some(_), _) => String::from("docker"),
+        (None, _) => String::from("host")
+    }
+}
+
+impl StepReport {
+    fn new(i: usize, ctx_step: &Context, record: Option<&Record>) -> StepReport {
+        let string = |key: &str| match ctx_step.get(key) {
+            Some(CtxObj::Str(s)) => Some(s.to_owned()),
+            _ => None
+        };
+        let mut report = StepReport {
+            index: i + 1,
+            name: string("name"),
+            action: string("action"),
+            infrastructure: infrastructure(ctx_step),
+            status: StepStatus::NotRun,
+            started: None,
+            finished: None,
+            duration: None,
+            exit_code: None,
+            signal: None,
+            message: None,
+            children: Vec::new()
+        };
+        if let Some(record) = record {
+            report.started = record.started.to_owned();
+            report.finished = Some(record.time.to_owned());
+            report.duration = record.duration;
+            report.children = record.children.to_owned();
+            match record.outcome {
+                Outcome::Stateful(_) | Outcome::Stateless => {
+                    report.status = StepStatus::Passed;
+                    report.exit_code = Some(0);
+                },
+                Outcome::Skipped => { report.status = StepStatus::Skipped; },
+                Outcome::Diverging(exit_code) => {
+                    report.status = if exit_code == 0 { StepStatus::Passed } else { StepStatus::Failed };
+                    report.exit_code = Some(exit_code);
+                    if let Some(ref failure) = record.failure {
+                        report.message = Some(failure.message.to_owned());
+                        // * Exit codes of containers take precedence over those of playbook-rs.
+                        if failure.exit_code.is_some() { report.exit_code = failure.exit_code; }
+                        report.signal = failure.signal.to_owned();
+                    }
+                }
+            }
+        }
+        report
+    }
+}
+
+impl Report {
+    /// Report on a run from its journal
+    pub(crate) fn new(steps: &[Context], ctx_global: &Context, ctx_args: &Context, journal: &Journal, started: chrono::DateTime<chrono::Local>, exit_code: i32) -> Report {
+        let finished = chrono::Local::now();
+        let playbook = match ctx_args.get("playbook") {
+            Some(CtxObj::Str(playbook)) => playbook.to_owned(),
+            _ => String::new()
+        };
+        Report {
+            playbook,
+            run_id: journal.run_id().map(String::from),
+            status: if exit_code == 0 { StepStatus::Passed } else { StepStatus::Failed },
+            exit_code,
+            started: started.to_rfc3339(),
+            finished: finished.to_rfc3339(),
+            duration: (finished - started).num_milliseconds() as f64 / 1000.0,
+            steps: steps.iter().enumerate().map(|(i, ctx_step)| {
+                StepReport::new(i, &ctx_global.overlay(ctx_step).overlay(ctx_args), journal.latest(i))
+            }).collect()
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    pub fn to_junit(&self) -> String {
+        let count = |status: StepStatus| self.steps.iter().filter(|step| step.status == status).count();
+        // * Each child process of a step is a test case of its own.
+        let children = self.steps.iter().flat_map(|step| step.children.iter());
+        let (tests, failures, skipped) = (
+            self.steps.len() + children.clone().count(),
+            count(StepStatus::Failed) + children.filter(|child| child_failed(child)).count(),
+            count(StepStatus::Skipped) + count(StepStatus::NotRun)
+        );
+        let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        xml.push_str(&format!("<testsuites name=\"playbook\" tests=\"{}\" failures=\"{}\" skipped=\"{}\" time=\"{:.3}\">\n", tests, failures, skipped, self.duration));
+        xml.push_str(&format!("  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" skipped=\"{}\" time=\"{:.3}\" timestamp=\"{}\">\n",
+            escape(&self.playbook), tests, failures, skipped, self.duration, escape(&self.started)));
+        for step in self.steps.iter() {
+            let name = match step.name {
+                Some(ref name) => format!("Step {}: {}", step.index, name),
+                None => format!("Step {}", step.index)
+            };
+            xml.push_str(&format!("    <testcase classname=\"{}\" name=\"{}\" time=\"{:.3}\">\n", escape(&self.playbook), escape(&name), step.duration.unwrap_or(0.0)));
+            xml.push_str("      <properties>\n");
+            if let Some(ref action) = step.action {
+                xml.push_str(&format!("        <property name=\"action\" value=\"{}\"/>\n", escape(action)));
+            }
+            xml.push_str(&format!("        <property name=\"infrastructure\" value=\"{}\"/>\n", escape(&step.infrastructure)));
+            if let Some(ref started) = step.started {
+                xml.push_str(&format!("        <property name=\"started\" value=\"{}\"/>\n", escape(started)));
+            }
+            xml.push_str("      </properties>\n");
+            match step.status {
+                StepStatus::Failed => {
+                    let kind = match (step.exit_code, &step.signal) {
+                        (_, Some(sig)) => format!("signal {}", sig),
+                        (Some(exit_code), None) => format!("exit code {}", exit_code),
+                        (None, None) => String::from("failure")
+                    };
+                    let message = step.message.to_owned().unwrap_or_else(|| format!("The step has failed with {}.", kind));
+                    xml.push_str(&format!("      <failure message=\"{}\" type=\"{}\"/>\n", escape(&message), escape(&kind)));
+                },
+                StepStatus::Skipped => { xml.push_str("      <skipped message=\"condition\"/>\n"); },
+                StepStatus::NotRun => { xml.push_str("      <skipped message=\"not run\"/>\n"); },
+                StepStatus::Passed => {}
+            }
+            xml.push_str("    </testcase>\n");
+            for child in step.children.iter() {
+                let mut params: Vec<String> = child.params.keys().map(|key| format!("{}={}", key, show(child.params.get(key).unwrap()))).collect();
+                params.sort();
+                xml.push_str(&format!("    <testcase classname=\"{}\" name=\"{} [{}]\" time=\"0\">\n", escape(&self.playbook), escape(&name), escape(&params.join(", "))));
+                match (child.exit_code, &child.signal) {
+                    (_, Some(sig)) => xml.push_str(&format!("      <failure message=\"The child process has received {}.\" type=\"signal {}\"/>\n", escape(sig), escape(sig))),
+                    (Some(exit_code), None) if child_failed(child) => xml.push_str(&format!("      <failure message=\"The child process has exited with code {}.\" type=\"exit code {}\"/>\n", exit_code, exit_code)),
+                    _ => {}
+                }
+                xml.push_str("    </testcase>\n");
+            }
+        }
+        xml.push_str("  </testsuite>\n</testsuites>\n");
+        xml
+    }
+
+    /// Write the report to each of the paths given by the `report` argument
+    pub(crate) fn write(&self, ctx_args: &Context) {
+        let paths: Vec<String> = match ctx_args.get("report") {
+            Some(CtxObj::Array(paths)) => paths.iter().filter_map(|p| if let CtxObj::Str(p) = p { Some(p.to_owned()) } else { None }).collect(),
+            Some(CtxObj::Str(p)) => vec![p.to_owned()],
+            _ => { return; }
+        };
+        for path in paths {
+            let contents = if Path::new(&path).extension().is_some_and(|ext| ext == "xml") { self.to_junit() } else { self.to_json() };
+            match std::fs::write(&path, contents) {
+                Ok(()) => info!("The report has been written to {}.", path),
+                Err(e) => warn!("IO Error (while writing the report {}): {}", path, e)
+            }
+        }
+    }
+}
+
+/// A parameter of a child process, as it is shown in its name
+fn show(val: &CtxObj) -> String {
+    match val {
+        CtxObj::Str(s) => s.to_owned(),
+        CtxObj::Int(x) => x.to_string(),
+        CtxObj::Real(x) => x.to_string(),
+        CtxObj::Bool(x) => x.to_string(),
+        _ => String::from("..")
+    }
+}
+
+fn child_failed(child: &Child) -> bool {
+    child.signal.is_some() || child.exit_code.is_some_and(|exit_code| exit_code != 0)
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;").replace('\'', "&apos;")
+}
+
+#[test]
+fn test_junit() {
+    let step = |index: usize, status: StepStatus| StepReport {
+        index, name: Some(String::from("a <b>")), action: Some(String::from("train")), infrastructure: String::from("docker"),
+        status, started: None, finished: None, duration: Some(1.5), exit_code: Some(137), signal: None, message: None,
+        children: Vec::new()
+    };
+    let report = Report {
+        playbook: String::from("some.yml"), run_id: None, status: StepStatus::Failed, exit_code: 4,
+        started: String::new(), finished: String::new(), duration: 2.0,
+        steps: vec![step(1, StepStatus::Passed), step(2, StepStatus::Failed), step(3, StepStatus::NotRun)]
+    };
+    let xml = report.to_junit();
+    assert!(xml.contains("tests=\"3\" failures=\"1\" skipped=\"1\""));
+    assert!(xml.contains("name=\"Step 2: a &lt;b&gt;\" time=\"1.500\""));
+    assert!(xml.contains("<failure message=\"The step has failed with exit code 137.\" type=\"exit code 137\"/>"));
+    assert!(report.to_json().contains("\"status\": \"not_run\""));
+    let mut forked = step(4, StepStatus::Passed);
+    forked.children = vec![
+        Child { params: Context::from("n: 1"), exit_code: Some(0), signal: None },
+        Child { params: Context::from("n: 2"), exit_code: Some(1), signal: None },
+        Child { params: Context::from("n: 3"), exit_code: None, signal: Some(String::from("SIGKILL")) }
+    ];
+    let report = Report { steps: vec![step(1, StepStatus::Passed), forked], ..report };
+    let xml = report.to_junit();
+    assert_eq!(xml.matches("tests=\"5\" failures=\"2\" skipped=\"0\"").count(), 2);
+}
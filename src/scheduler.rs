@@ -0,0 +1,517 @@
+//! Dependency graph of steps declared with `needs`, and a scheduler that runs independent branches concurrently.
+//!
+//! **Example(s)**
+//! ```yaml
+//! steps:
+//! - name: prep-a
+//!   action: preprocess
+//!   needs: []
+//! - name: prep-b
+//!   action: preprocess
+//!   needs: []
+//! - name: train
+//!   action: train
+//!   needs: [prep-a, prep-b]
+//! ```
+//!
+//! Once any step declares `needs`, a step without it implicitly needs the step right before it.
+//! Each step of the graph runs in a child process of its own. A step sees the stateful contexts
+//! of all of its (transitive) predecessors, overlaid in the order in which they are declared.
+
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use std::collections::{HashMap, BTreeSet};
+use nix::unistd::{fork, ForkResult, Pid};
+use nix::sys::wait::{waitpid, WaitStatus, WaitPidFlag};
+use colored::*;
+use ymlctx::context::{Context, CtxObj};
+use crate::builtins::{TransientContext, ExitCode};
+use crate::{Closure, Failure, Halt};
+use crate::template;
+use crate::observer;
+use crate::incremental::{self, Freshness, Stamp};
+use crate::journal::{Journal, Outcome as JournaledOutcome};
+
+/// The outcome of a step that has run in a child process
+#[derive(Serialize, Deserialize)]
+enum Outcome {
+    Stateful(Context),
+    Stateless,
+    Diverging(ExitCode, Option<Failure>)
+}
+
+impl From<TransientContext> for Outcome {
+    fn from(x: TransientContext) -> Self {
+        match x {
+            TransientContext::Stateful(ctx) => Outcome::Stateful(ctx),
+            TransientContext::Stateless(_) => Outcome::Stateless,
+            TransientContext::Diverging(exit_code) => Outcome::Diverging(exit_code, crate::take_failure())
+        }
+    }
+}
+
+impl From<Outcome> for TransientContext {
+    fn from(x: Outcome) -> Self {
+        match x {
+            Outcome::Stateful(ctx) => TransientContext::Stateful(ctx),
+            Outcome::Stateless => TransientContext::Stateless(Context::new()),
+            Outcome::Diverging(exit_code, failure) => {
+                crate::set_failure(failure);
+                TransientContext::Diverging(exit_code)
+            }
+        }
+    }
+}
+
+/// Allocate a directory to collect the outcomes of child processes
+pub(crate) fn outcome_dir(purpose: &str) -> Result<PathBuf, ExitCode> {
+    let dir = std::env::temp_dir().join(format!("playbook-{}-{}", purpose, std::process::id()));
+    match std::fs::create_dir_all(&dir) {
+        Ok(()) => Ok(dir),
+        Err(e) => {
+            Err(fail!(ExitCode::ErrSys, "IO Error (while allocating {:?}): {}", dir, e))
+        }
+    }
+}
+
+/// Run a step in a child process, which writes its outcome to a file before exiting.
+pub(crate) fn spawn(ctx_step: Context, ctx_step_raw: &Context, closure: Closure, deadline: Option<Instant>, outcome: &Path) -> Result<Pid, ExitCode> {
+    match fork() {
+        Ok(ForkResult::Child) => {
+            let contents = serde_json::to_string(&Outcome::from(crate::run_step(ctx_step, ctx_step_raw, closure, deadline)));
+            let ret = match contents {
+                Ok(contents) => std::fs::write(outcome, contents).is_ok(),
+                Err(_) => false
+            };
+            unsafe { libc::_exit(if ret { 0 } else { ExitCode::ErrSys.into() }); }
+        },
+        Ok(ForkResult::Parent { child, .. }) => Ok(child),
+        Err(e) => {
+            Err(fail!(ExitCode::ErrSys, "Failed to fork a new process: {}", e))
+        }
+    }
+}
+
+/// Wait for any one of the given child processes to exit
+fn wait_any(children: &[Pid]) -> nix::Result<WaitStatus> {
+    loop {
+        for &child in children.iter() {
+            match waitpid(child, Some(WaitPidFlag::WNOHANG))? {
+                WaitStatus::StillAlive => {},
+                status => { return Ok(status); }
+            }
+        }
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    }
+}
+
+/// Collect the outcome of a child process that has exited
+pub(crate) fn collect(status: WaitStatus, outcome: &Path) -> TransientContext {
+    match status {
+        WaitStatus::Exited(_, 0) => {
+            let ret = std::fs::read_to_string(outcome).ok().and_then(|contents| serde_json::from_str::<Outcome>(&contents).ok());
+            let _ = std::fs::remove_file(outcome);
+            match ret {
+                Some(ret) => ret.into(),
+                None => {
+                    TransientContext::Diverging(fail!(ExitCode::ErrSys, "Failed to collect the outcome of a step from {:?}.", outcome))
+                }
+            }
+        },
+        WaitStatus::Exited(_, exit_code) => {
+            TransientContext::Diverging(fail!(ExitCode::ErrSys, "A step process has exited abnormally ({}).", exit_code))
+        },
+        WaitStatus::Signaled(_, sig, _core_dump) => {
+            TransientContext::Diverging(fail!(ExitCode::ErrTask, "A step process has received a signal ({:?}).", sig))
+        },
+        _ => unreachable!()
+    }
+}
+
+/// Specification of a loop over items
+pub(crate) struct Foreach {
+    pub(crate) items: Vec<CtxObj>,
+    loop_var: String,
+    concurrency: usize,
+    register: Option<String>
+}
+
+/// Read the loop specification of a step, if there is one.
+///
+/// **Example(s)**
+/// ```yaml
+/// action: preprocess
+/// foreach: [cifar10, imagenet] # or `with_items`
+/// loop_var: dataset            # defaults to `item`
+/// concurrency: 2               # defaults to 1, i.e. sequential iterations
+/// register: preprocessed       # collect the resulting contexts of all iterations as a list
+/// ```
+pub(crate) fn foreach(ctx_step: &Context) -> Result<Option<Foreach>, ExitCode> {
+    let items = match ctx_step.get("foreach").or_else(|| ctx_step.get("with_items")) {
+        Some(CtxObj::Array(items)) => items.to_owned(),
+        // * e.g. `foreach: "{{ datasets }}"`
+        Some(CtxObj::Str(s)) if template::is_template(s) => match template::render_str(s, ctx_step) {
+            Ok(CtxObj::Array(items)) => items,
+            Ok(_) => {
+                return Err(fail!(ExitCode::ErrYML, "Syntax Error: Key `foreach` must be a list."));
+            },
+            Err(e) => {
+                return Err(fail!(ExitCode::ErrYML, "Syntax Error: {}", e));
+            }
+        },
+        Some(_) => {
+            return Err(fail!(ExitCode::ErrYML, "Syntax Error: Key `foreach` must be a list."));
+        },
+        None => { return Ok(None); }
+    };
+    let loop_var = match ctx_step.get("loop_var") {
+        Some(CtxObj::Str(loop_var)) => loop_var.to_owned(),
+        Some(_) => {
+            return Err(fail!(ExitCode::ErrYML, "Syntax Error: Key `loop_var` must be a string."));
+        },
+        None => String::from("item")
+    };
+    let concurrency = match ctx_step.get("concurrency") {
+        Some(CtxObj::Int(n)) if *n > 0 => *n as usize,
+        Some(_) => {
+            return Err(fail!(ExitCode::ErrYML, "Syntax Error: Key `concurrency` must be a positive integer."));
+        },
+        None => 1
+    };
+    let register = match ctx_step.get("register") {
+        Some(CtxObj::Str(register)) => Some(register.to_owned()),
+        Some(_) => {
+            return Err(fail!(ExitCode::ErrYML, "Syntax Error: Key `register` must be a string."));
+        },
+        None => None
+    };
+    Ok(Some(Foreach { items, loop_var, concurrency, register }))
+}
+
+/// Run a step once per item, in contrast to `sys_fork` the playbook carries on once afterwards.
+pub(crate) fn run_foreach(ctx_step: Context, ctx_step_raw: &Context, closure: Closure, deadline: Option<Instant>, spec: Foreach) -> TransientContext {
+    let iterations: Vec<(Context, Closure)> = spec.items.iter().enumerate().map(|(k, item)| {
+        let closure_k = Closure {
+            ctx_states: closure.ctx_states.set(&spec.loop_var, item.to_owned()),
+            iteration: Some(k),
+            ..closure.clone()
+        };
+        (ctx_step.set(&spec.loop_var, item.to_owned()), closure_k)
+    }).collect();
+    let mut results: Vec<Option<TransientContext>> = iterations.iter().map(|_| None).collect();
+    if spec.concurrency == 1 {
+        for (k, (ctx_step_k, closure_k)) in iterations.into_iter().enumerate() {
+            let ret = crate::run_step(ctx_step_k, ctx_step_raw, closure_k, deadline);
+            let diverging = matches!(ret, TransientContext::Diverging(_));
+            results[k] = Some(ret);
+            if diverging { break; }
+        }
+    }
+    else {
+        let dir = match outcome_dir("foreach") {
+            Ok(dir) => dir,
+            Err(e) => { return TransientContext::Diverging(e); }
+        };
+        let outcome = |k: usize| dir.join(format!("{}.json", k));
+        let mut running: Vec<(Pid, usize)> = Vec::new();
+        let mut halt = false;
+        let mut pending = iterations.into_iter().enumerate();
+        loop {
+            while !halt && running.len() < spec.concurrency {
+                match pending.next() {
+                    Some((k, (ctx_step_k, closure_k))) => match spawn(ctx_step_k, ctx_step_raw, closure_k, deadline, &outcome(k)) {
+                        Ok(child) => { running.push((child, k)); },
+                        Err(e) => {
+                            results[k] = Some(TransientContext::Diverging(e));
+                            halt = true;
+                        }
+                    },
+                    None => { break; }
+                }
+            }
+            if running.is_empty() { break; }
+            let children: Vec<Pid> = running.iter().map(|&(child, _)| child).collect();
+            let status = match wait_any(&children) {
+                Ok(status) => status,
+                Err(e) => {
+                    error!("Failed to keep track of the child process: {}", e);
+                    let _ = std::fs::remove_dir_all(&dir);
+                    return TransientContext::Diverging(ExitCode::ErrSys);
+                }
+            };
+            let pid = match status {
+                WaitStatus::Exited(pid, _) | WaitStatus::Signaled(pid, _, _) => pid,
+                _ => { continue; }
+            };
+            if let Some(pos) = running.iter().position(|&(child, _)| child == pid) {
+                let (_, k) = running.remove(pos);
+                let ret = collect(status, &outcome(k));
+                if let TransientContext::Diverging(_) = ret { halt = true; }
+                results[k] = Some(ret);
+            }
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+    let mut ctx_pipe = Context::new();
+    let mut registered = Vec::new();
+    for ret in results.into_iter() {
+        match ret {
+            Some(TransientContext::Diverging(exit_code)) => { return TransientContext::Diverging(exit_code); },
+            Some(TransientContext::Stateful(ctx_ret)) => {
+                ctx_pipe = ctx_pipe.overlay(&ctx_ret);
+                registered.push(CtxObj::Context(ctx_ret));
+            },
+            Some(TransientContext::Stateless(_)) => { registered.push(CtxObj::None); },
+            None => {}
+        }
+    }
+    match spec.register {
+        Some(register) => TransientContext::Stateful(Context::new().set(&register, CtxObj::Array(registered))),
+        None => TransientContext::Stateful(ctx_pipe)
+    }
+}
+
+/// Read the names of the steps a step needs
+fn needs(ctx_step: &Context) -> Result<Option<Vec<String>>, String> {
+    match ctx_step.get("needs") {
+        None => Ok(None),
+        Some(CtxObj::Str(name)) => Ok(Some(vec![name.to_owned()])),
+        Some(CtxObj::Array(names)) => names.iter().map(|name| match name {
+            CtxObj::Str(name) => Ok(name.to_owned()),
+            _ => Err(String::from("Key `needs` must be a list of step names."))
+        }).collect::<Result<Vec<String>, String>>().map(Some),
+        Some(_) => Err(String::from("Key `needs` must be a list of step names."))
+    }
+}
+
+/// Build the dependency graph of steps, i.e. the direct predecessors of each step.
+///
+/// Returns `None` if no step declares `needs`, in which case steps simply run in order.
+pub fn dependencies(steps: &[Context]) -> Result<Option<Vec<Vec<usize>>>, String> {
+    let declared: Vec<Option<Vec<String>>> = steps.iter().map(needs).collect::<Result<_, String>>()?;
+    if declared.iter().all(|d| d.is_none()) {
+        return Ok(None);
+    }
+    let mut names = HashMap::new();
+    for (i, ctx_step) in steps.iter().enumerate() {
+        if let Some(CtxObj::Str(name)) = ctx_step.get("name") {
+            if names.insert(name.to_owned(), i).is_some() {
+                return Err(format!("Step name `{}` is ambiguous.", name));
+            }
+        }
+        if let Some(CtxObj::Str(action)) = ctx_step.get("action") {
+            if action == "sys_fork" {
+                return Err(String::from("Action `sys_fork` cannot be used along with `needs`."));
+            }
+        }
+    }
+    let mut deps = Vec::new();
+    for (i, needed) in declared.into_iter().enumerate() {
+        deps.push(match needed {
+            Some(needed) => needed.iter().map(|name| match names.get(name) {
+                Some(&j) => Ok(j),
+                None => Err(format!("Step {} needs an undefined step `{}`.", i+1, name))
+            }).collect::<Result<Vec<usize>, String>>()?,
+            None => if i > 0 { vec![i-1] } else { Vec::new() }
+        });
+    }
+    if let Some(cycle) = find_cycle(&deps) {
+        let path: Vec<String> = cycle.iter().map(|&i| match steps[i].get("name") {
+            Some(CtxObj::Str(name)) => name.to_owned(),
+            _ => format!("Step {}", i+1)
+        }).collect();
+        return Err(format!("Steps have cyclic dependencies: {}", path.join(" -> ")));
+    }
+    Ok(Some(deps))
+}
+
+fn find_cycle(deps: &[Vec<usize>]) -> Option<Vec<usize>> {
+    // 0 = unvisited, 1 = on the stack, 2 = done
+    fn visit(i: usize, deps: &[Vec<usize>], marks: &mut Vec<u8>, stack: &mut Vec<usize>) -> Option<Vec<usize>> {
+        marks[i] = 1;
+        stack.push(i);
+        for &j in deps[i].iter() {
+            if marks[j] == 1 {
+                let start = stack.iter().position(|&k| k == j).unwrap();
+                let mut cycle = stack[start..].to_vec();
+                cycle.push(j);
+                return Some(cycle);
+            }
+            if marks[j] == 0 {
+                if let Some(cycle) = visit(j, deps, marks, stack) {
+                    return Some(cycle);
+                }
+            }
+        }
+        stack.pop();
+        marks[i] = 2;
+        None
+    }
+    let mut marks = vec![0; deps.len()];
+    for i in 0..deps.len() {
+        if marks[i] == 0 {
+            if let Some(cycle) = visit(i, deps, &mut marks, &mut Vec::new()) {
+                return Some(cycle);
+            }
+        }
+    }
+    None
+}
+
+/// All (transitive) predecessors of each step
+fn ancestors(deps: &[Vec<usize>]) -> Vec<BTreeSet<usize>> {
+    fn collect(i: usize, deps: &[Vec<usize>], acc: &mut BTreeSet<usize>) {
+        for &j in deps[i].iter() {
+            if acc.insert(j) {
+                collect(j, deps, acc);
+            }
+        }
+    }
+    (0..deps.len()).map(|i| {
+        let mut acc = BTreeSet::new();
+        collect(i, deps, &mut acc);
+        acc
+    }).collect()
+}
+
+enum Node {
+    Pending,
+    Running(Pid),
+    Done(Option<Context>)
+}
+
+/// Run the steps as a dependency graph, and tell the stateful context of the steps that have run.
+/// Steps that have completed according to the journal are not run again, nor are those that are not selected.
+pub(crate) fn run_graph(steps: &[Context], deps: &[Vec<usize>], selected: &[bool], ctx_global: &Context, ctx_args: &Context, journal: &mut Journal, deadline: Option<Instant>) -> (Context, Option<Halt>) {
+    let dir = match outcome_dir("graph") {
+        Ok(dir) => dir,
+        Err(exit_code) => { return (Context::new(), Some(Halt { exit_code, step: None })); }
+    };
+    let outcome = |i: usize| dir.join(format!("{}.json", i));
+    let ancestors = ancestors(deps);
+    let mut nodes: Vec<Node> = (0..steps.len()).map(|i| match journal.completed(i) {
+        Some(JournaledOutcome::Stateful(ctx_pipe)) => Node::Done(Some(ctx_pipe.clone())),
+        Some(_) => Node::Done(None),
+        None => Node::Pending
+    }).collect();
+    let mut stamps: Vec<Option<Stamp>> = (0..steps.len()).map(|_| None).collect();
+    let mut halt: Option<Halt> = None;
+    loop {
+        let mut progress = halt.is_none();
+        while progress {
+            progress = false;
+            for i in 0..steps.len() {
+                if !matches!(nodes[i], Node::Pending) { continue; }
+                if !deps[i].iter().all(|&j| matches!(nodes[j], Node::Done(_))) { continue; }
+                let mut ctx_states_i = Context::new();
+                for &j in ancestors[i].iter() {
+                    if let Node::Done(Some(ref ctx_pipe)) = nodes[j] {
+                        ctx_states_i = ctx_states_i.overlay(ctx_pipe);
+                    }
+                }
+                let closure = Closure { container: 0, step_ptr: i, ctx_states: ctx_states_i, iteration: None };
+                let ctx_step = crate::deduce_context(&steps[i], ctx_global, ctx_args, &closure);
+                if !selected[i] {
+                    crate::show_step_header(&ctx_step, format!("Step {}", i+1).dimmed(), Some("not selected".dimmed()));
+                    nodes[i] = Node::Done(None);
+                    progress = true;
+                    continue;
+                }
+                match crate::step_condition(&ctx_step) {
+                    Ok(true) => {
+                        match incremental::freshness(&ctx_step, &steps[i], ctx_args, i) {
+                            Ok(Freshness::Untracked) => {},
+                            Ok(Freshness::Stale(stamp)) => { stamps[i] = Some(stamp); },
+                            Ok(Freshness::UpToDate(outcome)) => {
+                                crate::show_step_header(&ctx_step, format!("Step {}", i+1).dimmed(), Some("up to date".dimmed()));
+                                nodes[i] = match outcome {
+                                    JournaledOutcome::Stateful(ref ctx_pipe) => Node::Done(Some(ctx_pipe.clone())),
+                                    _ => Node::Done(None)
+                                };
+                                let ctx_states = merged(&nodes);
+                                if let JournaledOutcome::Stateful(_) = outcome {
+                                    observer::notify(|o| o.on_context_change(&ctx_states));
+                                }
+                                journal.record(i, outcome.clone(), &ctx_states);
+                                observer::notify(|o| o.on_step_end(i+1, &outcome));
+                                progress = true;
+                                continue;
+                            },
+                            Err(exit_code) => { halt = Some(Halt { exit_code, step: Some(i) }); break; }
+                        }
+                        let ctx_step = match crate::step_deadline(ctx_step, deadline) {
+                            Ok(ctx_step) => ctx_step,
+                            Err(exit_code) => { halt = Some(Halt { exit_code, step: Some(i) }); break; }
+                        };
+                        journal.start(i);
+                        observer::notify(|o| o.on_step_start(i+1, &ctx_step));
+                        match spawn(ctx_step, &steps[i], closure, deadline, &outcome(i)) {
+                            Ok(child) => { nodes[i] = Node::Running(child); },
+                            Err(exit_code) => { halt = Some(Halt { exit_code, step: Some(i) }); break; }
+                        }
+                    },
+                    Ok(false) => {
+                        crate::show_step_header(&ctx_step, format!("Step {}", i+1).dimmed(), Some("skipped".dimmed()));
+                        journal.record(i, JournaledOutcome::Skipped, &merged(&nodes));
+                        observer::notify(|o| o.on_step_end(i+1, &JournaledOutcome::Skipped));
+                        nodes[i] = Node::Done(None);
+                        progress = true;
+                    },
+                    Err(exit_code) => { halt = Some(Halt { exit_code, step: Some(i) }); break; }
+                }
+            }
+        }
+        if !nodes.iter().any(|node| matches!(node, Node::Running(_))) {
+            break;
+        }
+        let children: Vec<Pid> = nodes.iter().filter_map(|node| if let Node::Running(child) = node { Some(*child) } else { None }).collect();
+        let status = match wait_any(&children) {
+            Ok(status) => status,
+            Err(e) => {
+                error!("Failed to keep track of the child process: {}", e);
+                halt = Some(Halt { exit_code: ExitCode::ErrSys, step: None });
+                break;
+            }
+        };
+        let pid = match status {
+            WaitStatus::Exited(pid, _) | WaitStatus::Signaled(pid, _, _) => pid,
+            _ => { continue; }
+        };
+        if let Some(i) = nodes.iter().position(|node| if let Node::Running(child) = node { *child == pid } else { false }) {
+            let ret = collect(status, &outcome(i));
+            if let Some(stamp) = stamps[i].take() {
+                stamp.record(&ret);
+            }
+            let journaled = JournaledOutcome::from(&ret);
+            let stateful = matches!(ret, TransientContext::Stateful(_));
+            nodes[i] = match ret {
+                TransientContext::Stateful(ctx_pipe) => Node::Done(Some(ctx_pipe)),
+                TransientContext::Stateless(_) => Node::Done(None),
+                TransientContext::Diverging(exit_code) => {
+                    if halt.is_none() { halt = Some(Halt { exit_code, step: Some(i) }); }
+                    else { crate::take_failure(); }
+                    Node::Done(None)
+                }
+            };
+            let ctx_states = merged(&nodes);
+            if stateful {
+                observer::notify(|o| o.on_context_change(&ctx_states));
+            }
+            journal.record(i, journaled.clone(), &ctx_states);
+            observer::notify(|o| o.on_step_end(i+1, &journaled));
+        }
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+    (merged(&nodes), halt)
+}
+
+/// The stateful contexts of the steps that are done, overlaid in the order in which they are declared
+fn merged(nodes: &[Node]) -> Context {
+    let mut ctx_states = Context::new();
+    for node in nodes.iter() {
+        if let Node::Done(Some(ref ctx_pipe)) = node {
+            ctx_states = ctx_states.overlay(ctx_pipe);
+        }
+    }
+    ctx_states
+}
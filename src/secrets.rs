@@ -0,0 +1,141 @@
+//! Secret values, which are masked wherever contexts and commands are shown
+//!
+//! **Example(s)**
+//! ```yaml
+//! secrets:
+//!   api_token:
+//!     env: ACME_TOKEN
+//!   db_password:
+//!     file: secrets/db_password.txt # relative to the playbook
+//! steps:
+//! - action: upload
+//!   token: "{{ api_token }}"
+//!   docker:
+//!     image: acme/uploader:latest
+//! ```
+//!
+//! Secrets become global variables of the playbook. Their values are masked in the banners of the actions,
+//! context dumps, logs and `docker run` commands, and are handed to containers through their environment.
+
+use std::path::Path;
+use std::cell::RefCell;
+use ymlctx::context::{Context, CtxObj};
+use crate::builtins::ExitCode;
+
+const MASK: &str = "******";
+/// Prefix of the environment variables through which secrets are handed to containers
+pub const ENV_PREFIX: &str = "PLAYBOOK_SECRET_";
+
+thread_local! {
+    static SECRETS: RefCell<Vec<(String, String)>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Name of the environment variable through which a secret is handed to containers
+fn env_name(key: &str) -> String {
+    format!("{}{}", ENV_PREFIX, key.to_uppercase().replace(|c: char| !c.is_ascii_alphanumeric(), "_"))
+}
+
+/// Read the `secrets` of a playbook into its global context, and remember their values to be masked
+pub(crate) fn resolve(ctx_global: Context, playbook: &str) -> Result<Context, ExitCode> {
+    let ctx_secrets = match ctx_global.get("secrets") {
+        None => { return Ok(ctx_global); },
+        Some(CtxObj::Context(ctx_secrets)) => ctx_secrets.clone(),
+        Some(_) => {
+            return Err(fail!(ExitCode::ErrYML, "Syntax Error: Key `secrets` must be a mapping."));
+        }
+    };
+    let playbook_dir = Path::new(playbook).parent().unwrap_or(Path::new("."));
+    let mut ctx_global = ctx_global.hide("secrets");
+    for key in ctx_secrets.keys() {
+        // * Inside of a container, secrets are found in the environment that has been handed down.
+        let value = match std::env::var(env_name(key)) {
+            Ok(value) => value,
+            Err(_) => match ctx_secrets.get(key) {
+                Some(CtxObj::Context(ctx_source)) => match (ctx_source.get("env"), ctx_source.get("file")) {
+                    (Some(CtxObj::Str(var)), None) => match std::env::var(var) {
+                        Ok(value) => value,
+                        Err(_) => {
+                            return Err(fail!(ExitCode::ErrApp, "Cannot read the secret `{}`: environment variable {} is not set.", key, var));
+                        }
+                    },
+                    (None, Some(CtxObj::Str(fname))) => match std::fs::read_to_string(playbook_dir.join(fname)) {
+                        Ok(contents) => contents.trim_end_matches(['\r', '\n']).to_owned(),
+                        Err(e) => {
+                            return Err(fail!(ExitCode::ErrSys, "IO Error (while reading the secret `{}` from {}): {}", key, fname, e));
+                        }
+                    },
+                    _ => {
+                        return Err(fail!(ExitCode::ErrYML, "Syntax Error: Secret `{}` must be read from either an `env` variable or a `file`.", key));
+                    }
+                },
+                _ => {
+                    return Err(fail!(ExitCode::ErrYML, "Syntax Error: Secret `{}` must be a mapping.", key));
+                }
+            }
+        };
+        add(key, &value);
+        ctx_global = ctx_global.set(key, CtxObj::Str(value));
+    }
+    Ok(ctx_global)
+}
+
+/// Remember a secret value to be masked
+fn add(key: &str, value: &str) {
+    if value.is_empty() { return; }
+    SECRETS.with(|secrets| {
+        let mut secrets = secrets.borrow_mut();
+        secrets.retain(|(k, _)| k != key);
+        secrets.push((key.to_owned(), value.to_owned()));
+        // * Longer values are masked first, in case one contains another.
+        secrets.sort_by_key(|(_, value)| std::cmp::Reverse(value.len()));
+    });
+}
+
+/// Forget the secrets, e.g. as a new run begins
+pub(crate) fn clear() {
+    SECRETS.with(|secrets| secrets.borrow_mut().clear());
+}
+
+/// Mask the secret values in a piece of text
+pub fn redact(text: &str) -> String {
+    SECRETS.with(|secrets| {
+        let mut ret = text.to_owned();
+        for (_, value) in secrets.borrow().iter() {
+            if ret.contains(value.as_str()) {
+                ret = ret.replace(value.as_str(), MASK);
+            }
+        }
+        ret
+    })
+}
+
+/// Whether a value carries any of the secret values, e.g. before it is written to a file
+pub(crate) fn reveals(val: &CtxObj) -> bool {
+    match val {
+        CtxObj::Str(s) => SECRETS.with(|secrets| secrets.borrow().iter().any(|(_, value)| s.contains(value.as_str()))),
+        CtxObj::Array(items) => items.iter().any(reveals),
+        CtxObj::Context(ctx) => ctx.keys().any(|key| reveals(ctx.get(key).unwrap())),
+        _ => false
+    }
+}
+
+/// Environment variables to be handed to a container, given as `docker run -e NAME` so that
+/// their values stay out of the command line
+pub(crate) fn docker_env() -> Vec<(String, String)> {
+    SECRETS.with(|secrets| secrets.borrow().iter().map(|(key, value)| (env_name(key), value.to_owned())).collect())
+}
+
+#[test]
+fn test_secrets() {
+    std::env::set_var("PLAYBOOK_TEST_TOKEN", "s3cr3t-t0k3n");
+    let ctx = Context::from("secrets:\n  token:\n    env: PLAYBOOK_TEST_TOKEN\nurl: https://example.com");
+    let ctx = resolve(ctx, "some.yml").unwrap();
+    assert_eq!(ctx.get("token"), Some(&CtxObj::Str(String::from("s3cr3t-t0k3n"))));
+    assert!(ctx.get("secrets").is_none());
+    assert_eq!(redact(&format!("{}", ctx.set("auth", CtxObj::Str(String::from("Bearer s3cr3t-t0k3n"))))).matches(MASK).count(), 2);
+    assert_eq!(docker_env(), vec![(String::from("PLAYBOOK_SECRET_TOKEN"), String::from("s3cr3t-t0k3n"))]);
+    assert!(resolve(Context::from("secrets:\n  token:\n    env: PLAYBOOK_TEST_MISSING"), "some.yml").is_err());
+    assert!(resolve(Context::from("secrets:\n  token: plain"), "some.yml").is_err());
+    clear();
+    assert_eq!(redact("s3cr3t-t0k3n"), "s3cr3t-t0k3n");
+}
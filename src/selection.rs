@@ -0,0 +1,94 @@
+//! Selection of a subset of steps from the command line
+//!
+//! **Example(s)**
+//! ```sh
+//! playbook --only eval some.yml
+//! playbook --from 3 --until deploy some.yml
+//! playbook --tags eval,report --keep-stateful some.yml
+//! ```
+//!
+//! Steps are referred to by name or by their 1-based index, and the filters that are given
+//! must all agree for a step to be selected. With `--keep-stateful`, steps that are known to contribute
+//! states to the workflow, i.e. `sys_vars` and loops that `register` their results, still run even if they
+//! are not selected. Whether an action returns a dict cannot be told before it runs, so actions that pass
+//! on states to later steps are not kept; select them along with the others, e.g. by `--tags`.
+
+use ymlctx::context::{Context, CtxObj};
+
+/// Tell whether each of the steps is selected by the arguments
+pub fn select(steps: &[Context], ctx_args: &Context) -> Result<Vec<bool>, String> {
+    let strings = |key: &str| -> Option<Vec<String>> {
+        match ctx_args.get(key) {
+            Some(CtxObj::Array(items)) => Some(items.iter().filter_map(|item| if let CtxObj::Str(s) = item { Some(s.to_owned()) } else { None }).collect()),
+            Some(CtxObj::Str(s)) => Some(vec![s.to_owned()]),
+            _ => None
+        }
+    };
+    let only = match strings("only-steps") {
+        Some(refs) => Some(refs.iter().map(|r| find(steps, r)).collect::<Result<Vec<usize>, String>>()?),
+        None => None
+    };
+    let from = match ctx_args.get("from-step") {
+        Some(CtxObj::Str(r)) => find(steps, r)?,
+        _ => 0
+    };
+    let until = match ctx_args.get("until-step") {
+        Some(CtxObj::Str(r)) => find(steps, r)?,
+        _ => steps.len().saturating_sub(1)
+    };
+    if from > until {
+        return Err(format!("Step {} comes after step {}.", from+1, until+1));
+    }
+    let only_tags = strings("only-tags");
+    let keep_stateful = matches!(ctx_args.get("keep-stateful"), Some(CtxObj::Bool(true)));
+    Ok(steps.iter().enumerate().map(|(i, ctx_step)| {
+        let selected = i >= from && i <= until
+            && only.as_ref().is_none_or(|only| only.contains(&i))
+            && only_tags.as_ref().is_none_or(|only_tags| tags(ctx_step).iter().any(|tag| only_tags.contains(tag)));
+        selected || (keep_stateful && contributes_state(ctx_step))
+    }).collect())
+}
+
+/// Find a step by name or by its 1-based index
+fn find(steps: &[Context], r: &str) -> Result<usize, String> {
+    if let Some(i) = steps.iter().position(|ctx_step| matches!(ctx_step.get("name"), Some(CtxObj::Str(name)) if name == r)) {
+        return Ok(i);
+    }
+    match r.parse::<usize>() {
+        Ok(i) if i >= 1 && i <= steps.len() => Ok(i-1),
+        _ => Err(format!("Cannot find the step `{}`.", r))
+    }
+}
+
+fn tags(ctx_step: &Context) -> Vec<String> {
+    match ctx_step.get("tags") {
+        Some(CtxObj::Array(tags)) => tags.iter().filter_map(|tag| if let CtxObj::Str(tag) = tag { Some(tag.to_owned()) } else { None }).collect(),
+        Some(CtxObj::Str(tag)) => vec![tag.to_owned()],
+        _ => Vec::new()
+    }
+}
+
+/// Whether a step is known to introduce states to the workflow
+fn contributes_state(ctx_step: &Context) -> bool {
+    matches!(ctx_step.get("action"), Some(CtxObj::Str(action)) if action == "sys_vars")
+        || ctx_step.get("register").is_some()
+}
+
+#[test]
+fn test_select() {
+    let steps: Vec<Context> = vec![
+        Context::from("name: vars\naction: sys_vars"),
+        Context::from("name: train\naction: train\ntags: [gpu]"),
+        Context::from("name: eval\naction: eval\ntags: [gpu, report]"),
+        Context::from("name: report\naction: report\ntags: report"),
+        Context::from("name: prep\naction: prep\nforeach: [a, b]\nregister: prepared")
+    ];
+    let args = |yml: &str| Context::from(yml);
+    assert_eq!(select(&steps, &Context::new()), Ok(vec![true, true, true, true, true]));
+    assert_eq!(select(&steps, &args("only-steps: [eval]")), Ok(vec![false, false, true, false, false]));
+    assert_eq!(select(&steps, &args("only-steps: [eval]\nkeep-stateful: true")), Ok(vec![true, false, true, false, true]));
+    assert_eq!(select(&steps, &args("from-step: '2'\nuntil-step: eval")), Ok(vec![false, true, true, false, false]));
+    assert_eq!(select(&steps, &args("only-tags: [report]")), Ok(vec![false, false, true, true, false]));
+    assert!(select(&steps, &args("only-steps: [deploy]")).is_err());
+    assert!(select(&steps, &args("from-step: report\nuntil-step: train")).is_err());
+}
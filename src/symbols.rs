@@ -0,0 +1,275 @@
+//! Symbols declared by markers in the whitelisted sources, regardless of their languages
+//!
+//! **Example(s)**
+//! ```python
+//! #[playbook(train, alias=fit)]
+//! def train(ctx):
+//!     ...
+//! ```
+//! ```sh
+//! # [playbook(prep, clean)]
+//! prep() { ... }
+//! clean() { ... }
+//! ```
+//! ```r
+//! # [playbook(report)]
+//! report <- function(ctx) { ... }
+//! ```
+//! ```lua
+//! -- [playbook(report)]
+//! function report(ctx) ... end
+//! ```
+//!
+//! A marker may be indented, and may declare several functions, each of which may be aliased.
+//! An action is invoked by the name of its function, and found by either the name or an alias.
+//! Each function that is declared is expected to be defined after the marker.
+//!
+//! Sources are scanned once per run. The actions available to a playbook are listed by
+//! ```sh
+//! playbook --actions some.yml
+//! ```
+
+use std::path::{Path, PathBuf};
+use std::collections::HashMap;
+use std::cell::RefCell;
+use std::rc::Rc;
+use regex::Regex;
+use ymlctx::context::{Context, CtxObj};
+use crate::builtins::{self, ExitCode};
+use crate::error::Error;
+
+/// An action that a source provides
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol {
+    /// Name of the action, which is either the name of the function or an alias of it
+    pub action: String,
+    pub function: String,
+    /// 1-based line number of the marker
+    pub line: usize
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbols {
+    pub symbols: Vec<Symbol>,
+    /// Markers that are malformed or not followed by a definition, by their 1-based line numbers
+    pub problems: Vec<(usize, String)>
+}
+
+impl Symbols {
+    /// The function of an action
+    pub fn function(&self, action: &str) -> Option<&str> {
+        self.symbols.iter().find(|symbol| symbol.action == action).map(|symbol| symbol.function.as_str())
+    }
+
+    pub fn actions(&self) -> impl Iterator<Item = &str> {
+        self.symbols.iter().map(|symbol| symbol.action.as_str())
+    }
+}
+
+/// Scan a source for markers
+pub fn parse(contents: &str) -> Symbols {
+    let re_marker = Regex::new(r"^\s*(?:#|//|--)\s*\[playbook\(([^)]*)\)\]").unwrap();
+    let re_name = Regex::new(r"^\w+$").unwrap();
+    let definitions = definitions(contents);
+    let mut ret = Symbols { symbols: Vec::new(), problems: Vec::new() };
+    for (i, line) in contents.lines().enumerate() {
+        let caps = match re_marker.captures(line) {
+            Some(caps) => caps,
+            None => { continue; }
+        };
+        let marker = caps.get(0).unwrap().as_str().trim();
+        let mut function: Option<String> = None;
+        for item in caps.get(1).unwrap().as_str().split(',').map(|item| item.trim()) {
+            let (name, alias) = match item.find('=') {
+                Some(pos) if item[..pos].trim() == "alias" => (item[pos+1..].trim(), true),
+                Some(_) => {
+                    ret.problems.push((i+1, format!("`{}` has an unknown option `{}`.", marker, item)));
+                    continue;
+                },
+                None => (item, false)
+            };
+            if !re_name.is_match(name) {
+                ret.problems.push((i+1, format!("`{}` has an invalid name `{}`.", marker, name)));
+                continue;
+            }
+            if alias {
+                match function {
+                    Some(ref function) => ret.symbols.push(Symbol { action: name.to_owned(), function: function.to_owned(), line: i+1 }),
+                    None => ret.problems.push((i+1, format!("`{}` has an alias `{}` of nothing.", marker, name)))
+                }
+                continue;
+            }
+            let defined = definitions.get(name).is_some_and(|lines| lines.iter().any(|&j| j > i));
+            if !defined {
+                ret.problems.push((i+1, format!("`{}` is not followed by a definition of `{}`.", marker, name)));
+            }
+            ret.symbols.push(Symbol { action: name.to_owned(), function: name.to_owned(), line: i+1 });
+            function = Some(name.to_owned());
+        }
+    }
+    ret
+}
+
+/// Read a source for markers
+pub fn load<P: AsRef<Path>>(src: P) -> Result<Symbols, std::io::Error> {
+    Ok(parse(&std::fs::read_to_string(src)?))
+}
+
+thread_local! {
+    static INDEX: RefCell<HashMap<PathBuf, Rc<Symbols>>> = RefCell::new(HashMap::new());
+}
+
+/// Read a source for markers, unless it has been read during the current run
+pub fn cached<P: AsRef<Path>>(src: P) -> Result<Rc<Symbols>, std::io::Error> {
+    let src = src.as_ref();
+    if let Some(symbols) = INDEX.with(|index| index.borrow().get(src).cloned()) {
+        return Ok(symbols);
+    }
+    let symbols = Rc::new(load(src)?);
+    INDEX.with(|index| index.borrow_mut().insert(src.to_path_buf(), symbols.clone()));
+    Ok(symbols)
+}
+
+/// Forget the sources that have been read, e.g. as a new run begins
+pub(crate) fn clear_cache() {
+    INDEX.with(|index| index.borrow_mut().clear());
+}
+
+/// An action that is available to a playbook
+#[derive(Debug, Clone, PartialEq)]
+pub struct Action {
+    pub name: String,
+    /// The function of an alias
+    pub function: Option<String>,
+    /// The source and the 1-based line number of the marker, or nothing for a built-in action
+    pub src: Option<(String, usize)>
+}
+
+/// Sources of the whitelists that apply to a playbook, relative to the working directory.
+/// Those of the included playbooks are relocated along with their steps.
+fn sources(steps: &[Context], raw: &Context, playbook: &str) -> Vec<String> {
+    let playbook_dir = Path::new(playbook).parent().unwrap_or(Path::new("."));
+    let mut ret: Vec<String> = Vec::new();
+    let whitelists = std::iter::once(raw).chain(steps.iter()).filter_map(|ctx| ctx.list_contexts("whitelist"));
+    for whitelist in whitelists {
+        for ctx_source in whitelist {
+            if let Some(CtxObj::Str(src)) = ctx_source.get("src") {
+                let src = playbook_dir.join(src).to_string_lossy().into_owned();
+                if !ret.contains(&src) { ret.push(src); }
+            }
+        }
+    }
+    ret
+}
+
+/// Every action that is available to a playbook, those of the whitelists followed by the built-in ones
+pub fn available(raw: &Context, playbook: &str) -> Result<Vec<Action>, ExitCode> {
+    let steps = match raw.list_contexts("steps") {
+        Some(steps) => builtins::expand_includes(steps, playbook)?,
+        None => Vec::new()
+    };
+    let mut ret = Vec::new();
+    for src in sources(&steps, raw, playbook) {
+        match load(&src) {
+            Ok(symbols) => {
+                ret.extend(symbols.symbols.into_iter().map(|symbol| Action {
+                    function: if symbol.function != symbol.action { Some(symbol.function) } else { None },
+                    name: symbol.action,
+                    src: Some((src.to_owned(), symbol.line))
+                }));
+            },
+            Err(e) => { warn!("IO Error (while reading {}): {}", src, e); }
+        }
+    }
+    ret.extend(builtins::names().into_iter().map(|name| Action { name, function: None, src: None }));
+    Ok(ret)
+}
+
+/// List the actions that are available to a playbook
+pub fn actions<P: AsRef<Path>>(playbook: P) -> Result<(), Error> {
+    let playbook_str = playbook.as_ref().to_string_lossy().into_owned();
+    let (fname, _stage) = crate::split_stage(&playbook_str);
+    let raw = crate::load_yaml(playbook.as_ref())?;
+    let actions = available(&raw, fname).map_err(crate::diverged)?;
+    let width = actions.iter().map(|action| action.name.len()).max().unwrap_or(0);
+    for action in actions.iter() {
+        let src = match action.src {
+            Some((ref src, line)) => format!("{}:{}", src, line),
+            None => String::from("built-in")
+        };
+        match action.function {
+            Some(ref function) => println!("{:width$}  {} (alias of {})", action.name, src, function, width = width),
+            None => println!("{:width$}  {}", action.name, src, width = width)
+        }
+    }
+    Ok(())
+}
+
+/// The nearest name of the actions available to a step, as a suggestion for one that is not found
+pub(crate) fn suggest(action: &str, ctx_step: &Context) -> Option<String> {
+    let playbook = match ctx_step.get("playbook") {
+        Some(CtxObj::Str(playbook)) => playbook.as_str(),
+        _ => ""
+    };
+    let mut candidates = builtins::names();
+    for src in sources(&[], ctx_step, playbook) {
+        if let Ok(symbols) = cached(&src) {
+            candidates.extend(symbols.actions().map(String::from));
+        }
+    }
+    crate::lint::suggest(action, candidates.iter().map(|name| name.as_str())).map(String::from)
+}
+
+/// Functions defined in a source, along with the 0-based line numbers where they are defined
+fn definitions(contents: &str) -> HashMap<String, Vec<usize>> {
+    let patterns = [
+        // Python, Julia, Bash, JavaScript, Rust, Go, Perl, Tcl
+        r"\b(?:def|function|fn|func|sub|proc)\s+([A-Za-z_][\w.!]*)",
+        // R
+        r"^\s*([A-Za-z_][\w.]*)\s*(?:<-|=)\s*function\b",
+        // POSIX shell, where the body has to follow unlike in a call such as `main()`
+        r"^\s*([A-Za-z_]\w*)\s*\(\s*\)\s*\{",
+        // Julia, in the short form
+        r"^\s*([A-Za-z_][\w!]*)\(.*\)\s*=[^=]"
+    ];
+    let patterns: Vec<Regex> = patterns.iter().map(|pattern| Regex::new(pattern).unwrap()).collect();
+    // * The body of a POSIX shell function may as well open on the next line.
+    let re_posix_split = Regex::new(r"^\s*([A-Za-z_]\w*)\s*\(\s*\)\s*$").unwrap();
+    let lines: Vec<&str> = contents.lines().collect();
+    let mut ret: HashMap<String, Vec<usize>> = HashMap::new();
+    for (i, line) in lines.iter().enumerate() {
+        for re in patterns.iter() {
+            if let Some(caps) = re.captures(line) {
+                ret.entry(caps.get(1).unwrap().as_str().to_owned()).or_default().push(i);
+            }
+        }
+        if let Some(caps) = re_posix_split.captures(line) {
+            if lines.get(i+1).is_some_and(|next| next.trim_start().starts_with('{')) {
+                ret.entry(caps.get(1).unwrap().as_str().to_owned()).or_default().push(i);
+            }
+        }
+    }
+    ret
+}
+
+#[test]
+fn test_parse() {
+    let symbols = parse("#[playbook(say_hi)]\ndef say_hi(ctx):\n    pass\n\n    #[playbook(train, alias=fit)]\n    def train(ctx):\n        pass\n");
+    assert_eq!(symbols.function("say_hi"), Some("say_hi"));
+    assert_eq!(symbols.function("fit"), Some("train"));
+    assert!(symbols.problems.is_empty());
+    let symbols = parse("# [playbook(prep, clean)]\nprep() {\n  :\n}\nfunction clean {\n  :\n}\n");
+    assert_eq!(symbols.actions().collect::<Vec<&str>>(), vec!["prep", "clean"]);
+    assert!(symbols.problems.is_empty());
+    let symbols = parse("# [playbook(prep)]\nprep()\n{\n  :\n}\n");
+    assert!(symbols.problems.is_empty());
+    // * A call is no definition.
+    let symbols = parse("# [playbook(main)]\nmain()\nmain\n");
+    assert_eq!(symbols.problems.len(), 1);
+    let symbols = parse("-- [playbook(report)]\nreport <- function(ctx) {}\n// [playbook(plot!)]\n// [playbook(fit)]\nfit(ctx) = ctx\n");
+    assert_eq!(symbols.function("report"), Some("report"));
+    assert_eq!(symbols.function("fit"), Some("fit"));
+    assert_eq!(symbols.problems.len(), 1);
+    let symbols = parse("def train(ctx):\n    pass\n#[playbook(train, alias=)]\n#[playbook(alias=x, mode=y)]\n");
+    assert_eq!(symbols.problems.iter().map(|&(line, _)| line).collect::<Vec<usize>>(), vec![3, 3, 4, 4]);
+}
@@ -2,13 +2,21 @@ use std::ffi::{CString, OsStr};
 use std::collections::HashMap;
 use std::path::Path;
 use regex::Regex;
-use nix::unistd::{fork, execvp, ForkResult};
-use nix::sys::wait::{waitpid, WaitStatus};
+use nix::unistd::execvp;
+use nix::sys::wait::WaitStatus;
+use nix::sys::signal::Signal;
 use colored::Colorize;
 use ymlctx::context::{Context, CtxObj};
 use crate::{TaskError, TaskErrorSource};
+use crate::watchdog::{self, Limits};
 use super::Infrastructure;
 
+/// Environment variable that locates the pipe inside of a container
+pub const ENV_PIPE: &str = "PLAYBOOK_PIPE";
+
+/// Mount point of the pipe inside of a container
+const PIPE_MOUNT: &str = "/playbook-pipe";
+
 /// Local docker service
 pub struct Docker;
 
@@ -18,6 +26,12 @@ impl Infrastructure for Docker {
     {
         start(ctx_docker, cmd)
     }
+
+    fn plan<I>(&self, ctx_docker: Context, cmd: I) -> Result<String, TaskError>
+      where I: IntoIterator, I::Item: AsRef<std::ffi::OsStr>
+    {
+        command(&ctx_docker, cmd).map(|(docker_run, _container_name)| crate::format_cmd(docker_run))
+    }
 }
 
 pub fn inside_docker() -> bool {
@@ -29,7 +43,15 @@ pub fn inside_docker() -> bool {
     }
 }
 
-pub fn start<I, S>(ctx_docker: Context, cmd: I) -> Result<String, TaskError>
+/// ID of a local image, which changes whenever the image is built or pulled anew
+pub fn image_id(image: &str) -> Option<String> {
+    let output = std::process::Command::new("docker").args(["image", "inspect", "--format", "{{.Id}}", image]).output().ok()?;
+    if output.status.success() { Some(String::from_utf8_lossy(&output.stdout).trim().to_owned()) }
+    else { None }
+}
+
+/// Build the `docker run` command line, along with the name of the container if it has to be named
+pub fn command<I, S>(ctx_docker: &Context, cmd: I) -> Result<(Vec<String>, Option<String>), TaskError>
   where I: IntoIterator<Item = S>, S: AsRef<OsStr>
 {
     let username;
@@ -44,11 +66,27 @@ pub fn start<I, S>(ctx_docker: Context, cmd: I) -> Result<String, TaskError>
     else {
         return Err(TaskError { msg: String::from("Failed to identify the user."), src: TaskErrorSource::Internal });
     }
+    let limits = match Limits::from_ctx(ctx_docker) {
+        Ok(limits) => limits,
+        Err(e) => { return Err(TaskError { msg: e, src: TaskErrorSource::Internal }); }
+    };
     let mut userinfo = HashMap::new();
     crate::copy_user_info(&mut userinfo, &username);
     let home = format!("/home/{}", &username);
     let mut docker_run: Vec<String> = ["docker", "run", "--init", "--rm"].iter().map(|&s| {s.to_owned()}).collect();
-    if let Some(CtxObj::Bool(interactive)) = ctx_docker.get("interactive") {
+    if limits.idle_timeout.is_some() {
+        // * The watchdog pipes the output, which is no terminal to allocate a TTY for.
+        if let Some(CtxObj::Bool(true)) = ctx_docker.get("interactive") {
+            return Err(TaskError { msg: String::from("An interactive container cannot have an `idle_timeout`, which pipes its output."), src: TaskErrorSource::Internal });
+        }
+        if ctx_docker.get("interactive").is_none() {
+            docker_run.push(String::from("-i"));
+        }
+        // * PyO3 & Python don't print without either -u or -t
+        docker_run.push(String::from("-e"));
+        docker_run.push(String::from("PYTHONUNBUFFERED=1"));
+    }
+    else if let Some(CtxObj::Bool(interactive)) = ctx_docker.get("interactive") {
         if *interactive {
             docker_run.push(String::from("-it"));
         }
@@ -115,6 +153,21 @@ pub fn start<I, S>(ctx_docker: Context, cmd: I) -> Result<String, TaskError>
             }
         }
     }
+    for name in crate::hostenv::docker_env() {
+        docker_run.push(String::from("-e"));
+        docker_run.push(name);
+    }
+    for (name, _value) in crate::secrets::docker_env() {
+        docker_run.push(String::from("-e"));
+        docker_run.push(name);
+    }
+    if let Some(CtxObj::Str(pipe)) = ctx_docker.get("pipe") {
+        // * the resulting context of the step will be piped back through here
+        docker_run.push(String::from("-v"));
+        docker_run.push(format!("{}:{}:rw", pipe, PIPE_MOUNT));
+        docker_run.push(String::from("-e"));
+        docker_run.push(format!("{}={}", ENV_PIPE, PIPE_MOUNT));
+    }
     if let Some(CtxObj::Str(impersonate)) = ctx_docker.get("impersonate") {
         if impersonate == "dynamic" {
             docker_run.push(String::from("--cap-add=SETUID"));
@@ -136,7 +189,13 @@ pub fn start<I, S>(ctx_docker: Context, cmd: I) -> Result<String, TaskError>
         docker_run.push(String::from("-u"));
         docker_run.push(format!("{}:{}", userinfo["uid"], userinfo["gid"]));
     }
-    if let Some(CtxObj::Str(name)) = ctx_docker.get("name") {
+    let container_name = match ctx_docker.get("name") {
+        Some(CtxObj::Str(name)) => Some(name.to_owned()),
+        // * A container has to be named for signals to be relayed to it when time is up.
+        _ if !limits.is_empty() => Some(format!("playbook-{}-{}", std::process::id(), std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|d| d.as_millis()).unwrap_or(0))),
+        _ => None
+    };
+    if let Some(ref name) = container_name {
         docker_run.push(format!("--name={}", name));
     }
     if let Some(CtxObj::Str(image_name)) = ctx_docker.get("image") {
@@ -146,44 +205,60 @@ pub fn start<I, S>(ctx_docker: Context, cmd: I) -> Result<String, TaskError>
         return Err(TaskError {  msg: String::from("The Docker image specification was invalid."), src: TaskErrorSource::Internal });
     }
     docker_run.extend::<Vec<String>>(cmd.into_iter().map(|s| {s.as_ref().to_str().unwrap().to_owned()}).collect());
+    Ok((docker_run, container_name))
+}
+
+pub fn start<I, S>(ctx_docker: Context, cmd: I) -> Result<String, TaskError>
+  where I: IntoIterator<Item = S>, S: AsRef<OsStr>
+{
+    let limits = match Limits::from_ctx(&ctx_docker) {
+        Ok(limits) => limits,
+        Err(e) => { return Err(TaskError { msg: e, src: TaskErrorSource::Internal }); }
+    };
+    let (docker_run, container_name) = command(&ctx_docker, cmd)?;
     let docker_cmd = crate::format_cmd(docker_run.clone());
-    info!("{}", &docker_cmd);
+    info!("{}", crate::secrets::redact(&docker_cmd));
+    crate::observer::notify(|o| o.on_container_start(&docker_run));
     #[cfg(feature = "ci_only")] // Let's see the docker command during testing.
-    println!("{}", &docker_cmd);
+    println!("{}", crate::secrets::redact(&docker_cmd));
     let docker_linux: Vec<CString> = docker_run.iter().map(|s| {CString::new(s as &str).unwrap()}).collect();
-    match fork() {
-        Ok(ForkResult::Child) => {
-            match execvp(&CString::new("docker").unwrap(), &docker_linux) {
-                Ok(_void) => unreachable!(),
-                Err(e) => Err(TaskError { msg: format!("Failed to issue the Docker command. {}", e), src: TaskErrorSource::NixError(e) }),
+    let relay = |sig: Signal| {
+        if let Some(ref name) = container_name {
+            let _ = std::process::Command::new("docker").args(["kill", "--signal", &format!("{:?}", sig), name]).status();
+        }
+    };
+    let status = watchdog::run(|| {
+        // * Secrets are handed to the container by `-e NAME`, which reads them from here.
+        for (name, value) in crate::secrets::docker_env() {
+            std::env::set_var(name, value);
+        }
+        match execvp(&CString::new("docker").unwrap(), &docker_linux) {
+            Ok(_void) => unreachable!(),
+            Err(e) => {
+                error!("Failed to issue the Docker command. {}", e);
+                127
             }
-        },
-        Ok(ForkResult::Parent { child, .. }) => {
-            match waitpid(child, None) {
-                Ok(status) => match status {
-                    WaitStatus::Exited(_, exit_code) => {
-                        if exit_code == 0 { Ok(docker_cmd) }
-                        else {
-                            Err(TaskError {
-                                msg: format!("The container has returned a non-zero exit code ({}).", exit_code.to_string().red()),
-                                src: TaskErrorSource::ExitCode(exit_code)
-                            })
-                        }
-                    },
-                    WaitStatus::Signaled(_, sig, _core_dump) => {
-                        Err(TaskError {
-                            msg: format!("The container has received a signal ({:?}).", sig),
-                            src: TaskErrorSource::Signal(sig)
-                        })
-                    },
-                    WaitStatus::Stopped(_, _sig) => unreachable!(),
-                    WaitStatus::Continued(_) => unreachable!(),
-                    WaitStatus::StillAlive => unreachable!(),
-                    _ => unimplemented!()
-                },
-                Err(e) => Err(TaskError { msg: String::from("Failed to keep track of the child process."), src: TaskErrorSource::NixError(e) })
+        }
+    }, &limits, relay)?;
+    match status {
+        WaitStatus::Exited(_, exit_code) => {
+            if exit_code == 0 { Ok(docker_cmd) }
+            else {
+                Err(TaskError {
+                    msg: format!("The container has returned a non-zero exit code ({}).", exit_code.to_string().red()),
+                    src: TaskErrorSource::ExitCode(exit_code)
+                })
             }
         },
-        Err(e) => Err(TaskError { msg: String::from("Failed to spawn a new process."), src: TaskErrorSource::NixError(e) }),
+        WaitStatus::Signaled(_, sig, _core_dump) => {
+            Err(TaskError {
+                msg: format!("The container has received a signal ({:?}).", sig),
+                src: TaskErrorSource::Signal(sig)
+            })
+        },
+        WaitStatus::Stopped(_, _sig) => unreachable!(),
+        WaitStatus::Continued(_) => unreachable!(),
+        WaitStatus::StillAlive => unreachable!(),
+        _ => unimplemented!()
     }
 }
@@ -26,38 +26,52 @@ impl Infrastructure for Hotwings {
     fn start<I>(&self, ctx_docker: Context, cmd: I) -> Result<String, TaskError>
       where I: IntoIterator, I::Item: AsRef<std::ffi::OsStr>
     {
-        // TODO get user info by deserializing a file from the submission tgz
-        let username = "hotwings";
-
-        // let username;
-        // let output = std::process::Command::new("id").output().unwrap();
-        // let mut id_stdout = String::from_utf8_lossy(&output.stdout).into_owned();
-        // let newline_len = id_stdout.trim_right().len();
-        // id_stdout.truncate(newline_len);
-        // let rule = Regex::new(r"^uid=(?P<uid>[0-9]+)(\((?P<user>\w+)\))? gid=(?P<gid>[0-9]+)(\((?P<group>\w+)\))?").unwrap();
-        // if let Some(caps) = rule.captures(&id_stdout) {
-        //     username = caps.name("user").unwrap().as_str().to_owned();
-        // }
-        // else {
-        //     return Err(TaskError { msg: String::from("Failed to identify the user."), src: TaskErrorSource::Internal });
-        // }
-        // let mut userinfo = HashMap::new();
-        // crate::copy_user_info(&mut userinfo, &username);
-        let home = format!("/home/{}", &username);
-        let playbook_from: String = ctx_docker.unpack("playbook-from").unwrap();
-        let ctx_modded = ctx_docker
-            .set("hotwings_user", CtxObj::Str(username.to_owned()))
-            .set("hotwings_task_id", CtxObj::Str(get_task_id(&playbook_from)));
-        match k8s_api(ctx_modded, cmd) {
+        match manifests(ctx_docker, cmd) {
             Ok(resources) => {
                 match k8s_provisioner(&resources) {
                     Ok(()) => Ok(String::from(resources.iter().map(|(api, res)| res as &str).collect::<Vec<&str>>().join("\n"))),
                     Err(e) => Err(e)
                 }
             },
-            Err(e) => Err(TaskError { msg: e.desc, src: TaskErrorSource::Internal })
+            Err(e) => Err(e)
         }
     }
+
+    /// Render the K8s YAMLs that `start` would provision
+    fn plan<I>(&self, ctx_docker: Context, cmd: I) -> Result<String, TaskError>
+      where I: IntoIterator, I::Item: AsRef<std::ffi::OsStr>
+    {
+        manifests(ctx_docker, cmd).map(|resources| resources.iter().map(|(api, res)| res as &str).collect::<Vec<&str>>().join("---\n"))
+    }
+}
+
+/// Translate ctx_docker into K8s YAMLs on behalf of the Hotwings user
+fn manifests<I>(ctx_docker: Context, cmd: I) -> Result<Vec<(String, String)>, TaskError>
+  where I: IntoIterator, I::Item: AsRef<std::ffi::OsStr>
+{
+    // TODO get user info by deserializing a file from the submission tgz
+    let username = "hotwings";
+
+    // let username;
+    // let output = std::process::Command::new("id").output().unwrap();
+    // let mut id_stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+    // let newline_len = id_stdout.trim_right().len();
+    // id_stdout.truncate(newline_len);
+    // let rule = Regex::new(r"^uid=(?P<uid>[0-9]+)(\((?P<user>\w+)\))? gid=(?P<gid>[0-9]+)(\((?P<group>\w+)\))?").unwrap();
+    // if let Some(caps) = rule.captures(&id_stdout) {
+    //     username = caps.name("user").unwrap().as_str().to_owned();
+    // }
+    // else {
+    //     return Err(TaskError { msg: String::from("Failed to identify the user."), src: TaskErrorSource::Internal });
+    // }
+    // let mut userinfo = HashMap::new();
+    // crate::copy_user_info(&mut userinfo, &username);
+    let home = format!("/home/{}", &username);
+    let playbook_from: String = ctx_docker.unpack("playbook-from").unwrap();
+    let ctx_modded = ctx_docker
+        .set("hotwings_user", CtxObj::Str(username.to_owned()))
+        .set("hotwings_task_id", CtxObj::Str(get_task_id(&playbook_from)));
+    k8s_api(ctx_modded, cmd).map_err(|e| TaskError { msg: e.desc, src: TaskErrorSource::Internal })
 }
 
 /// Get task id from playbook path
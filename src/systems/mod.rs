@@ -11,6 +11,12 @@ pub trait Infrastructure {
       where
         I: IntoIterator,
         I::Item: AsRef<std::ffi::OsStr>;
+
+    /// Describe what `start` would do, e.g. the command line or the manifests, without doing it
+    fn plan<I>(&self, ctx_docker: Context, cmd: I) -> Result<String, TaskError>
+      where
+        I: IntoIterator,
+        I::Item: AsRef<std::ffi::OsStr>;
 }
 
 pub fn abstract_infrastructures(name: &str) -> Option<impl Infrastructure> {
@@ -38,4 +44,14 @@ impl Infrastructure for SupportedInfrastructure {
             SupportedInfrastructure::Hotwings(i) => i.start(ctx_docker, cmd)
         }
     }
+
+    fn plan<I>(&self, ctx_docker: Context, cmd: I) -> Result<String, TaskError>
+      where I: IntoIterator, I::Item: AsRef<std::ffi::OsStr>
+    {
+        match self {
+            SupportedInfrastructure::Docker(i) => i.plan(ctx_docker, cmd),
+            #[cfg(feature = "sys_hotwings")]
+            SupportedInfrastructure::Hotwings(i) => i.plan(ctx_docker, cmd)
+        }
+    }
 }
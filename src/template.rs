@@ -0,0 +1,128 @@
+//! Interpolation of `{{ var }}` references in the values of a step
+//!
+//! **Example(s)**
+//! ```yaml
+//! data_root: /mnt/data
+//! dataset: imagenet
+//! steps:
+//! - action: train
+//!   epochs: "{{ defaults.epochs }}" # a lone reference keeps the type of the value
+//!   docker:
+//!     image: aleozlx/tkstack2:latest
+//!     volumes: ["{{ data_root }}/{{ dataset }}:/data"]
+//! ```
+//!
+//! Only the values written in the step itself, and its `docker` settings, are interpolated.
+//! Their references are looked up in the deduced context of the step with dotted paths,
+//! so a global or a state that happens to contain `{{` is passed through as is.
+//! It is an error to refer to a variable that is undefined.
+//! A literal `{{` is written as `\{{`.
+
+use ymlctx::context::{Context, CtxObj};
+use crate::expr::lookup;
+
+/// Whether a string has anything to interpolate
+pub fn is_template(s: &str) -> bool {
+    s.contains("{{")
+}
+
+/// Interpolate the values that a step writes itself, against its deduced context
+pub fn render(ctx_step: &Context, ctx_step_raw: &Context) -> Result<Context, String> {
+    let mut ret = ctx_step.clone();
+    for key in ctx_step.keys() {
+        let val = ctx_step.get(key).unwrap();
+        // * A value that has been replaced by a state or an override is not the step's own.
+        let own = key == "docker" || ctx_step_raw.get(key) == Some(val);
+        if own && needs_rendering(val) {
+            ret = ret.set(key, render_obj(val, ctx_step)?);
+        }
+    }
+    Ok(ret)
+}
+
+fn render_ctx(ctx: &Context, scope: &Context) -> Result<Context, String> {
+    let mut ret = ctx.clone();
+    for key in ctx.keys() {
+        let val = ctx.get(key).unwrap();
+        if needs_rendering(val) {
+            ret = ret.set(key, render_obj(val, scope)?);
+        }
+    }
+    Ok(ret)
+}
+
+fn needs_rendering(val: &CtxObj) -> bool {
+    match val {
+        CtxObj::Str(s) => is_template(s),
+        CtxObj::Array(items) => items.iter().any(needs_rendering),
+        CtxObj::Context(ctx) => ctx.keys().any(|key| needs_rendering(ctx.get(key).unwrap())),
+        _ => false
+    }
+}
+
+fn render_obj(val: &CtxObj, scope: &Context) -> Result<CtxObj, String> {
+    match val {
+        CtxObj::Str(s) if is_template(s) => render_str(s, scope),
+        CtxObj::Array(items) => Ok(CtxObj::Array(items.iter().map(|item| render_obj(item, scope)).collect::<Result<Vec<CtxObj>, String>>()?)),
+        CtxObj::Context(ctx) => Ok(CtxObj::Context(render_ctx(ctx, scope)?)),
+        _ => Ok(val.to_owned())
+    }
+}
+
+/// Interpolate a string, which becomes the value itself if it consists of a lone reference
+pub fn render_str(s: &str, scope: &Context) -> Result<CtxObj, String> {
+    let mut ret = String::new();
+    let mut rest = s;
+    while let Some(start) = rest.find("{{") {
+        if rest[..start].ends_with('\\') {
+            ret.push_str(&rest[..start-1]);
+            ret.push_str("{{");
+            rest = &rest[start+2..];
+            continue;
+        }
+        let end = match rest[start..].find("}}") {
+            Some(end) => start + end,
+            None => { return Err(format!("Unterminated reference in `{}`.", s)); }
+        };
+        let path = rest[start+2..end].trim();
+        if path.is_empty() || !path.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '-' || c == '.') {
+            return Err(format!("Invalid reference `{}` in `{}`.", &rest[start..end+2], s));
+        }
+        let val = match lookup(scope, path) {
+            Some(val) => val,
+            None => { return Err(format!("Variable `{}` is undefined.", path)); }
+        };
+        if start == 0 && end + 2 == rest.len() && ret.is_empty() {
+            return Ok(val.to_owned());
+        }
+        ret.push_str(&rest[..start]);
+        match val {
+            CtxObj::Str(v) => ret.push_str(v),
+            CtxObj::Int(v) => ret.push_str(&v.to_string()),
+            CtxObj::Real(v) => ret.push_str(&v.to_string()),
+            CtxObj::Bool(v) => ret.push_str(&v.to_string()),
+            _ => { return Err(format!("Variable `{}` cannot be interpolated into a string.", path)); }
+        }
+        rest = &rest[end+2..];
+    }
+    ret.push_str(rest);
+    Ok(CtxObj::Str(ret))
+}
+
+#[test]
+fn test_render() {
+    let ctx = Context::from("data_root: /mnt/data\ndataset: imagenet\nepochs: 90\ndefaults:\n  lr: 0.1\nvolumes: ['{{ data_root }}/{{dataset}}:/data']\nn: '{{ epochs }}'\nlr: 'lr={{ defaults.lr }}'");
+    let ret = render(&ctx, &ctx).unwrap();
+    assert_eq!(ret.get("volumes"), Some(&CtxObj::Array(vec![CtxObj::Str(String::from("/mnt/data/imagenet:/data"))])));
+    assert_eq!(ret.get("n"), Some(&CtxObj::Int(90)));
+    assert_eq!(ret.get("lr"), Some(&CtxObj::Str(String::from("lr=0.1"))));
+    let ctx = Context::from("a: '{{ missing }}'");
+    assert!(render(&ctx, &ctx).is_err());
+    let ctx = Context::from("a: '{{ b'\nb: 1");
+    assert!(render(&ctx, &ctx).is_err());
+    let ctx_raw = Context::from("a: '\\{{ b }} is {{ b }}'");
+    let ctx = ctx_raw.overlay(&Context::from("b: 1\nfmt: '{{ Id }}'"));
+    let ret = render(&ctx, &ctx_raw).unwrap();
+    assert_eq!(ret.get("a"), Some(&CtxObj::Str(String::from("{{ b }} is 1"))));
+    assert_eq!(ret.get("fmt"), Some(&CtxObj::Str(String::from("{{ Id }}"))));
+}
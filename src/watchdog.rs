@@ -0,0 +1,238 @@
+//! Time limits of steps, enforced upon child processes
+//!
+//! **Example(s)**
+//! ```yaml
+//! timeout: 12h         # the whole playbook
+//! steps:
+//! - name: Train
+//!   action: train
+//!   timeout: 2h        # this step
+//!   idle_timeout: 10m  # this step, if it prints nothing for a while
+//! ```
+//!
+//! When a limit is exceeded, the child process receives a SIGTERM, followed by a SIGKILL
+//! should it still be alive after a grace period.
+//!
+//! The output of a step is piped through the watchdog under an `idle_timeout`, so its container runs
+//! without a terminal, and cannot be `interactive`.
+
+use std::io::Write;
+use std::os::unix::io::RawFd;
+use std::time::{Duration, Instant};
+use nix::unistd::{fork, pipe, dup2, close, read, ForkResult, Pid};
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use nix::sys::signal::{kill, Signal};
+use nix::sys::wait::{waitpid, WaitStatus, WaitPidFlag};
+use ymlctx::context::{Context, CtxObj};
+use crate::{TaskError, TaskErrorSource};
+use crate::policy::parse_duration;
+
+/// How long a child process has to terminate gracefully before it gets killed
+const GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+/// How often the child process is checked upon
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Pipes from which the stdout and the stderr of the child process are read, respectively
+type Output = (RawFd, RawFd);
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Limits {
+    pub timeout: Option<Duration>,
+    pub idle_timeout: Option<Duration>
+}
+
+impl Limits {
+    /// Read the `timeout` and `idle_timeout` of a step
+    pub fn from_ctx(ctx: &Context) -> Result<Limits, String> {
+        let read = |key: &str| match ctx.get(key) {
+            Some(val) => match parse_duration(val) {
+                Some(d) => Ok(Some(d)),
+                None => Err(format!("Key `{}` must be a duration, e.g. 30m.", key))
+            },
+            None => Ok(None)
+        };
+        Ok(Limits { timeout: read("timeout")?, idle_timeout: read("idle_timeout")? })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.timeout.is_none() && self.idle_timeout.is_none()
+    }
+}
+
+/// Shorten the timeout of a step so that it does not outlive the deadline of the playbook.
+pub fn apply_deadline(ctx_step: Context, deadline: Option<Instant>) -> Result<Context, TaskError> {
+    let deadline = match deadline {
+        Some(deadline) => deadline,
+        None => { return Ok(ctx_step); }
+    };
+    let now = Instant::now();
+    if now >= deadline {
+        return Err(TaskError { msg: String::from("The playbook has timed out."), src: TaskErrorSource::Timeout });
+    }
+    let remaining = deadline - now;
+    let timeout = match ctx_step.get("timeout").and_then(parse_duration) {
+        Some(timeout) if timeout < remaining => timeout,
+        _ => remaining
+    };
+    Ok(ctx_step.set("timeout", CtxObj::Real(timeout.as_secs_f64())))
+}
+
+/// Run `task` in a child process under the given limits, and wait for it to exit.
+///
+/// * `task` @param runs in the child process and returns its exit code, unless it never returns e.g. by `execvp`
+/// * `on_signal` @param is notified of the signals sent to the child process, e.g. to relay them to a container
+pub fn run<F, S>(task: F, limits: &Limits, on_signal: S) -> Result<WaitStatus, TaskError>
+  where F: FnOnce() -> i32, S: Fn(Signal)
+{
+    // * The output is only captured when we have to tell whether the child process is idle.
+    let pipes = match limits.idle_timeout {
+        Some(_) => match pipe().and_then(|fds_out| pipe().map(|fds_err| (fds_out, fds_err))) {
+            Ok(pipes) => Some(pipes),
+            Err(e) => { return Err(TaskError { msg: String::from("Failed to capture the output."), src: TaskErrorSource::NixError(e) }); }
+        },
+        None => None
+    };
+    match fork() {
+        Ok(ForkResult::Child) => {
+            if let Some(((fd_out_read, fd_out_write), (fd_err_read, fd_err_write))) = pipes {
+                let _ = close(fd_out_read);
+                let _ = close(fd_err_read);
+                let _ = dup2(fd_out_write, 1);
+                let _ = dup2(fd_err_write, 2);
+                let _ = close(fd_out_write);
+                let _ = close(fd_err_write);
+            }
+            let exit_code = task();
+            unsafe { libc::_exit(exit_code); }
+        },
+        Ok(ForkResult::Parent { child, .. }) => {
+            let output = match pipes {
+                Some(((fd_out_read, fd_out_write), (fd_err_read, fd_err_write))) => {
+                    let _ = close(fd_out_write);
+                    let _ = close(fd_err_write);
+                    let _ = fcntl(fd_out_read, FcntlArg::F_SETFL(OFlag::O_NONBLOCK));
+                    let _ = fcntl(fd_err_read, FcntlArg::F_SETFL(OFlag::O_NONBLOCK));
+                    Some((fd_out_read, fd_err_read))
+                },
+                None => None
+            };
+            let ret = supervise(child, output, limits, on_signal);
+            if let Some((fd_out_read, fd_err_read)) = output {
+                let _ = close(fd_out_read);
+                let _ = close(fd_err_read);
+            }
+            ret
+        },
+        Err(e) => Err(TaskError { msg: String::from("Failed to spawn a new process."), src: TaskErrorSource::NixError(e) })
+    }
+}
+
+/// Forward whatever the child process has printed to a stream, and tell whether it has printed anything.
+/// A chatty child process is read no further than `until`, for its limits to be checked on time.
+fn forward<W: Write>(fd: RawFd, sink: &mut W, until: Option<Instant>) -> bool {
+    let mut buf = [0u8; 4096];
+    let mut active = false;
+    while let Ok(n) = read(fd, &mut buf) {
+        if n == 0 { break; }
+        active = true;
+        let _ = sink.write_all(&buf[..n]);
+        let _ = sink.flush();
+        if matches!(until, Some(until) if Instant::now() >= until) { break; }
+    }
+    active
+}
+
+/// Forward both the stdout and the stderr of the child process to ours, respectively
+fn forward_all(output: Option<Output>, until: Option<Instant>) -> bool {
+    match output {
+        Some((fd_out, fd_err)) => forward(fd_out, &mut std::io::stdout(), until) | forward(fd_err, &mut std::io::stderr(), until),
+        None => false
+    }
+}
+
+fn supervise<S>(child: Pid, output: Option<Output>, limits: &Limits, on_signal: S) -> Result<WaitStatus, TaskError>
+  where S: Fn(Signal)
+{
+    let started = Instant::now();
+    let mut last_active = started;
+    loop {
+        if forward_all(output, Some(Instant::now() + POLL_INTERVAL)) { last_active = Instant::now(); }
+        match waitpid(child, Some(WaitPidFlag::WNOHANG)) {
+            Ok(WaitStatus::StillAlive) => {},
+            Ok(status) => {
+                forward_all(output, None);
+                return Ok(status);
+            },
+            Err(e) => { return Err(TaskError { msg: String::from("Failed to keep track of the child process."), src: TaskErrorSource::NixError(e) }); }
+        }
+        let reason = match (limits.timeout, limits.idle_timeout) {
+            (Some(timeout), _) if started.elapsed() >= timeout => Some(format!("The step has timed out after {:?}.", timeout)),
+            (_, Some(idle_timeout)) if last_active.elapsed() >= idle_timeout => Some(format!("The step has been idle for {:?}.", idle_timeout)),
+            _ => None
+        };
+        if let Some(msg) = reason {
+            terminate(child, output, &on_signal);
+            return Err(TaskError { msg, src: TaskErrorSource::Timeout });
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Escalate from SIGTERM to SIGKILL until the child process is gone
+fn terminate<S>(child: Pid, output: Option<Output>, on_signal: &S)
+  where S: Fn(Signal)
+{
+    warn!("Terminating the child process {}.", child);
+    on_signal(Signal::SIGTERM);
+    let _ = kill(child, Signal::SIGTERM);
+    let signaled = Instant::now();
+    loop {
+        forward_all(output, Some(Instant::now() + POLL_INTERVAL));
+        match waitpid(child, Some(WaitPidFlag::WNOHANG)) {
+            Ok(WaitStatus::StillAlive) => {},
+            _ => { return; }
+        }
+        if signaled.elapsed() >= GRACE_PERIOD {
+            warn!("Killing the child process {}.", child);
+            on_signal(Signal::SIGKILL);
+            let _ = kill(child, Signal::SIGKILL);
+            let _ = waitpid(child, None);
+            return;
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+#[test]
+fn test_watchdog_timeout() {
+    let limits = Limits { timeout: Some(Duration::from_millis(200)), idle_timeout: None };
+    let ret = run(|| { std::thread::sleep(Duration::from_secs(5)); 0 }, &limits, |_| {});
+    assert_eq!(ret.unwrap_err().src, TaskErrorSource::Timeout);
+}
+
+#[test]
+fn test_watchdog_exit() {
+    let limits = Limits { timeout: Some(Duration::from_secs(5)), idle_timeout: Some(Duration::from_secs(5)) };
+    match run(|| 3, &limits, |_| {}) {
+        Ok(WaitStatus::Exited(_, exit_code)) => assert_eq!(exit_code, 3),
+        _ => panic!("The child process should have exited normally.")
+    }
+}
+
+#[test]
+fn test_watchdog_forward() {
+    let (fd_read, fd_write) = pipe().unwrap();
+    let _ = fcntl(fd_read, FcntlArg::F_SETFL(OFlag::O_NONBLOCK));
+    let chunk = [b'.'; 4096];
+    for _ in 0..8 { nix::unistd::write(fd_write, &chunk).unwrap(); }
+    // * Past the deadline, no more than a chunk is read at a time.
+    let mut sink = Vec::new();
+    assert!(forward(fd_read, &mut sink, Some(Instant::now())));
+    assert_eq!(sink.len(), 4096);
+    assert!(forward(fd_read, &mut sink, None));
+    assert_eq!(sink.len(), 8 * 4096);
+    assert!(!forward(fd_read, &mut sink, None));
+    let _ = close(fd_read);
+    let _ = close(fd_write);
+}
@@ -1,6 +1,7 @@
 extern crate tempfile;
 extern crate ymlctx;
 extern crate playbook_api;
+extern crate serde_json;
 
 #[cfg(feature = "as_switch")]
 extern crate handlebars;
@@ -29,6 +30,15 @@ fn get_output(tmpdir: &TempDir, fname: &str) -> String {
     return contents;
 }
 
+/// Put a fake `docker` on the PATH, which runs a shell script instead of any container
+fn fake_docker(tmpdir: &TempDir, script: &str) -> String {
+    let bin = tmpdir.path().join("bin");
+    std::fs::create_dir_all(&bin).unwrap();
+    std::fs::write(bin.join("docker"), format!("#!/bin/sh\n{}", script)).unwrap();
+    std::fs::set_permissions(bin.join("docker"), std::fs::Permissions::from_mode(0o755)).unwrap();
+    format!("{}:{}", bin.to_str().unwrap(), std::env::var("PATH").unwrap())
+}
+
 #[cfg(test)]
 mod test_containers {
     use playbook_api::{Context, CtxObj};    
@@ -43,6 +53,35 @@ mod test_containers {
         }
     }
 
+    #[test]
+    fn docker_command00(){
+        let ctx_docker = Context::new()
+            .set("image", CtxObj::Str(String::from("aleozlx/playbook-test:test1")))
+            .set("timeout", CtxObj::Str(String::from("1h")));
+        match playbook_api::systems::docker::command(&ctx_docker, ["true"]) {
+            Ok((docker_run, container_name)) => {
+                assert_eq!(&docker_run[..2], &["docker", "run"]);
+                assert_eq!(&docker_run[docker_run.len()-2..], &["aleozlx/playbook-test:test1", "true"]);
+                assert!(container_name.is_some());
+            }
+            Err(e) => { panic!("{}", e); }
+        }
+    }
+
+    #[test]
+    fn docker_command01(){
+        // * The watchdog of an idle_timeout pipes the output, so there is no TTY to allocate.
+        let ctx_docker = Context::new()
+            .set("image", CtxObj::Str(String::from("aleozlx/playbook-test:test1")))
+            .set("idle_timeout", CtxObj::Str(String::from("10m")));
+        let (docker_run, _) = playbook_api::systems::docker::command(&ctx_docker, ["true"]).unwrap();
+        assert!(docker_run.iter().any(|arg| arg == "-i"));
+        assert!(!docker_run.iter().any(|arg| arg == "-it" || arg == "-t"));
+        let (docker_run, _) = playbook_api::systems::docker::command(&ctx_docker.set("interactive", CtxObj::Bool(false)), ["true"]).unwrap();
+        assert!(!docker_run.iter().any(|arg| arg == "-i" || arg == "-it" || arg == "-t"));
+        assert!(playbook_api::systems::docker::command(&ctx_docker.set("interactive", CtxObj::Bool(true)), ["true"]).is_err());
+    }
+
     #[test]
     fn docker_start00(){
         let ctx_docker = Context::new()
@@ -257,3 +296,825 @@ mod test_hotwings {
     }
 }
 
+
+#[cfg(test)]
+mod test_flow_control {
+    use playbook_api::{Context, CtxObj};
+
+    fn count_ctxdumps(scratch: &super::TempDir) -> usize {
+        std::fs::read_dir(scratch.path()).unwrap().filter(|f| {
+            if let Ok(entry) = f {
+                entry.path().file_name().unwrap().to_str().unwrap().starts_with("ctxdump-")
+            }
+            else { false }
+        }).count()
+    }
+
+    #[test]
+    fn step_when(){
+        let scratch = super::get_scratch();
+        let playbook = playbook_api::load_yaml("tests/test4/when.yml").expect("Cannot load test playbook.")
+            .set("ctxdump", CtxObj::Str(scratch.path().to_str().unwrap().to_owned()));
+        let ctx_args = Context::new()
+            .set("playbook", CtxObj::Str(String::from("tests/test4/when.yml")));
+        match playbook_api::run_playbook(playbook, ctx_args) {
+            Ok(()) => {
+                assert_eq!(count_ctxdumps(&scratch), 1);
+            }
+            Err(e) => { panic!("Error: exit_code = {:?}", e); }
+        }
+    }
+
+    #[test]
+    fn step_handlers(){
+        let scratch = super::get_scratch();
+        let playbook = playbook_api::load_yaml("tests/test4/handlers.yml").expect("Cannot load test playbook.")
+            .set("ctxdump", CtxObj::Str(scratch.path().to_str().unwrap().to_owned()));
+        let ctx_args = Context::new()
+            .set("playbook", CtxObj::Str(String::from("tests/test4/handlers.yml")));
+        match playbook_api::run_playbook(playbook, ctx_args) {
+            Ok(()) => { panic!("The playbook should have failed."); }
+            Err(e) => {
+                assert_eq!(e.kind, playbook_api::ErrorKind::Exit(3));
+                let dumps: Vec<String> = std::fs::read_dir(scratch.path()).unwrap()
+                    .map(|f| std::fs::read_to_string(f.unwrap().path()).unwrap()).collect();
+                assert_eq!(dumps.len(), 2);
+                let ctx_failure = Context::from(dumps[0].as_str()).subcontext("failure").unwrap();
+                assert_eq!(ctx_failure.get("step"), Some(&CtxObj::Str(String::from("Fail on purpose"))));
+                assert_eq!(ctx_failure.get("index"), Some(&CtxObj::Int(1)));
+                assert_eq!(ctx_failure.get("exit_code"), Some(&CtxObj::Int(3)));
+            }
+        }
+    }
+
+    #[test]
+    fn step_exit(){
+        let scratch = super::get_scratch();
+        let playbook = Context::from("steps:\n- action: sys_exit\n- action: sys_ctxdump\non_failure:\n- action: sys_ctxdump")
+            .set("ctxdump", CtxObj::Str(scratch.path().to_str().unwrap().to_owned()));
+        let ctx_args = Context::new()
+            .set("playbook", CtxObj::Str(String::from("tests/test4/exit.yml")));
+        match playbook_api::run_playbook(playbook, ctx_args) {
+            Ok(()) => {
+                assert_eq!(count_ctxdumps(&scratch), 0);
+            }
+            Err(e) => { panic!("Error: exit_code = {:?}", e); }
+        }
+    }
+
+    #[test]
+    fn step_include(){
+        let scratch = super::get_scratch();
+        let playbook = playbook_api::load_yaml("tests/test6/include.yml").expect("Cannot load test playbook.");
+        let steps = playbook_api::builtins::expand_includes(playbook.list_contexts("steps").unwrap(), "tests/test6/include.yml").unwrap();
+        assert_eq!(steps.len(), 3);
+        assert_eq!(steps[0].get("dataset"), Some(&CtxObj::Str(String::from("imagenet"))));
+        assert_eq!(steps[1].get("name"), Some(&CtxObj::Str(String::from("Normalize"))));
+        assert_eq!(steps[0].list_contexts("whitelist").unwrap()[0].get("src"), Some(&CtxObj::Str(String::from("common/prep.py"))));
+        let ctx_args = Context::new()
+            .set("playbook", CtxObj::Str(String::from("tests/test6/include.yml")));
+        match playbook_api::run_playbook(playbook.set("ctxdump", CtxObj::Str(scratch.path().to_str().unwrap().to_owned())), ctx_args) {
+            Ok(()) => {
+                assert_eq!(count_ctxdumps(&scratch), 3);
+            }
+            Err(e) => { panic!("Error: exit_code = {:?}", e); }
+        }
+    }
+
+    #[test]
+    fn step_include_keys(){
+        let scratch = super::get_scratch();
+        let path = |fname: &str| scratch.path().join(fname).to_str().unwrap().to_owned();
+        std::fs::write(path("sub.yml"), "steps:\n- name: fail\n  action: sys_exit\n  exit_code: 3\n  tags: sub\n- name: after\n  action: sys_ctxdump\n  when: \"ready is defined\"\n").unwrap();
+        std::fs::write(path("main.yml"), "steps:\n- name: first\n  action: sys_vars\n- action: sys_include\n  from: sub.yml\n  when: \"false\"\n  tags: [prep]\n  needs: first\n").unwrap();
+        let playbook = playbook_api::load_yaml(path("main.yml")).unwrap();
+        let steps = playbook_api::builtins::expand_includes(playbook.list_contexts("steps").unwrap(), &path("main.yml")).unwrap();
+        assert_eq!(steps.len(), 3);
+        assert_eq!(steps[1].get("when"), Some(&CtxObj::Str(String::from("false"))));
+        assert_eq!(steps[1].get("tags"), Some(&CtxObj::Array(vec![CtxObj::Str(String::from("prep")), CtxObj::Str(String::from("sub"))])));
+        assert_eq!(steps[1].get("needs"), Some(&CtxObj::Array(vec![CtxObj::Str(String::from("first"))])));
+        assert_eq!(steps[2].get("when"), Some(&CtxObj::Str(String::from("(false) and (ready is defined)"))));
+        assert_eq!(steps[2].get("needs"), None);
+        let ctx_args = Context::new().set("playbook", CtxObj::Str(path("main.yml")));
+        playbook_api::run_playbook(playbook, ctx_args).unwrap();
+    }
+
+    #[test]
+    fn step_include_cycle(){
+        let scratch = super::get_scratch();
+        let path = |fname: &str| scratch.path().join(fname).to_str().unwrap().to_owned();
+        std::fs::create_dir(path("common")).unwrap();
+        std::fs::write(path("main.yml"), "steps:\n- action: sys_include\n  from: common/sub.yml\n").unwrap();
+        std::fs::write(path("common/sub.yml"), "steps:\n- action: sys_include\n  from: ./../main.yml\n").unwrap();
+        std::fs::write(path("self.yml"), "steps:\n- action: sys_include\n  from: ./self.yml\n").unwrap();
+        for fname in ["main.yml", "self.yml"].iter() {
+            let playbook = playbook_api::load_yaml(path(fname)).unwrap();
+            assert!(playbook_api::builtins::expand_includes(playbook.list_contexts("steps").unwrap(), &path(fname)).is_err());
+        }
+    }
+
+    #[test]
+    fn playbook_stages(){
+        let step_name = |playbook: &Context| playbook.list_contexts("steps").unwrap()[0].get("name").cloned();
+        let playbook = playbook_api::load_yaml("tests/test6/stages.yml").expect("Cannot load test playbook.");
+        assert_eq!(step_name(&playbook), Some(CtxObj::Str(String::from("Default"))));
+        let playbook = playbook_api::load_yaml("tests/test6/stages.yml#eval").expect("Cannot load test playbook.");
+        assert_eq!(step_name(&playbook), Some(CtxObj::Str(String::from("Eval"))));
+        assert_eq!(playbook.get("dataset"), Some(&CtxObj::Str(String::from("imagenet"))));
+        assert_eq!(playbook.get("stage"), None);
+        let playbook = playbook_api::load_yaml("tests/test6/stages.yml#1").expect("Cannot load test playbook.");
+        assert_eq!(step_name(&playbook), Some(CtxObj::Str(String::from("Train"))));
+        assert_eq!(playbook.get("dataset"), Some(&CtxObj::Str(String::from("mnist"))));
+        assert!(playbook_api::load_yaml("tests/test6/stages.yml#deploy").is_err());
+    }
+
+    #[test]
+    fn step_template(){
+        let scratch = super::get_scratch();
+        let playbook = playbook_api::load_yaml("tests/test6/template.yml").expect("Cannot load test playbook.")
+            .set("ctxdump", CtxObj::Str(scratch.path().to_str().unwrap().to_owned()));
+        let ctx_args = Context::new()
+            .set("playbook", CtxObj::Str(String::from("tests/test6/template.yml")));
+        match playbook_api::run_playbook(playbook, ctx_args) {
+            Ok(()) => {
+                let mut volumes: Vec<CtxObj> = std::fs::read_dir(scratch.path()).unwrap()
+                    .map(|f| Context::from(std::fs::read_to_string(f.unwrap().path()).unwrap().as_str()).get_clone("volume").unwrap()).collect();
+                volumes.sort_by_key(|v| format!("{:?}", v));
+                assert_eq!(volumes, vec![
+                    CtxObj::Str(String::from("/mnt/data/cifar10:/data")),
+                    CtxObj::Str(String::from("/mnt/data/imagenet:/data"))
+                ]);
+            }
+            Err(e) => { panic!("Error: exit_code = {:?}", e); }
+        }
+    }
+
+    #[test]
+    fn step_template_scope(){
+        let scratch = super::get_scratch();
+        let playbook = playbook_api::load_yaml("tests/test6/scope.yml").expect("Cannot load test playbook.")
+            .set("ctxdump", CtxObj::Str(scratch.path().to_str().unwrap().to_owned()));
+        let ctx_args = Context::new()
+            .set("playbook", CtxObj::Str(String::from("tests/test6/scope.yml")));
+        playbook_api::run_playbook(playbook, ctx_args).unwrap();
+        let f = std::fs::read_dir(scratch.path()).unwrap().next().unwrap().unwrap();
+        let ctx = Context::from(std::fs::read_to_string(f.path()).unwrap().as_str());
+        assert_eq!(ctx.get("greeting"), Some(&CtxObj::Str(String::from("{{ name }} is world"))));
+        assert_eq!(ctx.get("fmt"), Some(&CtxObj::Str(String::from("{{.Id}}"))));
+        assert_eq!(ctx.get("banner"), Some(&CtxObj::Str(String::from("{{ not a reference }}"))));
+    }
+
+    #[test]
+    fn step_needs(){
+        let scratch = super::get_scratch();
+        let playbook = playbook_api::load_yaml("tests/test5/needs.yml").expect("Cannot load test playbook.")
+            .set("ctxdump", CtxObj::Str(scratch.path().to_str().unwrap().to_owned()));
+        let ctx_args = Context::new()
+            .set("playbook", CtxObj::Str(String::from("tests/test5/needs.yml")));
+        match playbook_api::run_playbook(playbook, ctx_args) {
+            Ok(()) => {
+                let dumps: Vec<String> = std::fs::read_dir(scratch.path()).unwrap()
+                    .map(|f| std::fs::read_to_string(f.unwrap().path()).unwrap()).collect();
+                assert_eq!(dumps.len(), 1);
+                let ctx_dump = Context::from(dumps[0].as_str());
+                assert_eq!(ctx_dump.get("dataset_a"), Some(&CtxObj::Str(String::from("ready"))));
+                assert_eq!(ctx_dump.get("dataset_b"), Some(&CtxObj::Str(String::from("ready"))));
+                assert_eq!(ctx_dump.get("shared"), Some(&CtxObj::Str(String::from("b"))));
+            }
+            Err(e) => { panic!("Error: exit_code = {:?}", e); }
+        }
+    }
+
+    #[test]
+    fn step_needs_cycle(){
+        let playbook = playbook_api::load_yaml("tests/test5/needs_cycle.yml").expect("Cannot load test playbook.");
+        let ctx_args = Context::new()
+            .set("playbook", CtxObj::Str(String::from("tests/test5/needs_cycle.yml")));
+        assert!(playbook_api::run_playbook(playbook, ctx_args).is_err());
+    }
+
+    #[test]
+    fn step_exit_modes(){
+        let run = |step: &str| {
+            let playbook = Context::from(format!("steps:\n- name: exit\n  action: sys_exit\n  {}", step).as_str());
+            let ctx_args = Context::new().set("playbook", CtxObj::Str(String::from("exit-modes.yml")));
+            playbook_api::run_playbook(playbook, ctx_args).unwrap_err().kind
+        };
+        // * Steps that run in child processes fail the same way as those that run in place.
+        for exit_code in [1, 3, 4, 7].iter() {
+            let sequential = run(&format!("exit_code: {}", exit_code));
+            assert_eq!(sequential, playbook_api::ErrorKind::Exit(*exit_code));
+            assert_eq!(run(&format!("exit_code: {}\n  needs: []", exit_code)), sequential);
+            assert_eq!(run(&format!("exit_code: {}\n  foreach: [a, b]", exit_code)), sequential);
+            assert_eq!(run(&format!("exit_code: {}\n  foreach: [a, b]\n  concurrency: 2", exit_code)), sequential);
+        }
+    }
+
+    #[test]
+    fn step_foreach(){
+        let scratch = super::get_scratch();
+        let playbook = playbook_api::load_yaml("tests/test4/foreach.yml").expect("Cannot load test playbook.")
+            .set("ctxdump", CtxObj::Str(scratch.path().to_str().unwrap().to_owned()));
+        let ctx_args = Context::new()
+            .set("playbook", CtxObj::Str(String::from("tests/test4/foreach.yml")));
+        match playbook_api::run_playbook(playbook, ctx_args) {
+            Ok(()) => {
+                let dumps: Vec<Context> = std::fs::read_dir(scratch.path()).unwrap()
+                    .map(|f| Context::from(std::fs::read_to_string(f.unwrap().path()).unwrap().as_str())).collect();
+                assert_eq!(dumps.len(), 4);
+                assert_eq!(dumps.iter().filter(|ctx_dump| ctx_dump.get("dataset").is_none()).count(), 1);
+            }
+            Err(e) => { panic!("Error: exit_code = {:?}", e); }
+        }
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "lang_python")]
+mod test_pipe {
+    use playbook_api::{Context, CtxObj};
+
+    #[test]
+    fn step_returns(){
+        let scratch = super::get_scratch();
+        let playbook = playbook_api::load_yaml("tests/test9/pipe.yml").expect("Cannot load test playbook.")
+            .set("ctxdump", CtxObj::Str(scratch.path().to_str().unwrap().to_owned()));
+        let ctx_args = Context::new()
+            .set("playbook", CtxObj::Str(String::from("tests/test9/pipe.yml")));
+        match playbook_api::run_playbook(playbook, ctx_args) {
+            Ok(()) => {
+                let dumps: Vec<Context> = std::fs::read_dir(scratch.path()).unwrap()
+                    .map(|f| Context::from(std::fs::read_to_string(f.unwrap().path()).unwrap().as_str())).collect();
+                assert_eq!(dumps.len(), 1);
+                assert_eq!(dumps[0].get("checkpoint"), Some(&CtxObj::Str(String::from("/scratch/resnet.ckpt"))));
+                assert_eq!(dumps[0].subcontext("metrics"), Some(Context::from("accuracy: 0.9")));
+            }
+            Err(e) => { panic!("Error: exit_code = {:?}", e); }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_lint {
+    use playbook_api::lint::Severity;
+
+    #[test]
+    fn lint_diagnostics(){
+        let playbook = playbook_api::load_yaml("tests/test7/lint.yml").expect("Cannot load test playbook.");
+        let mut diagnostics: Vec<(Severity, String)> = playbook_api::lint::check(&playbook, "tests/test7/lint.yml").into_iter()
+            .map(|diagnostic| (diagnostic.severity, diagnostic.path)).collect();
+        diagnostics.sort_by(|a, b| a.1.cmp(&b.1));
+        assert_eq!(diagnostics, vec![
+            (Severity::Error, String::from("steps.0")),
+            (Severity::Warning, String::from("steps.0.acton")),
+            (Severity::Error, String::from("steps.1.action")),
+            (Severity::Error, String::from("steps.1.docker.interactive")),
+            (Severity::Error, String::from("steps.1.docker.ports.0")),
+            (Severity::Error, String::from("steps.2.grid.1"))
+        ]);
+        assert!(playbook_api::lint::lint("tests/test7/lint.yml").is_err());
+        assert!(playbook_api::lint::lint("tests/test1/say_hi.yml").is_ok());
+    }
+
+    #[test]
+    fn lint_symbols(){
+        let playbook = playbook_api::load_yaml("tests/test8/symbols.yml").expect("Cannot load test playbook.");
+        let diagnostics: Vec<(Severity, String, String)> = playbook_api::lint::check(&playbook, "tests/test8/symbols.yml").into_iter()
+            .map(|diagnostic| (diagnostic.severity, diagnostic.path, diagnostic.message)).collect();
+        assert_eq!(diagnostics, vec![
+            (Severity::Warning, String::from("whitelist.0.src"), String::from("actions.sh:12: `# [playbook(deploy)]` is not followed by a definition of `deploy`.")),
+            (Severity::Error, String::from("steps.2.action"), String::from("Cannot find the action `deplyo` in the whitelist. Did you mean `deploy`?"))
+        ]);
+    }
+
+    #[test]
+    fn run_error(){
+        use playbook_api::ErrorKind;
+        let playbook = playbook_api::load_yaml("tests/test7/error.yml").expect("Cannot load test playbook.");
+        let ctx_args = ymlctx::context::Context::new()
+            .set("playbook", ymlctx::context::CtxObj::Str(String::from("tests/test7/error.yml")));
+        let e = playbook_api::run_playbook(playbook, ctx_args.clone()).unwrap_err();
+        assert_eq!(e.kind, ErrorKind::Exit(5));
+        assert_eq!(e.step, Some(playbook_api::error::StepRef { index: 2, name: Some(String::from("Bail out")) }));
+        assert_eq!(e.location.map(|location| (location.line, location.col)), Some((4, 3)));
+        let playbook = ymlctx::context::Context::from("steps:\n- action: sys_ctxdump\n  foreach: 3");
+        let e = playbook_api::run_playbook(playbook, ctx_args).unwrap_err();
+        assert_eq!(e.kind, ErrorKind::Syntax);
+        assert_eq!(e.message, "Syntax Error: Key `foreach` must be a list.");
+        let e = playbook_api::load_yaml("tests/test7/missing.yml").unwrap_err();
+        assert_eq!(e.kind, ErrorKind::System);
+    }
+}
+
+#[cfg(test)]
+mod test_report {
+    use ymlctx::context::{Context, CtxObj};
+
+    #[test]
+    fn run_report(){
+        let scratch = super::get_scratch();
+        let path = |fname: &str| scratch.path().join(fname).to_str().unwrap().to_owned();
+        let playbook = playbook_api::load_yaml("tests/test7/error.yml").expect("Cannot load test playbook.");
+        let ctx_args = Context::new()
+            .set("playbook", CtxObj::Str(String::from("tests/test7/error.yml")))
+            .set("report", CtxObj::Array(vec![CtxObj::Str(path("report.json")), CtxObj::Str(path("junit.xml"))]));
+        assert!(playbook_api::run_playbook(playbook, ctx_args).is_err());
+        let report: serde_json::Value = serde_json::from_str(&super::get_output(&scratch, "report.json")).unwrap();
+        assert_eq!(report["exit_code"], 5);
+        assert_eq!(report["steps"][0]["status"], "passed");
+        assert_eq!(report["steps"][0]["infrastructure"], "builtin");
+        assert_eq!(report["steps"][1]["name"], "Bail out");
+        assert_eq!(report["steps"][1]["status"], "failed");
+        assert_eq!(report["steps"][1]["exit_code"], 5);
+        let junit = super::get_output(&scratch, "junit.xml");
+        assert!(junit.contains("tests=\"2\" failures=\"1\""));
+        assert!(junit.contains("<testcase classname=\"tests/test7/error.yml\" name=\"Step 2: Bail out\""));
+    }
+}
+
+#[cfg(test)]
+mod test_observer {
+    use std::rc::Rc;
+    use std::cell::RefCell;
+    use ymlctx::context::{Context, CtxObj};
+    use playbook_api::{RunObserver, RunOptions};
+    use playbook_api::journal::Outcome;
+
+    struct Recorder {
+        events: RefCell<Vec<String>>
+    }
+
+    impl RunObserver for Recorder {
+        fn on_step_start(&self, index: usize, _ctx_step: &Context) {
+            self.events.borrow_mut().push(format!("start {}", index));
+        }
+
+        fn on_step_end(&self, index: usize, outcome: &Outcome) {
+            let outcome = match outcome {
+                Outcome::Stateful(_) => String::from("stateful"),
+                Outcome::Stateless => String::from("stateless"),
+                Outcome::Skipped => String::from("skipped"),
+                Outcome::Diverging(exit_code) => format!("exit code {}", exit_code)
+            };
+            self.events.borrow_mut().push(format!("end {} {}", index, outcome));
+        }
+
+        fn on_action_start(&self, action: &str, src: Option<&str>, _ctx: &Context) {
+            self.events.borrow_mut().push(format!("action {} {:?}", action, src));
+        }
+
+        fn on_context_change(&self, ctx_states: &Context) {
+            self.events.borrow_mut().push(format!("states {:?}", ctx_states.get("message")));
+        }
+    }
+
+    #[test]
+    fn run_observed(){
+        let playbook = Context::from("steps:\n- action: sys_vars\n  states:\n    from: external_vars.yml\n- action: sys_exit\n  exit_code: 5");
+        let ctx_args = Context::new().set("playbook", CtxObj::Str(String::from("tests/test1/observed.yml")));
+        let recorder = Rc::new(Recorder { events: RefCell::new(Vec::new()) });
+        assert!(playbook_api::run_playbook_with(playbook, ctx_args, RunOptions::new().observers(vec![recorder.clone()])).is_err());
+        assert_eq!(*recorder.events.borrow(), vec![
+            "start 1", "action sys_vars None", "states Some(Str(\"Salut!\"))", "end 1 stateful",
+            "start 2", "action sys_exit None", "end 2 exit code 5"
+        ]);
+    }
+}
+
+#[cfg(test)]
+mod test_builder {
+    use ymlctx::context::{Context, CtxObj};
+    use playbook_api::{Playbook, Step, ErrorKind};
+
+    #[test]
+    fn run_built(){
+        let contents = std::fs::read_to_string("tests/test1/say_hi.yml").unwrap();
+        let playbook = Playbook::from_yaml(&contents).expect("Cannot read test playbook.");
+        assert_eq!(playbook.whitelist[0].src, "say_hi.py");
+        assert_eq!(playbook.docker.as_ref().map(|docker| docker.image.as_str()), Some("aleozlx/playbook-test:test1"));
+        assert_eq!(Playbook::from_yaml(&playbook.to_yaml()), Ok(playbook));
+
+        let playbook = Playbook::new()
+            .set("code", CtxObj::Int(5))
+            .step(Step::new("sys_vars").name("Prepare"))
+            .step(Step::new("sys_exit").name("Bail out").set("exit_code", CtxObj::Str(String::from("{{ code }}"))));
+        let ctx_args = Context::new().set("playbook", CtxObj::Str(String::from("built.yml")));
+        let e = playbook_api::run_playbook(playbook.into(), ctx_args).unwrap_err();
+        assert_eq!(e.kind, ErrorKind::Exit(5));
+        assert_eq!(e.step.map(|step| step.name), Some(Some(String::from("Bail out"))));
+    }
+}
+
+#[cfg(test)]
+mod test_registry {
+    use ymlctx::context::{Context, CtxObj};
+    use playbook_api::{Playbook, Step, Registry, RunOptions, ErrorKind};
+    use playbook_api::builtins::TransientContext;
+
+    fn answer(ctx: Context) -> TransientContext {
+        let base: i64 = ctx.unpack("base").unwrap_or(0);
+        TransientContext::Stateful(Context::new().set("answer", CtxObj::Int(base + 42)))
+    }
+
+    #[test]
+    fn custom_builtins(){
+        let mut registry = Registry::new();
+        registry.register("acme", "answer", answer).unwrap();
+        assert!(registry.register("acme", "answer", answer).is_err());
+        assert!(registry.register("sys", "exit", answer).is_err());
+        assert!(registry.register("acme_x", "answer", answer).is_err());
+        let playbook = Playbook::new()
+            .step(Step::new("acme_answer").set("base", CtxObj::Int(8)))
+            .step(Step::new("sys_exit").set("exit_code", CtxObj::Str(String::from("{{ answer }}"))));
+        let ctx_args = Context::new().set("playbook", CtxObj::Str(String::from("registry.yml")));
+        let e = playbook_api::run_playbook_with(playbook.clone().into(), ctx_args.clone(), RunOptions::new().registry(registry)).unwrap_err();
+        assert_eq!(e.kind, ErrorKind::Exit(50));
+        // * Custom actions are only known to the runs they are given to.
+        let e = playbook_api::run_playbook(playbook.into(), ctx_args).unwrap_err();
+        assert_eq!(e.kind, ErrorKind::Syntax);
+    }
+}
+
+#[cfg(test)]
+mod test_symbols {
+    use std::rc::Rc;
+    use ymlctx::context::{Context, CtxObj};
+    use playbook_api::{Playbook, Step, ErrorKind};
+    use playbook_api::symbols;
+
+    #[test]
+    fn symbol_index(){
+        let playbook = playbook_api::load_yaml("tests/test8/symbols.yml").expect("Cannot load test playbook.");
+        let actions = symbols::available(&playbook, "tests/test8/symbols.yml").unwrap();
+        let prepare = actions.iter().find(|action| action.name == "prepare").unwrap();
+        assert_eq!(prepare.function, Some(String::from("prep")));
+        assert_eq!(prepare.src, Some((String::from("tests/test8/actions.sh"), 2)));
+        assert!(actions.iter().any(|action| action.name == "sys_exit" && action.src.is_none()));
+        let a = symbols::cached("tests/test8/actions.sh").unwrap();
+        let b = symbols::cached("tests/test8/actions.sh").unwrap();
+        assert!(Rc::ptr_eq(&a, &b));
+    }
+
+    #[test]
+    fn suggest_action(){
+        let playbook = Playbook::new().step(Step::new("sys_exti"));
+        let ctx_args = Context::new().set("playbook", CtxObj::Str(String::from("suggest.yml")));
+        let e = playbook_api::run_playbook(playbook.into(), ctx_args).unwrap_err();
+        assert_eq!(e.kind, ErrorKind::Syntax);
+        assert_eq!(e.message, "Action not recognized: sys_exti. Did you mean `sys_exit`?");
+    }
+}
+
+#[cfg(test)]
+mod test_secrets {
+    use ymlctx::context::{Context, CtxObj};
+    use playbook_api::{Playbook, Step};
+
+    #[test]
+    fn redact_ctxdump(){
+        let scratch = super::get_scratch();
+        let path = |fname: &str| scratch.path().join(fname).to_str().unwrap().to_owned();
+        std::fs::write(path("token.txt"), "t0k3n-from-file\n").unwrap();
+        let playbook = Playbook::new()
+            .set("secrets", CtxObj::Context(Context::from("token:\n  file: token.txt")))
+            .step(Step::new("sys_ctxdump")
+                .set("ctxdump", CtxObj::Str(path("")))
+                .set("auth", CtxObj::Str(String::from("Bearer {{ token }}"))));
+        let ctx_args = Context::new().set("playbook", CtxObj::Str(path("secrets.yml")));
+        playbook_api::run_playbook(playbook.into(), ctx_args).unwrap();
+        let dumps: Vec<String> = std::fs::read_dir(scratch.path()).unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|fname| fname.starts_with("ctxdump-")).collect();
+        assert_eq!(dumps.len(), 1);
+        let dump = super::get_output(&scratch, &dumps[0]);
+        assert!(dump.contains("auth: Bearer ******"));
+        assert!(!dump.contains("t0k3n-from-file"));
+    }
+}
+
+#[cfg(test)]
+mod test_hostenv {
+    use ymlctx::context::{Context, CtxObj};
+    use playbook_api::{Playbook, Step};
+
+    #[test]
+    fn expand_env(){
+        std::env::set_var("PLAYBOOK_TEST_DATA_DIR", "/data");
+        let scratch = super::get_scratch();
+        let path = |fname: &str| scratch.path().join(fname).to_str().unwrap().to_owned();
+        let playbook = |env: &str| Playbook::new()
+            .set("env", Context::from(format!("env: [{}]", env).as_str()).get_clone("env").unwrap())
+            .step(Step::new("sys_ctxdump")
+                .set("ctxdump", CtxObj::Str(path("")))
+                .set("dataset", CtxObj::Str(String::from("${PLAYBOOK_TEST_DATA_DIR}/mnist")))
+                .set("logs", CtxObj::Str(String::from("${PLAYBOOK_TEST_SCRATCH}/logs"))));
+        let ctx_args = Context::new().set("playbook", CtxObj::Str(path("hostenv.yml")));
+        playbook_api::run_playbook(playbook("PLAYBOOK_TEST_DATA_DIR, 'PLAYBOOK_TEST_SCRATCH:-/tmp'").into(), ctx_args.clone()).unwrap();
+        let dumps: Vec<String> = std::fs::read_dir(scratch.path()).unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|fname| fname.starts_with("ctxdump-")).collect();
+        assert_eq!(dumps.len(), 1);
+        let dump = super::get_output(&scratch, &dumps[0]);
+        assert!(dump.contains("dataset: /data/mnist"));
+        assert!(dump.contains("logs: /tmp/logs"));
+        assert!(dump.contains("PLAYBOOK_TEST_DATA_DIR: /data"));
+        assert!(playbook_api::run_playbook(playbook("PLAYBOOK_TEST_DATA_DIR, PLAYBOOK_TEST_SCRATCH").into(), ctx_args.clone()).is_err());
+        assert!(playbook_api::run_playbook(playbook("PLAYBOOK_TEST_DATA_DIR").into(), ctx_args).is_err());
+    }
+}
+
+#[cfg(test)]
+mod test_overrides {
+    use ymlctx::context::{Context, CtxObj};
+    use playbook_api::{Playbook, Step};
+
+    #[test]
+    fn override_ctxdump(){
+        let scratch = super::get_scratch();
+        let path = |fname: &str| scratch.path().join(fname).to_str().unwrap().to_owned();
+        std::fs::write(path("overrides.yml"), "learning_rate: 0.1\nmodel:\n  depth: 50\n").unwrap();
+        let playbook = Playbook::new()
+            .set("learning_rate", CtxObj::Real(0.5))
+            .set("model", CtxObj::Context(Context::from("depth: 18\nwidth: 64")))
+            .set("overrides", CtxObj::Context(Context::from("learning_rate: 0.9")))
+            .step(Step::new("sys_ctxdump")
+                .set("ctxdump", CtxObj::Str(path(""))));
+        let vars = path("overrides.yml");
+        let ctx_overrides = playbook_api::overrides::from_args(vec![vars.as_str()], vec!["learning_rate=0.01", "model.pretrained=true"]).unwrap();
+        let ctx_args = Context::new()
+            .set("playbook", CtxObj::Str(path("overrides-playbook.yml")))
+            .set("arg-overrides", CtxObj::Context(ctx_overrides));
+        let options = playbook_api::RunOptions::new().overrides(Context::from("model:\n  width: 128"));
+        playbook_api::run_playbook_with(playbook.into(), ctx_args, options).unwrap();
+        let dumps: Vec<String> = std::fs::read_dir(scratch.path()).unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|fname| fname.starts_with("ctxdump-")).collect();
+        assert_eq!(dumps.len(), 1);
+        let ctx = Context::from(super::get_output(&scratch, &dumps[0]).as_str());
+        assert_eq!(ctx.get("learning_rate"), Some(&CtxObj::Real(0.01)));
+        assert_eq!(ctx.subcontext("model"), Some(Context::from("depth: 50\nwidth: 128\npretrained: true")));
+        assert!(ctx.get("arg-overrides").is_none());
+        assert!(playbook_api::overrides::from_args(vec![], vec!["learning_rate"]).is_err());
+    }
+}
+
+#[cfg(test)]
+mod test_incremental {
+    use ymlctx::context::{Context, CtxObj};
+    use playbook_api::{Playbook, Step};
+
+    #[test]
+    fn skip_up_to_date(){
+        let scratch = super::get_scratch();
+        let path = |fname: &str| scratch.path().join(fname).to_str().unwrap().to_owned();
+        std::fs::create_dir(path("out")).unwrap();
+        std::fs::write(path("data.txt"), "1").unwrap();
+        let playbook: Context = Playbook::new()
+            .step(Step::new("sys_ctxdump")
+                .set("ctxdump", CtxObj::Str(path("out")))
+                .set("inputs", CtxObj::Array(vec![CtxObj::Str(path("*.txt"))]))
+                .set("outputs", CtxObj::Array(vec![CtxObj::Str(path("out"))]))).into();
+        let ctx_args = Context::new().set("playbook", CtxObj::Str(path("incremental.yml")));
+        let run = |ctx_args: &Context| {
+            for entry in std::fs::read_dir(path("out")).unwrap() {
+                std::fs::remove_file(entry.unwrap().path()).unwrap();
+            }
+            playbook_api::run_playbook(playbook.clone(), ctx_args.clone()).unwrap();
+            std::fs::read_dir(path("out")).unwrap().count()
+        };
+        assert_eq!(run(&ctx_args), 1);
+        assert!(scratch.path().join(".incremental.fingerprints.json").exists());
+        assert_eq!(run(&ctx_args), 0);
+        std::fs::write(path("data.txt"), "22").unwrap();
+        assert_eq!(run(&ctx_args), 1);
+        assert_eq!(run(&ctx_args), 0);
+        assert_eq!(run(&ctx_args.set("rebuild", CtxObj::Bool(true))), 1);
+    }
+
+    #[test]
+    fn secret_states(){
+        let scratch = super::get_scratch();
+        let path = |fname: &str| scratch.path().join(fname).to_str().unwrap().to_owned();
+        std::fs::write(path("token.txt"), "hunter2-incremental\n").unwrap();
+        std::fs::write(path("vars.yml"), "token: hunter2-incremental\nanswer: 42\n").unwrap();
+        let playbook: Context = Playbook::new()
+            .set("secrets", CtxObj::Context(Context::from("token:\n  file: token.txt")))
+            .step(Step::new("sys_vars")
+                .set("states", CtxObj::Context(Context::new().set("from", CtxObj::Str(String::from("vars.yml")))))
+                .set("outputs", CtxObj::Array(vec![CtxObj::Str(path("vars.yml"))]))).into();
+        let ctx_args = Context::new().set("playbook", CtxObj::Str(path("secret.yml")));
+        playbook_api::run_playbook(playbook, ctx_args).unwrap();
+        let contents = std::fs::read_to_string(path(".secret.fingerprints.json")).unwrap_or_default();
+        assert!(!contents.contains("hunter2"));
+    }
+}
+
+#[cfg(test)]
+mod test_cli {
+    use std::process::{Command, Output};
+    use ymlctx::context::{Context, CtxObj};
+
+    fn playbook(args: &[&str]) -> Output {
+        Command::new(env!("CARGO_BIN_EXE_playbook")).args(args).output().expect("Cannot run the playbook binary.")
+    }
+
+    #[test]
+    fn playbook_names(){
+        let scratch = super::get_scratch();
+        for fname in ["rest.yml", "res.yml", "resume.yml", "plan.yml", "plans.yml", "lint.yml", "actions.yml"].iter() {
+            let path = scratch.path().join(fname);
+            std::fs::write(&path, "steps:\n- action: sys_ctxdump\n").unwrap();
+            let path = path.to_str().unwrap();
+            let output = playbook(&[path]);
+            assert!(output.status.success(), "{}: {}", fname, String::from_utf8_lossy(&output.stderr));
+            let output = playbook(&["-v", path]);
+            assert!(output.status.success(), "-v {}: {}", fname, String::from_utf8_lossy(&output.stderr));
+            let output = playbook(&["--plan", path]);
+            assert!(output.status.success(), "--plan {}: {}", fname, String::from_utf8_lossy(&output.stderr));
+            let output = playbook(&["--lint", path]);
+            assert!(output.status.success(), "--lint {}: {}", fname, String::from_utf8_lossy(&output.stderr));
+            let output = playbook(&["--actions", path]);
+            assert!(output.status.success(), "--actions {}: {}", fname, String::from_utf8_lossy(&output.stderr));
+        }
+        assert!(!playbook(&["--resume", "no-such-run"]).status.success());
+    }
+
+    #[test]
+    fn select_steps(){
+        let scratch = super::get_scratch();
+        let path = |fname: &str| scratch.path().join(fname).to_str().unwrap().to_owned();
+        std::fs::create_dir(path("dumps")).unwrap();
+        std::fs::write(path("vars.yml"), "answer: 42\n").unwrap();
+        std::fs::write(path("select.yml"), format!("ctxdump: {}\nsteps:\n- name: vars\n  action: sys_vars\n  states:\n    from: vars.yml\n- name: dump\n  action: sys_ctxdump\n  tags: [dump]\n", path("dumps"))).unwrap();
+        let dump = |args: &[&str]| {
+            for entry in std::fs::read_dir(path("dumps")).unwrap() {
+                std::fs::remove_file(entry.unwrap().path()).unwrap();
+            }
+            let output = playbook(args);
+            assert!(output.status.success(), "{:?}: {}", args, String::from_utf8_lossy(&output.stderr));
+            let dumps: Vec<std::path::PathBuf> = std::fs::read_dir(path("dumps")).unwrap().map(|entry| entry.unwrap().path()).collect();
+            assert_eq!(dumps.len(), 1);
+            Context::from(std::fs::read_to_string(&dumps[0]).unwrap().as_str())
+        };
+        assert_eq!(dump(&["--only", "dump", &path("select.yml")]).get("answer"), None);
+        assert_eq!(dump(&["--only", "dump", "--keep-stateful", &path("select.yml")]).get("answer"), Some(&CtxObj::Int(42)));
+        assert_eq!(dump(&["--tags", "dump", "--keep-stateful", &path("select.yml")]).get("answer"), Some(&CtxObj::Int(42)));
+        assert_eq!(dump(&["--from", "2", &path("select.yml")]).get("answer"), None);
+        assert!(!playbook(&["--only", "deploy", &path("select.yml")]).status.success());
+    }
+}
+
+#[cfg(test)]
+mod test_retry {
+    use std::process::{Command, Output};
+    use std::time::{Duration, Instant};
+
+    /// Run a step of `tests/test8/actions.sh` with a retry policy, given a fake `docker` that fails a number of times
+    fn run(scratch: &super::TempDir, step: &str, failures: usize) -> (Output, Duration) {
+        let path = |fname: &str| scratch.path().join(fname).to_str().unwrap().to_owned();
+        let env_path = super::fake_docker(scratch, "if [ \"$1\" = run ]; then\n  echo run >> \"$FAKE_DOCKER_LOG\"\n  [ $(wc -l < \"$FAKE_DOCKER_LOG\") -gt $FAKE_DOCKER_FAILURES ] || exit 3\nfi\n");
+        let actions = std::fs::canonicalize("tests/test8/actions.sh").unwrap();
+        std::fs::write(path("retry.yml"), format!("whitelist:\n- src: {}\nsteps:\n- action: prepare\n  {}\n", actions.to_str().unwrap(), step)).unwrap();
+        let _ = std::fs::remove_file(path("docker.log"));
+        let t0 = Instant::now();
+        let output = Command::new(env!("CARGO_BIN_EXE_playbook")).args(["-v", "--no-journal", &path("retry.yml")])
+            .env("PATH", env_path)
+            .env("FAKE_DOCKER_LOG", path("docker.log"))
+            .env("FAKE_DOCKER_FAILURES", failures.to_string())
+            .output().unwrap();
+        (output, t0.elapsed())
+    }
+
+    fn attempts(output: &Output) -> usize {
+        String::from_utf8_lossy(&output.stderr).lines().filter(|line| line.contains("Step 1: attempt ") && !line.contains("has failed")).count()
+    }
+
+    #[test]
+    fn retry_backoff(){
+        // * Shell actions cannot be run on the host, which is an internal failure of every attempt.
+        let scratch = super::get_scratch();
+        let (output, elapsed) = run(&scratch, "retry: {attempts: 3, backoff: exponential, initial: 200ms}", 0);
+        assert!(!output.status.success());
+        assert_eq!(attempts(&output), 3);
+        assert!(elapsed >= Duration::from_millis(600), "{:?}", elapsed);
+    }
+
+    #[test]
+    fn retry_classified(){
+        let scratch = super::get_scratch();
+        let (output, elapsed) = run(&scratch, "retry: {attempts: 3, initial: 10s, on: [signal, exit_code]}", 0);
+        assert!(!output.status.success());
+        assert_eq!(attempts(&output), 1);
+        assert!(elapsed < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn retry_container(){
+        let scratch = super::get_scratch();
+        let step = "docker: {image: acme/prepare}\n  retry: {attempts: 3, backoff: linear, initial: 100ms, on: [exit_code]}";
+        let (output, _) = run(&scratch, step, 2);
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        assert_eq!(super::get_output(&scratch, "docker.log"), "run\nrun\nrun\n");
+        let (output, _) = run(&scratch, step, 3);
+        assert!(!output.status.success());
+        assert_eq!(super::get_output(&scratch, "docker.log"), "run\nrun\nrun\n");
+    }
+}
+
+#[cfg(test)]
+mod test_watchdog {
+    use std::process::Command;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn step_timeout(){
+        let scratch = super::get_scratch();
+        let path = |fname: &str| scratch.path().join(fname).to_str().unwrap().to_owned();
+        let env_path = super::fake_docker(&scratch, "if [ \"$1\" = run ]; then\n  echo to-stdout\n  echo to-stderr >&2\n  exec sleep 30\nfi\n");
+        let actions = std::fs::canonicalize("tests/test8/actions.sh").unwrap();
+        std::fs::write(path("timeout.yml"), format!("whitelist:\n- src: {}\nsteps:\n- action: prepare\n  docker: {{image: acme/prepare}}\n  timeout: 1s\n  idle_timeout: 10m\n", actions.to_str().unwrap())).unwrap();
+        let t0 = Instant::now();
+        let output = Command::new(env!("CARGO_BIN_EXE_playbook")).args(["--no-journal", &path("timeout.yml")]).env("PATH", env_path).output().unwrap();
+        assert!(t0.elapsed() < Duration::from_secs(10), "{:?}", t0.elapsed());
+        assert!(!output.status.success());
+        let (stdout, stderr) = (String::from_utf8_lossy(&output.stdout), String::from_utf8_lossy(&output.stderr));
+        assert!(stderr.contains("The step has timed out"), "{}", stderr);
+        // * The output of the step is forwarded to either stream as it has been printed.
+        assert!(stdout.contains("to-stdout") && !stdout.contains("to-stderr"), "{}", stdout);
+        assert!(stderr.contains("to-stderr") && !stderr.contains("to-stdout"), "{}", stderr);
+    }
+}
+
+#[cfg(test)]
+mod test_journal {
+    use std::process::Command;
+    use ymlctx::context::{Context, CtxObj};
+    use playbook_api::journal::{Journal, Outcome, Status};
+
+    fn run_dir(run_id: &str) -> std::path::PathBuf {
+        std::path::Path::new(&std::env::var("HOME").unwrap()).join(".playbook-rs").join("runs").join(run_id)
+    }
+
+    #[test]
+    fn journal_exit(){
+        let scratch = super::get_scratch();
+        let run_id = format!("test-journal-exit-{}", std::process::id());
+        let path = scratch.path().join("exit.yml");
+        std::fs::write(&path, format!("ctxdump: {}\nsteps:\n- action: sys_exit\n- action: sys_ctxdump\n", scratch.path().to_str().unwrap())).unwrap();
+        let ctx_args = Context::new()
+            .set("playbook", CtxObj::Str(path.to_str().unwrap().to_owned()))
+            .set("run-id", CtxObj::Str(run_id.clone()));
+        playbook_api::run_playbook(playbook_api::load_yaml(&path).unwrap(), ctx_args).unwrap();
+        let journal = Journal::load(&run_id).unwrap();
+        assert_eq!(journal.status, Status::Succeeded);
+        assert_eq!(journal.steps.len(), 1);
+        assert_eq!(journal.steps[0].outcome, Outcome::Diverging(0));
+        // * Resuming a run that has exited cleanly does not run its exit step again, nor any after it.
+        let output = Command::new(env!("CARGO_BIN_EXE_playbook")).args(["--resume", &run_id]).output().unwrap();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        assert_eq!(std::fs::read_dir(scratch.path()).unwrap().count(), 1);
+        assert_eq!(Journal::load(&run_id).unwrap().steps.len(), 1);
+        std::fs::remove_dir_all(run_dir(&run_id)).unwrap();
+    }
+
+    #[test]
+    fn journal_resume(){
+        let scratch = super::get_scratch();
+        let path = |fname: &str| scratch.path().join(fname).to_str().unwrap().to_owned();
+        std::fs::create_dir(path("dumps")).unwrap();
+        std::fs::write(path("vars.yml"), "answer: 42\n").unwrap();
+        let playbook = |step: &str| format!("ctxdump: {}\nsteps:\n- action: sys_vars\n  states:\n    from: vars.yml\n- {}\n- action: sys_ctxdump\n", path("dumps"), step);
+        std::fs::write(path("resume.yml"), playbook("action: sys_exit\n  exit_code: 3")).unwrap();
+        let output = Command::new(env!("CARGO_BIN_EXE_playbook")).args(["--rebuild", &path("resume.yml")]).output().unwrap();
+        assert!(!output.status.success());
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let run_id = stderr.lines().filter_map(|line| line.split("playbook --resume ").nth(1)).next().expect("The run should have been journaled.").trim().to_owned();
+        assert_eq!(Journal::load(&run_id).unwrap().status, Status::Failed);
+        // * Once the failing step has been fixed, the run continues from it with the states restored.
+        std::fs::write(path("resume.yml"), playbook("action: sys_vars")).unwrap();
+        let output = Command::new(env!("CARGO_BIN_EXE_playbook")).args(["--resume", &run_id]).output().unwrap();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        let journal = Journal::load(&run_id).unwrap();
+        std::fs::remove_dir_all(run_dir(&run_id)).unwrap();
+        assert_eq!(journal.status, Status::Succeeded);
+        assert_eq!(journal.steps.iter().map(|record| record.step_ptr).collect::<Vec<usize>>(), vec![0, 1, 1, 2]);
+        let dumps: Vec<std::path::PathBuf> = std::fs::read_dir(path("dumps")).unwrap().map(|entry| entry.unwrap().path()).collect();
+        assert_eq!(dumps.len(), 1);
+        let ctx = Context::from(std::fs::read_to_string(&dumps[0]).unwrap().as_str());
+        assert_eq!(ctx.get("answer"), Some(&CtxObj::Int(42)));
+        assert!(ctx.get("run-id").is_none());
+        assert!(ctx.get("rebuild").is_none());
+    }
+
+    #[test]
+    fn journal_opt_out(){
+        let scratch = super::get_scratch();
+        let path = scratch.path().join("exit.yml");
+        std::fs::write(&path, "steps:\n- action: sys_exit\n  exit_code: 3\n").unwrap();
+        let output = Command::new(env!("CARGO_BIN_EXE_playbook")).args(["--no-journal", path.to_str().unwrap()]).output().unwrap();
+        assert!(!output.status.success());
+        assert!(!String::from_utf8_lossy(&output.stderr).contains("playbook --resume "));
+        let output = Command::new(env!("CARGO_BIN_EXE_playbook")).args(["--no-journal", "--resume", "any"]).output().unwrap();
+        assert!(!output.status.success());
+    }
+}
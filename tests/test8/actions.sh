@@ -0,0 +1,12 @@
+#!/bin/bash
+# [playbook(prep, alias=prepare)]
+prep() {
+    echo "Preparing..."
+}
+
+    # [playbook(train)]
+function train {
+    echo "Training..."
+}
+
+# [playbook(deploy)]
@@ -0,0 +1,3 @@
+#[playbook(train)]
+def train(ctx):
+    return {'checkpoint': '/scratch/{}.ckpt'.format(ctx['model']), 'metrics': {'accuracy': 0.9}}